@@ -212,16 +212,18 @@ parseBtn.addEventListener('click', async () => {
                 }
 
                 let data = new Uint8Array(await response.arrayBuffer());
+                let archive;
 
                 if (spec.extract) {
                     updateStatus(`Extracting ${spec.extract}...`);
+                    archive = data;
                     const zip = await JSZip.loadAsync(data);
                     const file = zip.file(spec.extract);
                     if (!file) throw new Error(`File ${spec.extract} not found in zip`);
                     data = new Uint8Array(await file.async('arraybuffer'));
                 }
 
-                gen_add_file(builder, spec.id, data);
+                gen_add_file(builder, spec.id, data, archive);
             } catch (e) {
                 if (e.name === 'AbortError') {
                     throw new Error(`Timeout fetching ${spec.source} after 10 seconds`);
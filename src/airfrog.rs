@@ -0,0 +1,520 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Device access through an Airfrog SWD probe.
+//!
+//! The probe's link, such as WebSerial or a WebSocket, stays on the JS side,
+//! given here as callbacks reading and writing target memory through it.
+//! [`JsReader`] and [`JsWriter`] implement `airfrog_rpc`'s [`Reader`] and
+//! [`Writer`] over those callbacks, and everything else is built on them:
+//! the firmware parser reads flash and RAM through the `Reader`, STM32F4
+//! flash is programmed through its flash controller's registers, and
+//! [`airfrog_rpc_request`] exchanges commands with firmware serving airfrog
+//! RPC channels through `airfrog_rpc`'s [`AsyncRpcClient`]. RP2350 flash is
+//! external and needs bootrom routines to program, so is only read here;
+//! use [`picoboot_flash`](crate::picoboot_flash) to write it.
+//!
+//! A memory access the probe fails while flashing, for instance on SWD
+//! WAIT, is retried. The tests run plans and RPC requests against a mock
+//! probe in `airfrog_mock`.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use airfrog_rpc::client::{AsyncDelay, AsyncRpcClient, RpcClientConfig};
+use airfrog_rpc::io::{Reader, Writer};
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::mcu::Family;
+use onerom_fw_parser::Parser;
+
+use crate::flash_plan::{FlashPlan, Geometry};
+use crate::mcu_detect::{RP2350_CHIP_ID, is_rp2350};
+use crate::{DeviceSummary, device_summary};
+
+/// Default number of times a failed memory access is retried.
+const DEFAULT_RETRIES: u32 = 2;
+/// Size of the window [`JsReader`] maps onto the device's flash.
+const FLASH_WINDOW: u32 = 0x0100_0000;
+/// How long to wait between polls of an RPC response channel, in ms.
+const RPC_POLL_MS: u32 = 10;
+
+/// Cortex-M debug halting control and status register.
+const DHCSR: u32 = 0xE000_EDF0;
+const DHCSR_HALT: u32 = 0xA05F_0003;
+const DHCSR_RUN: u32 = 0xA05F_0000;
+/// Cortex-M application interrupt and reset control register.
+const AIRCR: u32 = 0xE000_ED0C;
+const AIRCR_SYSRESETREQ: u32 = 0x05FA_0004;
+
+/// STM32F4 flash controller.
+pub(crate) const FLASH_KEYR: u32 = 0x4002_3C04;
+pub(crate) const FLASH_SR: u32 = 0x4002_3C0C;
+pub(crate) const FLASH_CR: u32 = 0x4002_3C10;
+pub(crate) const FLASH_KEY1: u32 = 0x4567_0123;
+pub(crate) const FLASH_KEY2: u32 = 0xCDEF_89AB;
+pub(crate) const SR_BSY: u32 = 1 << 16;
+/// `FLASH_SR` error flags: OPERR, WRPERR, PGAERR, PGPERR and PGSERR.
+pub(crate) const SR_ERRORS: u32 = 0xF2;
+pub(crate) const CR_PG: u32 = 1 << 0;
+pub(crate) const CR_SER: u32 = 1 << 1;
+pub(crate) const CR_SNB_SHIFT: u32 = 3;
+pub(crate) const CR_PSIZE_X32: u32 = 2 << 8;
+pub(crate) const CR_STRT: u32 = 1 << 16;
+pub(crate) const CR_LOCK: u32 = 1 << 31;
+/// `FLASH_SR` polls allowed for one erase or program before giving up.
+const MAX_BUSY_POLLS: u32 = 10_000;
+
+/// A [`Reader`] the flasher can use, failing with a message.
+pub(crate) trait ProbeReader: Reader<Error = String> {}
+impl<R: Reader<Error = String>> ProbeReader for R {}
+
+/// A [`Writer`] the flasher can use, failing with a message.
+pub(crate) trait ProbeWriter: Writer<Error = String> {}
+impl<W: Writer<Error = String>> ProbeWriter for W {}
+
+/// A target reached through an Airfrog probe.
+pub(crate) struct Probe<R: ProbeReader, W: ProbeWriter> {
+    pub(crate) reader: R,
+    pub(crate) writer: W,
+    retries: u32,
+    /// Accesses retried after the probe failed them.
+    pub(crate) retried: u32,
+}
+
+impl<R: ProbeReader, W: ProbeWriter> Probe<R, W> {
+    pub(crate) fn new(reader: R, writer: W, retries: u32) -> Self {
+        Self {
+            reader,
+            writer,
+            retries,
+            retried: 0,
+        }
+    }
+
+    pub(crate) async fn read_memory(&mut self, address: u32, len: u32) -> Result<Vec<u8>, String> {
+        let mut buf = vec![0; len as usize];
+        let mut attempt = 0;
+        loop {
+            match self.reader.read(address, &mut buf).await {
+                Ok(()) => return Ok(buf),
+                Err(_) if attempt < self.retries => {
+                    attempt += 1;
+                    self.retried += 1;
+                }
+                Err(e) => return Err(format!("Probe read at {address:#010x} failed: {e}")),
+            }
+        }
+    }
+
+    pub(crate) async fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), String> {
+        let mut attempt = 0;
+        loop {
+            match self.writer.write(address, data).await {
+                Ok(()) => return Ok(()),
+                Err(_) if attempt < self.retries => {
+                    attempt += 1;
+                    self.retried += 1;
+                }
+                Err(e) => return Err(format!("Probe write at {address:#010x} failed: {e}")),
+            }
+        }
+    }
+
+    async fn read_u32(&mut self, address: u32) -> Result<u32, String> {
+        let bytes = self.read_memory(address, 4).await?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    async fn write_u32(&mut self, address: u32, value: u32) -> Result<(), String> {
+        self.write_memory(address, &value.to_le_bytes()).await
+    }
+}
+
+/// Send `command` over the airfrog RPC channels whose control blocks are at
+/// `cmd_channel` and `rsp_channel` in target RAM, and return the response.
+pub(crate) async fn rpc_request<R: Reader, W: Writer, D: AsyncDelay>(
+    reader: &mut R,
+    writer: &mut W,
+    cmd_channel: u32,
+    rsp_channel: u32,
+    command: &[u8],
+) -> Result<Vec<u8>, String> {
+    let config = RpcClientConfig::FromTarget {
+        cmd_ch_ptr: cmd_channel,
+        rsp_ch_ptr: rsp_channel,
+    };
+    AsyncRpcClient::<R, W, D>::new(reader, writer, config)
+        .request(command)
+        .await
+        .map_err(|e| format!("RPC request failed: {e:?}"))
+}
+
+/// Options for [`airfrog_flash`]. All optional.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct AirfrogOptions {
+    /// Read back and compare each write, default true.
+    pub(crate) verify: Option<bool>,
+    /// Reset the target into the new firmware once done, default true.
+    pub(crate) reset: Option<bool>,
+    /// Times a memory access the probe fails is retried, default 2.
+    pub(crate) retries: Option<u32>,
+}
+
+/// What an SWD flash did, from [`airfrog_flash`].
+#[derive(Debug, Default, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct AirfrogReport {
+    pub mcu: String,
+    pub sectors_erased: u32,
+    pub chunks_written: u32,
+    /// Bytes written, padding included.
+    pub bytes_written: u32,
+    /// Bytes read back and found to match.
+    pub bytes_verified: u32,
+    /// Memory accesses retried after the probe failed them.
+    pub retries: u32,
+    /// Whether the target was reset once done.
+    pub reset: bool,
+    /// Erases and other steps, in order.
+    pub log: Vec<String>,
+}
+
+/// Wait for the flash controller, failing on any error flag.
+async fn flash_wait<R: ProbeReader, W: ProbeWriter>(
+    probe: &mut Probe<R, W>,
+    what: &str,
+) -> Result<(), String> {
+    for _ in 0..MAX_BUSY_POLLS {
+        let sr = probe.read_u32(FLASH_SR).await?;
+        if sr & SR_ERRORS != 0 {
+            // Clear the flags, which are write-one-to-clear, for next time.
+            probe.write_u32(FLASH_SR, sr & SR_ERRORS).await?;
+            return Err(format!("{what} failed: FLASH_SR {sr:#010x}"));
+        }
+        if sr & SR_BSY == 0 {
+            return Ok(());
+        }
+    }
+    Err(format!("{what} failed: flash still busy"))
+}
+
+/// Carry out `plan` on an STM32F4 through `probe`, reporting bytes written
+/// through `progress`.
+pub(crate) async fn run<R: ProbeReader, W: ProbeWriter>(
+    probe: &mut Probe<R, W>,
+    plan: &FlashPlan,
+    options: &AirfrogOptions,
+    progress: &mut dyn FnMut(u32, u32),
+) -> Result<AirfrogReport, String> {
+    if plan.variant.family() != Family::Stm32f4 {
+        return Err(format!(
+            "SWD flashing is for STM32F4; flash {} with PICOBOOT",
+            plan.variant
+        ));
+    }
+    let mut report = AirfrogReport {
+        mcu: plan.variant.to_string(),
+        ..Default::default()
+    };
+
+    probe.write_u32(DHCSR, DHCSR_HALT).await?;
+    report.log.push("Halted core".to_string());
+    if probe.read_u32(FLASH_CR).await? & CR_LOCK != 0 {
+        probe.write_u32(FLASH_KEYR, FLASH_KEY1).await?;
+        probe.write_u32(FLASH_KEYR, FLASH_KEY2).await?;
+        if probe.read_u32(FLASH_CR).await? & CR_LOCK != 0 {
+            return Err("Flash controller did not unlock".to_string());
+        }
+    }
+    // Clear any error left from before.
+    let sr = probe.read_u32(FLASH_SR).await?;
+    if sr & SR_ERRORS != 0 {
+        probe.write_u32(FLASH_SR, sr & SR_ERRORS).await?;
+        report
+            .log
+            .push(format!("Cleared FLASH_SR {sr:#010x} from before"));
+    }
+
+    let geometry = Geometry::of(plan.variant);
+    for sector in &plan.erase {
+        let snb = geometry
+            .sectors
+            .iter()
+            .position(|s| s == sector)
+            .ok_or_else(|| format!("{:#010x} is not a sector", sector.address))?
+            as u32;
+        let what = format!("Erasing sector {snb} at {:#010x}", sector.address);
+        let cr = CR_SER | CR_PSIZE_X32 | (snb << CR_SNB_SHIFT);
+        probe.write_u32(FLASH_CR, cr).await?;
+        probe.write_u32(FLASH_CR, cr | CR_STRT).await?;
+        flash_wait(probe, &what).await?;
+        report.log.push(what);
+        report.sectors_erased += 1;
+    }
+
+    let verify = options.verify.unwrap_or(true);
+    let total: u32 = plan.writes.iter().map(|w| w.data.len() as u32).sum();
+    progress(0, total);
+    probe.write_u32(FLASH_CR, CR_PG | CR_PSIZE_X32).await?;
+    for w in &plan.writes {
+        let len = w.data.len() as u32;
+        let what = format!("Writing {len} bytes at {:#010x}", w.address);
+        probe.write_memory(w.address, &w.data).await?;
+        flash_wait(probe, &what).await?;
+        report.chunks_written += 1;
+        report.bytes_written += len;
+        if verify {
+            let read = probe.read_memory(w.address, len).await?;
+            if let Some(i) = read.iter().zip(&w.data).position(|(a, b)| a != b) {
+                return Err(format!(
+                    "Verify failed at {:#010x}: wrote {:#04x}, read {:#04x}",
+                    w.address + i as u32,
+                    w.data[i],
+                    read[i]
+                ));
+            }
+            report.bytes_verified += len;
+        }
+        progress(report.bytes_written, total);
+    }
+    probe.write_u32(FLASH_CR, CR_LOCK).await?;
+
+    if options.reset.unwrap_or(true) {
+        probe.write_u32(DHCSR, DHCSR_RUN).await?;
+        // The target resets before it can acknowledge.
+        if let Err(e) = probe
+            .writer
+            .write(AIRCR, &AIRCR_SYSRESETREQ.to_le_bytes())
+            .await
+        {
+            report.log.push(format!("No acknowledgement of reset: {e}"));
+        }
+        report.reset = true;
+        report.log.push("Reset target".to_string());
+    } else {
+        probe.write_u32(DHCSR, DHCSR_RUN).await?;
+        report.log.push("Resumed core".to_string());
+    }
+    report.retries = probe.retried;
+    Ok(report)
+}
+
+/// A future holding JS values, passed off as `Send`.
+///
+/// `Writer`, and `Reader` off wasm32, want `Send` futures, which nothing
+/// awaiting a JS promise is. This crate runs on single-threaded wasm32, so
+/// the future never leaves its thread.
+struct AssumeSend<F>(F);
+
+// SAFETY: see above; there is no other thread to send the future to.
+unsafe impl<F> Send for AssumeSend<F> {}
+
+impl<F: Future> Future for AssumeSend<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        // SAFETY: the inner future is never moved out of the pinned wrapper.
+        unsafe { self.map_unchecked_mut(|s| &mut s.0) }.poll(cx)
+    }
+}
+
+/// Call a JS memory callback with `address` and `arg`, and await the
+/// promise it returns.
+async fn call_js(
+    cb: &js_sys::Function,
+    address: u32,
+    arg: &JsValue,
+    what: &str,
+) -> Result<JsValue, String> {
+    let promise = cb
+        .call2(&JsValue::NULL, &JsValue::from_f64(address as f64), arg)
+        .map_err(|e| format!("{what} callback threw: {e:?}"))?;
+    wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&promise))
+        .await
+        .map_err(|e| format!("{e:?}"))
+}
+
+/// A [`Reader`] over a JS read callback.
+pub(crate) struct JsReader {
+    /// JS `async (addr: number, len: number) => Uint8Array`.
+    read_cb: js_sys::Function,
+    /// Where the device's flash is mapped.
+    flash_base: u32,
+    /// Where the parser takes flash to be. Reads from the 16MB there go to
+    /// `flash_base` instead, as the parser starts from the STM32F4 base
+    /// until it finds RP2350 firmware.
+    reader_base: u32,
+}
+
+impl JsReader {
+    fn new(read_cb: js_sys::Function) -> Self {
+        Self {
+            read_cb,
+            flash_base: 0,
+            reader_base: 0,
+        }
+    }
+}
+
+impl Reader for JsReader {
+    type Error = String;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> impl Future<Output = Result<(), String>> {
+        let addr = match addr.checked_sub(self.reader_base) {
+            Some(offset) if offset < FLASH_WINDOW => self.flash_base + offset,
+            _ => addr,
+        };
+        AssumeSend(async move {
+            let len = JsValue::from_f64(buf.len() as f64);
+            let resolved = call_js(&self.read_cb, addr, &len, "read").await?;
+            let bytes = js_sys::Uint8Array::new(&resolved).to_vec();
+            if bytes.len() != buf.len() {
+                return Err(format!(
+                    "Probe read {} bytes at {addr:#010x}, expected {}",
+                    bytes.len(),
+                    buf.len()
+                ));
+            }
+            buf.copy_from_slice(&bytes);
+            Ok(())
+        })
+    }
+
+    fn update_base_address(&mut self, new_base: u32) {
+        self.reader_base = new_base;
+    }
+}
+
+/// A [`Writer`] over a JS write callback.
+pub(crate) struct JsWriter {
+    /// JS `async (addr: number, data: Uint8Array) => void`.
+    write_cb: js_sys::Function,
+}
+
+impl Writer for JsWriter {
+    type Error = String;
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> impl Future<Output = Result<(), String>> {
+        AssumeSend(async move {
+            let data = js_sys::Uint8Array::from(data);
+            call_js(&self.write_cb, addr, &data, "write").await?;
+            Ok(())
+        })
+    }
+
+    /// Writes are always to absolute addresses.
+    fn update_base_address(&mut self, _new_base: u32) {}
+}
+
+/// Waits between RPC response polls with JS `setTimeout`.
+struct JsDelay;
+
+impl AsyncDelay for JsDelay {
+    async fn delay() {
+        let promise = js_sys::Promise::new(&mut |resolve, _| {
+            let set_timeout = js_sys::Reflect::get(&js_sys::global(), &"setTimeout".into())
+                .ok()
+                .and_then(|f| f.dyn_into::<js_sys::Function>().ok());
+            let _ = match set_timeout {
+                Some(f) => f.call2(
+                    &JsValue::NULL,
+                    &resolve,
+                    &JsValue::from_f64(RPC_POLL_MS as f64),
+                ),
+                None => resolve.call0(&JsValue::NULL),
+            };
+        });
+        let _ = wasm_bindgen_futures::JsFuture::from(promise).await;
+    }
+}
+
+/// Parse the firmware on a device through an Airfrog probe, as
+/// [`parse_firmware`](crate::parse_firmware) does from a flash image, but
+/// reading flash and RAM over SWD as the parser needs them.
+///
+/// `read_cb` is a JS `async (addr: number, len: number) => Uint8Array`
+/// reading exactly `len` bytes of target memory at `addr` through the probe.
+#[wasm_bindgen]
+pub async fn airfrog_parse_firmware(read_cb: js_sys::Function) -> Result<DeviceSummary, JsValue> {
+    let mut reader = JsReader::new(read_cb);
+    let mut chip_id = [0; 4];
+    let family = match reader.read(RP2350_CHIP_ID, &mut chip_id).await {
+        Ok(()) if is_rp2350(u32::from_le_bytes(chip_id)) => Family::Rp2350,
+        _ => Family::Stm32f4,
+    };
+    // As for parse_firmware, the parser starts at the STM32F4 flash base and
+    // re-bases if it finds RP2350 firmware.
+    reader.flash_base = family.get_flash_base();
+    reader.reader_base = Family::Stm32f4.get_flash_base();
+    let mut parser = Parser::new(&mut reader);
+    let parsed = parser.parse_device().await;
+    device_summary(&parsed).map_err(|e| JsValue::from_str(&e))
+}
+
+/// Flash an STM32F4 through an Airfrog probe according to `plan`.
+///
+/// The core is halted while the flash controller erases and programs, and
+/// reset into the new firmware once done. `read_cb` is as for
+/// [`airfrog_parse_firmware`], and `write_cb` a JS `async (addr: number,
+/// data: Uint8Array) => void` writing target memory at `addr` through the
+/// probe. Either should reject if the probe fails the access. `progress_cb`,
+/// if given, is called with bytes written so far and the total.
+///
+/// `options` may be omitted, or an object with any of `verify` (read back
+/// each write, default true), `reset` (default true) and `retries` (failed
+/// memory accesses, default 2).
+#[wasm_bindgen]
+pub async fn airfrog_flash(
+    plan: &FlashPlan,
+    read_cb: js_sys::Function,
+    write_cb: js_sys::Function,
+    progress_cb: Option<js_sys::Function>,
+    options: JsValue,
+) -> Result<AirfrogReport, JsValue> {
+    let options: AirfrogOptions = crate::options(options)?;
+    let mut probe = Probe::new(
+        JsReader::new(read_cb),
+        JsWriter { write_cb },
+        options.retries.unwrap_or(DEFAULT_RETRIES),
+    );
+    let mut progress = |done: u32, total: u32| {
+        if let Some(cb) = &progress_cb {
+            let _ = cb.call2(
+                &JsValue::NULL,
+                &JsValue::from_f64(done as f64),
+                &JsValue::from_f64(total as f64),
+            );
+        }
+    };
+    run(&mut probe, plan, &options, &mut progress)
+        .await
+        .map_err(|e| JsValue::from_str(&e))
+}
+
+/// Send `command` to firmware serving airfrog RPC channels on a device
+/// through an Airfrog probe, and return its response.
+///
+/// `cmd_channel` and `rsp_channel` are the addresses in target RAM of the
+/// command and response channels' control blocks; their sizes are read from
+/// the target. The command and response are in the firmware's own format.
+/// `read_cb` and `write_cb` are as for [`airfrog_flash`]. The response
+/// channel is polled every 10ms until the firmware answers.
+#[wasm_bindgen]
+pub async fn airfrog_rpc_request(
+    read_cb: js_sys::Function,
+    write_cb: js_sys::Function,
+    cmd_channel: u32,
+    rsp_channel: u32,
+    command: Vec<u8>,
+) -> Result<Vec<u8>, JsValue> {
+    let mut reader = JsReader::new(read_cb);
+    let mut writer = JsWriter { write_cb };
+    rpc_request::<_, _, JsDelay>(&mut reader, &mut writer, cmd_channel, rsp_channel, &command)
+        .await
+        .map_err(|e| JsValue::from_str(&e))
+}
@@ -0,0 +1,505 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! A mock Airfrog probe and the target behind it.
+//!
+//! [`MockProbe`] implements `airfrog_rpc`'s [`Reader`] and [`Writer`] as
+//! [`JsReader`](crate::airfrog::JsReader) and
+//! [`JsWriter`](crate::airfrog::JsWriter) do, over a [`MockTarget`] with
+//! flash, SRAM, the RP2350 `CHIP_ID` register, the Cortex-M debug registers
+//! and, on STM32F4, a flash controller that must be unlocked, erases by
+//! sector, programs only with `PG` set and reports errors in `FLASH_SR` as
+//! the real one does. Accesses anywhere else fail, as SWD FAULTs do.
+//!
+//! The target's firmware serves a pair of airfrog RPC channels in SRAM with
+//! `airfrog_rpc`'s own target-side [`Channel`], answering each command with
+//! its bytes reversed, so requests go through real channel frames.
+//!
+//! Test only: the tests below flash [`FlashPlan`](crate::flash_plan::FlashPlan)s
+//! through [`run`](crate::airfrog::run) and send requests through
+//! [`rpc_request`](crate::airfrog::rpc_request) against it, so the SWD
+//! sequence can be checked without hardware.
+
+use std::sync::{Arc, Mutex};
+
+use airfrog_rpc::channel::{Channel, ChannelActor, ChannelIo};
+use airfrog_rpc::client::AsyncDelay;
+use airfrog_rpc::io::{Reader, Writer};
+use onerom_config::mcu::{Family, Variant};
+
+use crate::airfrog::{
+    CR_LOCK, CR_PG, CR_SER, CR_SNB_SHIFT, CR_STRT, FLASH_CR, FLASH_KEY1, FLASH_KEY2, FLASH_KEYR,
+    FLASH_SR, SR_BSY, SR_ERRORS,
+};
+use crate::flash_plan::{ERASED, Geometry};
+use crate::mcu_detect::RP2350_CHIP_ID;
+use crate::test_util::SimulatedFlash;
+
+const SRAM_BASE: u32 = 0x2000_0000;
+const SRAM_SIZE: u32 = 256 * 1024;
+/// RP2350 A2 `CHIP_ID`.
+const CHIP_ID_RP2350: u32 = 0x2000_4927;
+const DHCSR: u32 = 0xE000_EDF0;
+const AIRCR: u32 = 0xE000_ED0C;
+const AIRCR_SYSRESETREQ: u32 = 0x05FA_0004;
+/// `FLASH_SR` PGAERR and PGSERR.
+const SR_PGAERR: u32 = 1 << 5;
+const SR_PGSERR: u32 = 1 << 7;
+/// The firmware's RPC command and response channels, and their size.
+pub(crate) const CMD_CHANNEL: u32 = SRAM_BASE + 0x1000;
+pub(crate) const RSP_CHANNEL: u32 = SRAM_BASE + 0x2000;
+const CHANNEL_SIZE: usize = 256;
+
+/// A simulated target with erased flash.
+pub(crate) struct MockTarget {
+    variant: Variant,
+    geometry: Geometry,
+    flash: Vec<u8>,
+    sram: Vec<u8>,
+    /// Accesses seen so far.
+    accesses: u32,
+    /// Access numbers that fail once, as on SWD WAIT.
+    faults: Vec<u32>,
+    flash_cr: u32,
+    flash_sr: u32,
+    /// Keys written to `FLASH_KEYR` since the last lock.
+    keys: Vec<u32>,
+    /// `FLASH_SR` polls left reporting busy.
+    busy_polls: u32,
+    dhcsr: u32,
+    /// Set once the target has been reset.
+    pub(crate) reset: bool,
+    /// Whether the firmware serves RPC channels.
+    rpc: bool,
+}
+
+/// The firmware's view of SRAM, for its RPC channels.
+struct SramIo<'a>(&'a mut [u8]);
+
+impl SramIo<'_> {
+    fn offset(&self, addr: u32, len: usize) -> airfrog_rpc::Result<usize> {
+        MockTarget::offset(SRAM_BASE, SRAM_SIZE, addr, len as u32).ok_or(airfrog_rpc::Error::Io)
+    }
+}
+
+impl ChannelIo for SramIo<'_> {
+    fn read_u32(&mut self, addr: u32) -> airfrog_rpc::Result<u32> {
+        let o = self.offset(addr, 4)?;
+        Ok(u32::from_le_bytes(self.0[o..o + 4].try_into().unwrap()))
+    }
+
+    fn write_u32(&mut self, addr: u32, value: u32) -> airfrog_rpc::Result<()> {
+        let o = self.offset(addr, 4)?;
+        self.0[o..o + 4].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn read_bulk(&mut self, addr: u32, buf: &mut [u32]) -> airfrog_rpc::Result<()> {
+        for (i, word) in buf.iter_mut().enumerate() {
+            *word = self.read_u32(addr + i as u32 * 4)?;
+        }
+        Ok(())
+    }
+
+    fn write_bulk(&mut self, addr: u32, data: &[u32]) -> airfrog_rpc::Result<()> {
+        for (i, word) in data.iter().enumerate() {
+            self.write_u32(addr + i as u32 * 4, *word)?;
+        }
+        Ok(())
+    }
+}
+
+impl MockTarget {
+    pub(crate) fn new(variant: Variant, faults: Vec<u32>) -> Self {
+        let geometry = Geometry::of(variant);
+        Self {
+            variant,
+            flash: vec![ERASED; geometry.size as usize],
+            geometry,
+            sram: vec![0; SRAM_SIZE as usize],
+            accesses: 0,
+            faults,
+            flash_cr: CR_LOCK,
+            flash_sr: 0,
+            keys: Vec::new(),
+            busy_polls: 0,
+            dhcsr: 0,
+            reset: false,
+            rpc: false,
+        }
+    }
+
+    /// Start firmware serving RPC channels at [`CMD_CHANNEL`] and
+    /// [`RSP_CHANNEL`].
+    pub(crate) fn serve_rpc(&mut self) {
+        let mut io = SramIo(&mut self.sram);
+        Channel::new(&mut io, ChannelActor::Consumer, CMD_CHANNEL, CHANNEL_SIZE).unwrap();
+        Channel::new(&mut io, ChannelActor::Producer, RSP_CHANNEL, CHANNEL_SIZE).unwrap();
+        self.rpc = true;
+    }
+
+    /// Run the firmware: answer a pending command, if any, once the last
+    /// response has been taken.
+    fn poll_firmware(&mut self) {
+        if !self.rpc || self.reset {
+            return;
+        }
+        let mut io = SramIo(&mut self.sram);
+        let mut cmd = Channel::from_target(&mut io, ChannelActor::Consumer, CMD_CHANNEL).unwrap();
+        if cmd.data_available().unwrap().is_none() {
+            return;
+        }
+        let mut command = vec![0; CHANNEL_SIZE];
+        let Ok(len) = cmd.consume_bytes(&mut command) else {
+            return;
+        };
+        command.truncate(len);
+        command.reverse();
+        let mut rsp = Channel::from_target(&mut io, ChannelActor::Producer, RSP_CHANNEL).unwrap();
+        rsp.publish_bytes(&command).unwrap();
+    }
+
+    fn is_stm32(&self) -> bool {
+        self.variant.family() == Family::Stm32f4
+    }
+
+    /// Offset of `address..address + len` into `size` bytes at `base`.
+    fn offset(base: u32, size: u32, address: u32, len: u32) -> Option<usize> {
+        let offset = address.checked_sub(base)?;
+        (offset.checked_add(len)? <= size).then_some(offset as usize)
+    }
+
+    fn read_register(&mut self, address: u32) -> Option<u32> {
+        Some(match address {
+            FLASH_SR if self.is_stm32() => {
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+                    self.flash_sr | SR_BSY
+                } else {
+                    self.flash_sr
+                }
+            }
+            FLASH_CR if self.is_stm32() => self.flash_cr,
+            FLASH_KEYR if self.is_stm32() => 0,
+            RP2350_CHIP_ID if self.is_stm32() => 0,
+            RP2350_CHIP_ID => CHIP_ID_RP2350,
+            DHCSR => self.dhcsr & 0xFFFF,
+            AIRCR => 0xFA05_0000,
+            _ => return None,
+        })
+    }
+
+    fn read_memory(&mut self, address: u32, len: u32) -> Option<Vec<u8>> {
+        let g = &self.geometry;
+        if let Some(o) = Self::offset(g.base, g.size, address, len) {
+            return Some(self.flash[o..o + len as usize].to_vec());
+        }
+        if let Some(o) = Self::offset(SRAM_BASE, SRAM_SIZE, address, len) {
+            return Some(self.sram[o..o + len as usize].to_vec());
+        }
+        if len == 4 {
+            return self
+                .read_register(address)
+                .map(|v| v.to_le_bytes().to_vec());
+        }
+        None
+    }
+
+    fn write_register(&mut self, address: u32, value: u32) -> Option<()> {
+        match address {
+            FLASH_KEYR if self.is_stm32() => {
+                self.keys.push(value);
+                match self.keys[..] {
+                    [FLASH_KEY1] => {}
+                    [FLASH_KEY1, FLASH_KEY2] => self.flash_cr &= !CR_LOCK,
+                    // A wrong key locks the controller until reset.
+                    _ => return None,
+                }
+            }
+            FLASH_SR if self.is_stm32() => self.flash_sr &= !(value & SR_ERRORS),
+            FLASH_CR if self.is_stm32() => {
+                if self.flash_cr & CR_LOCK != 0 {
+                    return Some(());
+                }
+                self.flash_cr = value & !CR_STRT;
+                if value & CR_LOCK != 0 {
+                    self.keys.clear();
+                } else if value & (CR_STRT | CR_SER) == CR_STRT | CR_SER {
+                    let snb = (value >> CR_SNB_SHIFT) & 0xF;
+                    match self.geometry.sectors.get(snb as usize) {
+                        Some(sector) => {
+                            let o = (sector.address - self.geometry.base) as usize;
+                            self.flash[o..o + sector.size as usize].fill(ERASED);
+                            self.busy_polls = 1;
+                        }
+                        None => self.flash_sr |= SR_PGSERR,
+                    }
+                }
+            }
+            DHCSR => self.dhcsr = value,
+            AIRCR => {
+                if value == AIRCR_SYSRESETREQ {
+                    self.reset = true;
+                    self.flash_cr = CR_LOCK;
+                    self.keys.clear();
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn write_memory(&mut self, address: u32, data: &[u8]) -> Option<()> {
+        let len = data.len() as u32;
+        let g = &self.geometry;
+        if let Some(o) = Self::offset(g.base, g.size, address, len) {
+            if !self.is_stm32() {
+                // External QSPI flash is read-only through XIP.
+                return None;
+            }
+            if self.flash_cr & (CR_LOCK | CR_PG) != CR_PG {
+                self.flash_sr |= SR_PGSERR;
+            } else if !address.is_multiple_of(4) || !len.is_multiple_of(4) {
+                self.flash_sr |= SR_PGAERR;
+            } else {
+                for (byte, new) in self.flash[o..o + data.len()].iter_mut().zip(data) {
+                    *byte &= new;
+                }
+            }
+            return Some(());
+        }
+        if let Some(o) = Self::offset(SRAM_BASE, SRAM_SIZE, address, len) {
+            self.sram[o..o + data.len()].copy_from_slice(data);
+            return Some(());
+        }
+        let value = u32::from_le_bytes(data.try_into().ok()?);
+        self.write_register(address, value)
+    }
+
+    /// Count an access, failing it if it is one to fail or the debug
+    /// connection was lost to a reset.
+    fn access(&mut self, address: u32) -> Result<(), String> {
+        let number = self.accesses;
+        self.accesses += 1;
+        if let Some(i) = self.faults.iter().position(|&f| f == number) {
+            self.faults.remove(i);
+            return Err(format!("SWD WAIT at {address:#010x}"));
+        }
+        if self.reset {
+            return Err("no target".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl SimulatedFlash for MockTarget {
+    fn flash(&self) -> (u32, &[u8]) {
+        (self.geometry.base, &self.flash)
+    }
+}
+
+/// A mock probe, reaching a [`MockTarget`] as both [`Reader`] and
+/// [`Writer`].
+#[derive(Clone)]
+pub(crate) struct MockProbe(pub(crate) Arc<Mutex<MockTarget>>);
+
+impl MockProbe {
+    pub(crate) fn new(target: MockTarget) -> Self {
+        Self(Arc::new(Mutex::new(target)))
+    }
+
+    /// Carry out one access, with the firmware running alongside.
+    fn access<T>(
+        &self,
+        address: u32,
+        f: impl FnOnce(&mut MockTarget) -> Option<T>,
+    ) -> Result<T, String> {
+        let mut target = self.0.lock().unwrap();
+        target.poll_firmware();
+        target.access(address)?;
+        f(&mut target).ok_or_else(|| format!("SWD FAULT at {address:#010x}"))
+    }
+}
+
+impl Reader for MockProbe {
+    type Error = String;
+
+    async fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), String> {
+        let bytes = self.access(addr, |t| t.read_memory(addr, buf.len() as u32))?;
+        buf.copy_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn update_base_address(&mut self, _new_base: u32) {}
+}
+
+impl Writer for MockProbe {
+    type Error = String;
+
+    async fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), String> {
+        self.access(addr, |t| t.write_memory(addr, data))
+    }
+
+    fn update_base_address(&mut self, _new_base: u32) {}
+}
+
+/// Polls the mock firmware's response channel without waiting.
+pub(crate) struct NoDelay;
+
+impl AsyncDelay for NoDelay {
+    async fn delay() {}
+}
+
+mod tests {
+    use super::*;
+    use crate::airfrog::{AirfrogOptions, AirfrogReport, Probe, rpc_request, run};
+    use crate::flash_plan::{FlashPlan, plan};
+    use crate::test_util::{holds, run_ready};
+
+    fn test_plan() -> FlashPlan {
+        crate::test_util::test_plan(Variant::F411RE)
+    }
+
+    fn flash(
+        target: MockTarget,
+        plan: &FlashPlan,
+        options: &AirfrogOptions,
+    ) -> (Result<AirfrogReport, String>, MockTarget) {
+        let link = MockProbe::new(target);
+        let mut probe = Probe::new(link.clone(), link.clone(), options.retries.unwrap_or(2));
+        let result = run_ready(run(&mut probe, plan, options, &mut |_, _| {}));
+        drop(probe);
+        let target = Arc::into_inner(link.0).unwrap().into_inner().unwrap();
+        (result, target)
+    }
+
+    fn request(target: MockTarget, command: &[u8]) -> Result<Vec<u8>, String> {
+        let mut link = MockProbe::new(target);
+        let mut writer = link.clone();
+        run_ready(rpc_request::<_, _, NoDelay>(
+            &mut link,
+            &mut writer,
+            CMD_CHANNEL,
+            RSP_CHANNEL,
+            command,
+        ))
+    }
+
+    #[test]
+    fn flashes_plan() {
+        let plan = test_plan();
+        let target = MockTarget::new(plan.variant, vec![]);
+        let (report, target) = flash(target, &plan, &AirfrogOptions::default());
+        let report = report.unwrap();
+        assert!(holds(&target, &plan));
+        assert_eq!(report.sectors_erased, plan.erase.len() as u32);
+        assert_eq!(report.bytes_verified, report.bytes_written);
+        assert_eq!(report.retries, 0);
+        assert!(report.reset && target.reset);
+        assert_eq!(target.flash_cr, CR_LOCK);
+    }
+
+    #[test]
+    fn retries_failed_accesses() {
+        let plan = test_plan();
+        let target = MockTarget::new(plan.variant, vec![0, 4, 20]);
+        let (report, target) = flash(target, &plan, &AirfrogOptions::default());
+        assert!(holds(&target, &plan));
+        assert_eq!(report.unwrap().retries, 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let plan = test_plan();
+        let target = MockTarget::new(plan.variant, vec![4, 5, 6]);
+        let (report, target) = flash(target, &plan, &AirfrogOptions::default());
+        let e = report.unwrap_err();
+        assert!(e.contains("SWD WAIT"), "{e}");
+        assert!(!target.reset);
+    }
+
+    #[test]
+    fn clears_errors_left_from_before() {
+        let plan = test_plan();
+        let mut target = MockTarget::new(plan.variant, vec![]);
+        target.flash_sr = SR_PGSERR;
+        let (report, target) = flash(target, &plan, &AirfrogOptions::default());
+        assert!(holds(&target, &plan));
+        assert!(report.unwrap().log.iter().any(|l| l.starts_with("Cleared")));
+    }
+
+    #[test]
+    fn reports_flash_controller_errors() {
+        let mut plan = test_plan();
+        plan.writes[0].address += 2;
+        let target = MockTarget::new(plan.variant, vec![]);
+        let (report, target) = flash(target, &plan, &AirfrogOptions::default());
+        let e = report.unwrap_err();
+        assert!(e.contains("FLASH_SR"), "{e}");
+        assert_eq!(target.flash_sr & SR_ERRORS, 0);
+    }
+
+    #[test]
+    fn verify_catches_unerased_flash() {
+        let mut plan = test_plan();
+        plan.erase.clear();
+        let mut target = MockTarget::new(plan.variant, vec![]);
+        target.flash[1] = 0;
+        let (report, _) = flash(target, &plan, &AirfrogOptions::default());
+        let e = report.unwrap_err();
+        assert!(e.starts_with("Verify failed at 0x08000001"), "{e}");
+    }
+
+    #[test]
+    fn refuses_rp2350() {
+        let plan = plan(Variant::RP2350, vec![("firmware", 0, vec![0; 256])], None).unwrap();
+        let target = MockTarget::new(plan.variant, vec![]);
+        let (report, _) = flash(target, &plan, &AirfrogOptions::default());
+        assert!(report.unwrap_err().contains("PICOBOOT"));
+    }
+
+    #[test]
+    fn answers_rpc_requests() {
+        let mut target = MockTarget::new(Variant::F411RE, vec![]);
+        target.serve_rpc();
+        let link = MockProbe::new(target);
+        for command in [&b"ping"[..], b"odd", &[0xA5; 100]] {
+            let mut reader = link.clone();
+            let mut writer = link.clone();
+            let response = run_ready(rpc_request::<_, _, NoDelay>(
+                &mut reader,
+                &mut writer,
+                CMD_CHANNEL,
+                RSP_CHANNEL,
+                command,
+            ))
+            .unwrap();
+            let expected: Vec<u8> = command.iter().rev().copied().collect();
+            assert_eq!(response, expected);
+        }
+    }
+
+    #[test]
+    fn rpc_fails_without_channels() {
+        let target = MockTarget::new(Variant::F411RE, vec![]);
+        let e = request(target, b"ping").unwrap_err();
+        assert!(e.contains("BufferTooSmall"), "{e}");
+    }
+
+    #[test]
+    fn rpc_fails_on_probe_fault() {
+        let mut target = MockTarget::new(Variant::F411RE, vec![0]);
+        target.serve_rpc();
+        let e = request(target, b"ping").unwrap_err();
+        assert!(e.contains("Io"), "{e}");
+    }
+
+    #[test]
+    fn rpc_rejects_oversized_commands() {
+        let mut target = MockTarget::new(Variant::F411RE, vec![]);
+        target.serve_rpc();
+        let e = request(target, &[0; CHANNEL_SIZE]).unwrap_err();
+        assert!(e.contains("PayloadTooLarge"), "{e}");
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Board capability matrix.
+//!
+//! One row per board in `BOARDS`, combining what [`board_info`](crate::board_info)
+//! returns for a single board with the chip types each firmware generation
+//! can serve on it, so hardware documentation and compatibility tables can be
+//! generated rather than maintained by hand.
+//!
+//! Firmware before 0.7.0 serves any chip type the board's socket takes.
+//! 0.7.0 onwards is Fire only, and serves a chip type where `onerom-gen` can
+//! lay out its address and CS lines on the board.
+
+use std::fmt::Write;
+
+use serde::Serialize;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::{CHIP_TYPES, ChipType};
+use onerom_config::fw::FirmwareVersion;
+use onerom_config::hw::{BOARDS, Board};
+use onerom_config::mcu::Family;
+use onerom_gen::compat::check_chip_on_board;
+
+/// A board's capabilities, from [`board_matrix`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct BoardCapabilities {
+    pub name: String,
+    pub description: String,
+    /// "Fire" or "Ice".
+    pub model: String,
+    pub mcu_family: String,
+    /// Socket pin count.
+    pub chip_pins: u8,
+    pub has_usb: bool,
+    pub supports_multi_chip_sets: bool,
+    pub supports_banked_roms: bool,
+    pub supports_plugins: bool,
+    /// Data bus widths, 8 and/or 16.
+    pub bit_modes: Vec<u8>,
+    /// Chip types of a different pin count the socket also takes.
+    pub extra_chip_types: Vec<String>,
+    /// Chip types served by any firmware, in `CHIP_TYPES` order.
+    pub chip_types: Vec<String>,
+    /// Status LED and X1/X2 GPIOs, `None` where the board has none.
+    pub pin_status: Option<u8>,
+    pub pin_x1: Option<u8>,
+    pub pin_x2: Option<u8>,
+    /// Firmware version ranges the board is supported by, oldest first.
+    pub firmware: Vec<FirmwareSupport>,
+}
+
+/// A firmware version range in a [`BoardCapabilities`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FirmwareSupport {
+    pub min_version: String,
+    pub max_version: String,
+    /// Versions within the range that are not supported.
+    pub unsupported_versions: Vec<String>,
+    /// Chip types this range serves on the board.
+    pub chip_types: Vec<String>,
+}
+
+/// Return the capabilities of every board, in `BOARDS` order.
+#[wasm_bindgen]
+pub fn board_matrix() -> Vec<BoardCapabilities> {
+    BOARDS.iter().map(|&b| capabilities(b)).collect()
+}
+
+/// Return [`board_matrix`] as pretty-printed JSON.
+#[wasm_bindgen]
+pub fn board_matrix_json() -> Result<String, JsValue> {
+    serde_json::to_string_pretty(&board_matrix()).map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// Return [`board_matrix`] as CSV, one row per board after a header row.
+///
+/// List fields are separated by spaces. Firmware support is given as
+/// `min-max` ranges, with any unsupported versions in the range after
+/// `!`, e.g. `0.2.0-0.6.999 !0.6.3`. Per-range chip types are left out;
+/// the `chip_types` column lists every type any firmware serves.
+#[wasm_bindgen]
+pub fn board_matrix_csv() -> String {
+    let mut csv = String::from(
+        "name,description,model,mcu_family,chip_pins,has_usb,supports_multi_chip_sets,\
+         supports_banked_roms,supports_plugins,bit_modes,extra_chip_types,chip_types,\
+         pin_status,pin_x1,pin_x2,firmware\n",
+    );
+    for b in board_matrix() {
+        let optional = |pin: Option<u8>| pin.map(|p| p.to_string()).unwrap_or_default();
+        let bit_modes: Vec<String> = b.bit_modes.iter().map(u8::to_string).collect();
+        let firmware: Vec<String> = b
+            .firmware
+            .iter()
+            .map(|f| {
+                let mut range = format!("{}-{}", f.min_version, f.max_version);
+                for v in &f.unsupported_versions {
+                    let _ = write!(range, " !{v}");
+                }
+                range
+            })
+            .collect();
+        let fields = [
+            b.name,
+            b.description,
+            b.model,
+            b.mcu_family,
+            b.chip_pins.to_string(),
+            b.has_usb.to_string(),
+            b.supports_multi_chip_sets.to_string(),
+            b.supports_banked_roms.to_string(),
+            b.supports_plugins.to_string(),
+            bit_modes.join(" "),
+            b.extra_chip_types.join(" "),
+            b.chip_types.join(" "),
+            optional(b.pin_status),
+            optional(b.pin_x1),
+            optional(b.pin_x2),
+            firmware.join("; "),
+        ];
+        let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        csv.push_str(&fields.join(","));
+        csv.push('\n');
+    }
+    csv
+}
+
+fn capabilities(board: Board) -> BoardCapabilities {
+    let names = |types: &mut dyn Iterator<Item = ChipType>| -> Vec<String> {
+        types.map(|t| t.name().to_string()).collect()
+    };
+    let roms = || CHIP_TYPES.iter().copied().filter(|t| !t.is_plugin());
+
+    let mut firmware = vec![FirmwareSupport {
+        min_version: onerom_gen::MIN_SUPPORTED_FIRMWARE_VERSION_V1.to_string(),
+        max_version: onerom_gen::MAX_SUPPORTED_FIRMWARE_VERSION_V1.to_string(),
+        unsupported_versions: versions(&onerom_gen::UNSUPPORTED_FIRMWARE_VERSIONS_V1),
+        chip_types: names(&mut roms().filter(|&t| {
+            onerom_gen::SUPPORTED_CHIP_TYPES_V1.contains(&t) && board.allows_chip_type(t)
+        })),
+    }];
+    if board.mcu_family() == Family::Rp2350 {
+        firmware.push(FirmwareSupport {
+            min_version: onerom_gen::MIN_SUPPORTED_FIRMWARE_VERSION_V2.to_string(),
+            max_version: onerom_gen::MAX_SUPPORTED_FIRMWARE_VERSION_V2.to_string(),
+            unsupported_versions: versions(onerom_gen::UNSUPPORTED_FIRMWARE_VERSIONS_V2),
+            chip_types: names(&mut roms().filter(|&t| check_chip_on_board(board, t).is_some())),
+        });
+    }
+
+    BoardCapabilities {
+        name: board.name().to_string(),
+        description: board.description().to_string(),
+        model: board.model().name().to_string(),
+        mcu_family: board.mcu_family().to_string(),
+        chip_pins: board.chip_pins(),
+        has_usb: board.has_usb(),
+        supports_multi_chip_sets: board.supports_multi_chip_sets(),
+        supports_banked_roms: board.supports_banked_roms(),
+        supports_plugins: board.mcu_family() == Family::Rp2350,
+        bit_modes: board.bit_modes().iter().map(|&m| m as u8).collect(),
+        extra_chip_types: names(&mut board.extra_chip_types().iter().copied()),
+        chip_types: names(&mut roms().filter(|t| {
+            firmware
+                .iter()
+                .any(|f| f.chip_types.iter().any(|n| n == t.name()))
+        })),
+        pin_status: gpio(board.pin_status()),
+        pin_x1: gpio(board.pin_x1()),
+        pin_x2: gpio(board.pin_x2()),
+        firmware,
+    }
+}
+
+/// A GPIO, or `None` for 255, which boards use for a pin they lack.
+fn gpio(pin: u8) -> Option<u8> {
+    (pin != 255).then_some(pin)
+}
+
+fn versions(versions: &[FirmwareVersion]) -> Vec<String> {
+    versions.iter().map(|v| v.to_string()).collect()
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,305 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Board pin mapping for a chip type.
+//!
+//! Joins a chip type's pinout to a board's wiring: each chip pin's signal,
+//! the socket pin it lands on, the MCU port and GPIO(s) behind that, and
+//! which bit of the address (ROM table index) or data word the firmware sees
+//! it as. Those bits are what `onerom-gen` scrambles ROM data by.
+//!
+//! Boards with a `socket_pin_map` are mapped from it directly. Older boards
+//! (Ice) are mapped through the reference chip for their socket size, as
+//! their GPIO lists are laid out by that chip's address and data lines.
+
+use serde::Serialize;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::ChipType;
+use onerom_config::hw::Board;
+use onerom_config::mcu::Port;
+
+use crate::custom_chips;
+
+/// A chip type's pins as wired on a board, from [`board_pin_map`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct BoardPinMapping {
+    pub board: String,
+    pub chip_type: String,
+    /// Socket pin number minus chip pin number. Non-zero when the chip has a
+    /// different pin count from the socket, in which case it sits at the
+    /// end away from the notch; negative when it overhangs the socket.
+    pub pin_offset: i8,
+    /// One entry per chip signal, in chip pin order.
+    pub pins: Vec<PinMapping>,
+}
+
+/// A single chip signal in a [`BoardPinMapping`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct PinMapping {
+    pub chip_pin: u8,
+    /// "A0".."An", "D0".."Dn", or the control, programming or power pin name
+    /// in upper case ("CS1", "OE", "VPP", "VCC", ...).
+    pub signal: String,
+    /// Socket pin, or `None` where the chip overhangs the socket.
+    pub socket_pin: Option<u8>,
+    /// MCU port, as in `BoardInfo` ("PORT_A", "PORT_0", ...).
+    pub port: Option<String>,
+    /// MCU GPIO(s) wired to the socket pin. Empty for power pins and
+    /// unconnected pins.
+    pub gpios: Vec<u8>,
+    /// "address" if the firmware reads this pin as part of the ROM table
+    /// index, "data" if it drives it as part of the served word.
+    pub bus: Option<String>,
+    /// Bit position within `bus`.
+    pub bit: Option<u8>,
+}
+
+/// Return how `chip_type` is wired when plugged into `board`. A custom chip
+/// type is wired as the built-in type it is served as.
+#[wasm_bindgen]
+pub fn board_pin_map(board: String, chip_type: String) -> Result<BoardPinMapping, JsValue> {
+    let board = Board::try_from_str(&board)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown board: {}", board)))?;
+    let chip_type =
+        custom_chips::socket_chip_type(board, &chip_type).map_err(|e| JsValue::from_str(&e))?;
+    pin_mapping(board, chip_type).map_err(|e| JsValue::from_str(&e))
+}
+
+pub(crate) fn pin_mapping(board: Board, chip_type: ChipType) -> Result<BoardPinMapping, String> {
+    let offset = pin_offset(chip_type.chip_pins(), board.chip_pins()).ok_or_else(|| {
+        format!(
+            "A {}-pin {} does not fit the {}-pin {} socket",
+            chip_type.chip_pins(),
+            chip_type.name(),
+            board.chip_pins(),
+            board.name()
+        )
+    })?;
+
+    let mut signals: Vec<(u8, String, Option<&'static str>)> = Vec::new();
+    for (line, &pin) in chip_type.address_pins().iter().enumerate() {
+        signals.push((pin, format!("A{line}"), None));
+    }
+    for (line, &pin) in chip_type.data_pins().iter().enumerate() {
+        signals.push((pin, format!("D{line}"), None));
+    }
+    for cl in chip_type.control_lines() {
+        signals.push((cl.pin, cl.name.to_uppercase(), Some(cl.name)));
+    }
+    for p in chip_type.programming_pins().unwrap_or(&[]) {
+        signals.push((p.pin, p.name.to_uppercase(), None));
+    }
+    for p in chip_type.power_pins() {
+        signals.push((p.pin, p.name.to_uppercase(), None));
+    }
+    signals.sort_by_key(|(pin, _, _)| *pin);
+
+    let pins = signals
+        .into_iter()
+        .map(|(chip_pin, signal, control)| {
+            let socket_pin = u8::try_from(chip_pin as i16 + offset as i16)
+                .ok()
+                .filter(|&p| (1..=board.chip_pins()).contains(&p));
+            let wiring = match socket_pin {
+                Some(pin) if !board.non_signal_pins().contains(&pin) => {
+                    socket_wiring(board, chip_type, pin, control)
+                }
+                _ => Vec::new(),
+            };
+            let (port, bus, bit) = wiring
+                .iter()
+                .find_map(|&(gpio, line)| line.or_else(|| gpio_line(board, chip_type, gpio)))
+                .map_or((None, None, None), |line| {
+                    let (port, bus, bit) = line.position(board);
+                    let port = (port != Port::None).then(|| port.to_string());
+                    (port, Some(bus.to_string()), bit)
+                });
+            let gpios = wiring.into_iter().map(|(gpio, _)| gpio).collect();
+            PinMapping {
+                chip_pin,
+                signal,
+                socket_pin,
+                port,
+                gpios,
+                bus,
+                bit,
+            }
+        })
+        .collect();
+
+    Ok(BoardPinMapping {
+        board: board.name().to_string(),
+        chip_type: chip_type.name().to_string(),
+        pin_offset: offset,
+        pins,
+    })
+}
+
+/// Socket pin minus chip pin, as `onerom-gen` seats a chip in a socket of a
+/// different size.
+pub(crate) fn pin_offset(chip_pins: u8, board_pins: u8) -> Option<i8> {
+    match (chip_pins, board_pins) {
+        (c, b) if c == b => Some(0),
+        (24 | 28 | 32, 24 | 28 | 32) => Some((board_pins as i8 - chip_pins as i8) / 2),
+        _ => None,
+    }
+}
+
+/// The canonical chip whose address and data lines a board's GPIO lists are
+/// laid out by.
+fn reference_chip(board_pins: u8) -> Option<ChipType> {
+    match board_pins {
+        24 => Some(ChipType::Chip2364),
+        28 => Some(ChipType::Chip27512),
+        32 => Some(ChipType::Chip27C040),
+        40 => Some(ChipType::Chip27C400),
+        _ => None,
+    }
+}
+
+/// GPIO for a named control line, or 255 if the board does not define one.
+fn control_gpio(board: Board, chip_type: ChipType, name: &str) -> u8 {
+    match name {
+        "cs1" => board.pin_cs1(chip_type),
+        "cs2" => board.pin_cs2(chip_type),
+        "cs3" => board.pin_cs3(chip_type),
+        "ce" => board.pin_ce(chip_type),
+        "oe" => board.pin_oe(chip_type),
+        "byte" => board.pin_byte(),
+        _ => 255,
+    }
+}
+
+/// Bit position of a named control line, or 255 if the board does not
+/// define one.
+fn control_bit(board: Board, chip_type: ChipType, name: &str) -> u8 {
+    match name {
+        "cs1" => board.bit_cs1(chip_type),
+        "cs2" => board.bit_cs2(chip_type),
+        "cs3" => board.bit_cs3(chip_type),
+        "ce" => board.bit_ce(chip_type),
+        "oe" => board.bit_oe(chip_type),
+        _ => 255,
+    }
+}
+
+const CONTROL_NAMES: [&str; 5] = ["cs1", "cs2", "cs3", "ce", "oe"];
+
+/// What a GPIO carries, as the firmware sees it.
+#[derive(Clone, Copy)]
+enum Line {
+    /// Board address line, as indexed in `addr_pins`.
+    Addr(usize),
+    Data(u8),
+    /// A control line, with the chip type the board defines it for.
+    Control(ChipType, &'static str),
+    /// An X pin, with its address bit.
+    X(u8),
+}
+
+impl Line {
+    /// Port, bus and bit within the bus.
+    fn position(self, board: Board) -> (Port, &'static str, Option<u8>) {
+        match self {
+            Line::Addr(line) => {
+                let bit = board
+                    .phys_pin_to_addr_map()
+                    .iter()
+                    .position(|&l| l == Some(line))
+                    .map(|bit| bit as u8);
+                (board.port_addr(), "address", bit)
+            }
+            Line::Data(gpio) => {
+                let base = board.data_pins().iter().min().copied().unwrap_or(0);
+                (board.port_data(), "data", gpio.checked_sub(base))
+            }
+            Line::Control(chip_type, name) => {
+                let bit = control_bit(board, chip_type, name);
+                (board.port_cs(), "address", (bit != 255).then_some(bit))
+            }
+            Line::X(bit) => (board.port_cs(), "address", Some(bit)),
+        }
+    }
+}
+
+/// The GPIO(s) wired to socket pin `pin`, each with what it carries where
+/// that is known from the socket side. `control` is the chip's own control
+/// line name on that pin, if any.
+fn socket_wiring(
+    board: Board,
+    chip_type: ChipType,
+    pin: u8,
+    control: Option<&'static str>,
+) -> Vec<(u8, Option<Line>)> {
+    // Boards with a socket map are RP2350, with one GPIO bank, so the GPIO
+    // alone says what it carries.
+    if !board.socket_pin_map().is_empty() {
+        return board
+            .gpios_for_socket_pin(pin)
+            .iter()
+            .map(|&gpio| (gpio, None))
+            .collect();
+    }
+
+    // Otherwise ports overlap, so work from the socket pin. A board-defined
+    // GPIO for this chip type's own control line wins.
+    if let Some(name) = control {
+        let gpio = control_gpio(board, chip_type, name);
+        if gpio != 255 {
+            return vec![(gpio, Some(Line::Control(chip_type, name)))];
+        }
+    }
+
+    let Some(ref_chip) = reference_chip(board.chip_pins()) else {
+        return Vec::new();
+    };
+    let line_of = |pins: &[u8]| pins.iter().position(|&p| p == pin);
+    if let Some(line) = line_of(ref_chip.address_pins())
+        && let Some(&gpio) = board.addr_pins().get(line)
+    {
+        return vec![(gpio, Some(Line::Addr(line)))];
+    }
+    if let Some(line) = line_of(ref_chip.data_pins())
+        && let Some(&gpio) = board.data_pins().get(line)
+    {
+        return vec![(gpio, Some(Line::Data(gpio)))];
+    }
+    ref_chip
+        .control_lines()
+        .iter()
+        .filter(|cl| cl.pin == pin)
+        .map(|cl| (control_gpio(board, ref_chip, cl.name), cl.name))
+        .filter(|&(gpio, _)| gpio != 255)
+        .map(|(gpio, name)| (gpio, Some(Line::Control(ref_chip, name))))
+        .collect()
+}
+
+/// What `gpio` carries, on a single-bank board.
+fn gpio_line(board: Board, chip_type: ChipType, gpio: u8) -> Option<Line> {
+    if let Some(line) = board.addr_pins().iter().position(|&g| g == gpio) {
+        return Some(Line::Addr(line));
+    }
+    if board.data_pins().contains(&gpio) {
+        return Some(Line::Data(gpio));
+    }
+    let ref_chip = reference_chip(board.chip_pins());
+    for name in CONTROL_NAMES {
+        for chip in [Some(chip_type), ref_chip].into_iter().flatten() {
+            if control_gpio(board, chip, name) == gpio {
+                return Some(Line::Control(chip, name));
+            }
+        }
+    }
+    if board.pin_x1() == gpio {
+        return Some(Line::X(board.bit_x1()));
+    }
+    if board.pin_x2() == gpio {
+        return Some(Line::X(board.bit_x2()));
+    }
+    None
+}
@@ -0,0 +1,322 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Pin compatibility between two chip types.
+//!
+//! Compares the pinout of the chip a host socket was designed for with that
+//! of a part to be fitted in its place, from each type's [`ChipTypeInfo`]:
+//! which pins carry the same signals, which differ, and the bodge wires or
+//! socket adapter needed to make the part work. The part is seated as
+//! `onerom-gen` seats a chip in a socket of a different size, at the end
+//! away from the notch.
+//!
+//! One ROM itself needs none of this: it serves whatever the config says,
+//! so configured as the socket's chip type it stands in for the part as
+//! long as the image is the same size.
+
+use serde::Serialize;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::ChipType;
+use onerom_config::hw::BOARDS;
+
+use crate::board_pins::pin_offset;
+use crate::{ChipTypeInfo, chip_type_info, custom_chips};
+
+/// Comparison of two chip types, from [`chip_compatibility`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct ChipCompatibility {
+    /// Chip type the socket was designed for.
+    pub socket_chip: String,
+    /// Chip type fitted in its place.
+    pub part_chip: String,
+    /// Socket pin number minus part pin number. Negative when the part
+    /// overhangs the socket.
+    pub pin_offset: i8,
+    /// One entry per socket pin, in order, followed by any part pins
+    /// overhanging the socket.
+    pub pins: Vec<PinComparison>,
+    /// Wires needed from socket pins to part pins. Each part pin wired is
+    /// lifted out of the socket.
+    pub wires: Vec<AdapterWire>,
+    /// Whether the part can be plugged straight into the socket.
+    pub drop_in: bool,
+    /// Whether One ROM, configured as `socket_chip`, can serve the part's
+    /// image with no wiring changes.
+    pub served_by_config: bool,
+    /// Boards on which One ROM can be configured as `socket_chip`.
+    pub boards: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+/// A single socket position in a [`ChipCompatibility`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct PinComparison {
+    /// Socket pin, or `None` where the part overhangs the socket.
+    pub socket_pin: Option<u8>,
+    /// Signals the socket chip has on this pin, as in `BoardPinMapping`.
+    pub socket_signals: Vec<String>,
+    /// Part pin seated here, or `None` where the socket extends beyond it.
+    pub chip_pin: Option<u8>,
+    /// Signals the part has on that pin.
+    pub chip_signals: Vec<String>,
+    /// "match" (same signals), "control" (both only control lines, under
+    /// different names), "differs", "socket_only", "chip_only" or "nc" (no
+    /// signal on either side).
+    pub status: String,
+}
+
+/// A bodge wire or adapter trace from a [`ChipCompatibility`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct AdapterWire {
+    /// Part signal.
+    pub signal: String,
+    pub chip_pin: u8,
+    /// Socket pin to wire it to.
+    pub socket_pin: u8,
+    /// Socket signal on that pin; differs from `signal` where a programming
+    /// pin is tied to a supply.
+    pub socket_signal: String,
+}
+
+/// Compare chip type `part_chip`, fitted in a socket designed for
+/// `socket_chip`. Either may be a built-in or custom chip type.
+#[wasm_bindgen]
+pub fn chip_compatibility(
+    socket_chip: String,
+    part_chip: String,
+) -> Result<ChipCompatibility, JsValue> {
+    let socket = chip_type_info(socket_chip)?;
+    let part = chip_type_info(part_chip)?;
+    let offset = pin_offset(part.chip_pins, socket.chip_pins).ok_or_else(|| {
+        JsValue::from_str(&format!(
+            "Cannot seat a {}-pin {} in a {}-pin {} socket",
+            part.chip_pins, part.name, socket.chip_pins, socket.name
+        ))
+    })?;
+    let socket_pin = |chip_pin: u8| {
+        let pin = chip_pin as i16 + offset as i16;
+        (1..=socket.chip_pins as i16)
+            .contains(&pin)
+            .then_some(pin as u8)
+    };
+
+    let mut pins = Vec::new();
+    for pin in 1..=socket.chip_pins {
+        let chip_pin = pin as i16 - offset as i16;
+        let chip_pin = (1..=part.chip_pins as i16)
+            .contains(&chip_pin)
+            .then_some(chip_pin as u8);
+        pins.push(compare(&socket, Some(pin), &part, chip_pin));
+    }
+    for chip_pin in 1..=part.chip_pins {
+        if socket_pin(chip_pin).is_none() {
+            pins.push(compare(&socket, None, &part, Some(chip_pin)));
+        }
+    }
+
+    let mut wires = Vec::new();
+    let mut notes = Vec::new();
+    for chip_pin in 1..=part.chip_pins {
+        let seated = socket_pin(chip_pin);
+        for signal in signals(&part, chip_pin) {
+            if seated.is_some_and(|s| signals(&socket, s).contains(&signal)) {
+                continue;
+            }
+            if let Some(from) = signal_pin(&socket, &signal) {
+                wires.push(AdapterWire {
+                    signal: signal.clone(),
+                    chip_pin,
+                    socket_pin: from,
+                    socket_signal: signal,
+                });
+            } else if let Some(wire) = programming_tie(&socket, &part, chip_pin, &signal) {
+                wires.push(wire);
+            } else if !is_control(&part, &signal) {
+                notes.push(format!(
+                    "{} pin {chip_pin} ({signal}) has no equivalent in the {} socket",
+                    part.name, socket.name
+                ));
+            } else if seated.is_none_or(|s| !only_control(&socket, s)) {
+                notes.push(format!(
+                    "{} pin {chip_pin} ({signal}) must be wired to a chip select in the {} socket, or tied active",
+                    part.name, socket.name
+                ));
+            }
+        }
+    }
+    for pin in 1..=socket.chip_pins {
+        for signal in signals(&socket, pin) {
+            if signal_pin(&part, &signal).is_none()
+                && !(is_control(&socket, &signal)
+                    && pins
+                        .iter()
+                        .any(|p| p.socket_pin == Some(pin) && p.status == "control"))
+            {
+                notes.push(format!(
+                    "Socket pin {pin} ({signal}) is not used by the {}",
+                    part.name
+                ));
+            }
+        }
+    }
+    for p in pins.iter().filter(|p| p.status == "control") {
+        notes.push(format!(
+            "Socket pin {} ({}) drives {} pin {} ({}); check the host asserts it at the level the {} expects",
+            p.socket_pin.unwrap_or_default(),
+            p.socket_signals.join("/"),
+            part.name,
+            p.chip_pin.unwrap_or_default(),
+            p.chip_signals.join("/"),
+            part.name
+        ));
+    }
+
+    let drop_in = wires.is_empty()
+        && !pins
+            .iter()
+            .any(|p| p.status == "differs" || p.status == "chip_only");
+    if !drop_in && !wires.is_empty() {
+        notes.push(format!(
+            "Fit the {} on an adapter, or lift the wired pins out of the socket",
+            part.name
+        ));
+    }
+
+    let base = ChipType::try_from_str(&socket.name)
+        .or_else(|| custom_chips::custom_chip_base(&socket.name));
+    let boards: Vec<String> = match base {
+        Some(base) => BOARDS
+            .iter()
+            .filter(|b| b.supports_chip_type(base))
+            .map(|b| b.name().to_string())
+            .collect(),
+        None => Vec::new(),
+    };
+    let same_size = socket.size_bytes == part.size_bytes;
+    let same_function = socket.chip_function == part.chip_function;
+    let served_by_config = socket.is_supported && !boards.is_empty() && same_size && same_function;
+    if !same_function {
+        notes.push(format!(
+            "The {} is a {} and the {} a {}",
+            socket.name, socket.chip_function, part.name, part.chip_function
+        ));
+    } else if !same_size {
+        notes.push(format!(
+            "The {} holds {} bytes and the {} {}; its image must be cut or padded to serve it as a {}",
+            socket.name, socket.size_bytes, part.name, part.size_bytes, socket.name
+        ));
+    }
+    if served_by_config {
+        notes.push(format!(
+            "One ROM configured as a {} serves the {}'s image unchanged",
+            socket.name, part.name
+        ));
+    }
+
+    Ok(ChipCompatibility {
+        socket_chip: socket.name,
+        part_chip: part.name,
+        pin_offset: offset,
+        pins,
+        wires,
+        drop_in,
+        served_by_config,
+        boards,
+        notes,
+    })
+}
+
+fn compare(
+    socket: &ChipTypeInfo,
+    socket_pin: Option<u8>,
+    part: &ChipTypeInfo,
+    chip_pin: Option<u8>,
+) -> PinComparison {
+    let socket_signals = socket_pin.map_or_else(Vec::new, |p| signals(socket, p));
+    let chip_signals = chip_pin.map_or_else(Vec::new, |p| signals(part, p));
+    let status = match (socket_signals.is_empty(), chip_signals.is_empty()) {
+        (true, true) => "nc",
+        (false, true) => "socket_only",
+        (true, false) => "chip_only",
+        _ if socket_signals == chip_signals => "match",
+        _ if socket_pin.is_some_and(|p| only_control(socket, p))
+            && chip_pin.is_some_and(|p| only_control(part, p)) =>
+        {
+            "control"
+        }
+        _ => "differs",
+    };
+    PinComparison {
+        socket_pin,
+        socket_signals,
+        chip_pin,
+        chip_signals,
+        status: status.to_string(),
+    }
+}
+
+/// The signals on `pin` when reading. A programming pin sharing a pin with
+/// another signal is left out, as that signal drives it when reading.
+fn signals(info: &ChipTypeInfo, pin: u8) -> Vec<String> {
+    let signals = info.pin_signals(pin);
+    let is_programming = |s: &String| {
+        info.programming_pins
+            .iter()
+            .flatten()
+            .any(|p| p.pin == pin && p.name.eq_ignore_ascii_case(s))
+    };
+    if signals.iter().all(is_programming) {
+        signals
+    } else {
+        signals.into_iter().filter(|s| !is_programming(s)).collect()
+    }
+}
+
+/// The pin carrying `signal` on `info`, if any.
+fn signal_pin(info: &ChipTypeInfo, signal: &str) -> Option<u8> {
+    (1..=info.chip_pins).find(|&p| signals(info, p).iter().any(|s| s == signal))
+}
+
+fn is_control(info: &ChipTypeInfo, signal: &str) -> bool {
+    info.control_lines
+        .iter()
+        .any(|c| c.name.eq_ignore_ascii_case(signal))
+}
+
+/// Whether every signal on `pin` is a control line.
+fn only_control(info: &ChipTypeInfo, pin: u8) -> bool {
+    signals(info, pin).iter().all(|s| is_control(info, s))
+}
+
+/// A wire holding one of the part's programming pins at its read level.
+fn programming_tie(
+    socket: &ChipTypeInfo,
+    part: &ChipTypeInfo,
+    chip_pin: u8,
+    signal: &str,
+) -> Option<AdapterWire> {
+    let read_state = part
+        .programming_pins
+        .iter()
+        .flatten()
+        .find(|p| p.pin == chip_pin && p.name.eq_ignore_ascii_case(signal))?
+        .read_state
+        .as_str();
+    let supply = match read_state {
+        "Vcc" | "High" => "VCC",
+        "Low" => "GND",
+        _ => return None,
+    };
+    Some(AdapterWire {
+        signal: signal.to_string(),
+        chip_pin,
+        socket_pin: signal_pin(socket, supply)?,
+        socket_signal: supply.to_string(),
+    })
+}
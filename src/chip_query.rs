@@ -0,0 +1,119 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Chip type queries.
+//!
+//! Filters `CHIP_TYPES` and returns full [`ChipTypeInfo`] records in one
+//! call, so a chip type picker does not need `chip_type_info` for every
+//! name.
+
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::{CHIP_TYPES, ChipFunction, ChipType};
+use onerom_config::fw::FirmwareVersion;
+use onerom_config::hw::Board;
+
+use crate::{ChipTypeInfo, chip_info, custom_chips};
+
+/// Filter for [`query_chip_types`]. Every field is optional; a chip type
+/// must match all that are given.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ChipTypeFilter {
+    /// Package pin count.
+    chip_pins: Option<u8>,
+    /// Inclusive bounds on size in bytes.
+    min_size: Option<usize>,
+    max_size: Option<usize>,
+    /// "rom" or "ram".
+    chip_function: Option<String>,
+    /// A bit mode the chip type must support, 8 or 16.
+    bit_mode: Option<u8>,
+    /// Exact number of CS lines whose polarity is configurable.
+    configurable_cs: Option<usize>,
+    /// Board the chip type must be usable on.
+    board: Option<String>,
+    /// Firmware version the chip type must be supported by.
+    firmware_version: Option<String>,
+    /// Case-insensitive substring of the name or an alias.
+    search: Option<String>,
+    /// Only chip types supported by the latest One ROM.
+    supported_only: bool,
+    /// Include plugins, which are otherwise left out as by `chip_types`.
+    include_plugins: bool,
+}
+
+/// Return full information on every chip type matching `filter`, in
+/// `CHIP_TYPES` order, then any loaded custom types.
+///
+/// `filter` may be omitted, returning every chip type bar plugins, or an
+/// object with any of `chip_pins`, `min_size`, `max_size`,
+/// `chip_function` ("rom" or "ram"), `bit_mode`, `configurable_cs`,
+/// `board`, `firmware_version`, `search`, `supported_only` and
+/// `include_plugins`.
+#[wasm_bindgen]
+pub fn query_chip_types(filter: JsValue) -> Result<Vec<ChipTypeInfo>, JsValue> {
+    let filter: ChipTypeFilter = crate::options(filter)?;
+
+    let function = match filter.chip_function.as_deref().map(str::to_lowercase) {
+        None => None,
+        Some(f) if f == "rom" => Some(ChipFunction::Rom),
+        Some(f) if f == "ram" => Some(ChipFunction::Ram),
+        Some(f) => {
+            return Err(JsValue::from_str(&format!(
+                "Unknown chip function: {f}, use rom or ram"
+            )));
+        }
+    };
+    let board = match &filter.board {
+        Some(name) => Some(
+            Board::try_from_str(name)
+                .ok_or_else(|| JsValue::from_str(&format!("Unknown board: {}", name)))?,
+        ),
+        None => None,
+    };
+    let version = match &filter.firmware_version {
+        Some(v) => Some(
+            FirmwareVersion::try_from_str(v)
+                .map_err(|_| JsValue::from_str("Invalid firmware version format"))?,
+        ),
+        None => None,
+    };
+    let search = filter.search.as_deref().map(str::to_lowercase);
+
+    // What a chip type is comes from its information, which a custom type
+    // has its own of; where it can be served from, from the type it is
+    // served as.
+    let matches = |info: &ChipTypeInfo, served_as: ChipType| {
+        (filter.include_plugins || !info.is_plugin)
+            && (!filter.supported_only || info.is_supported)
+            && filter.chip_pins.is_none_or(|p| info.chip_pins == p)
+            && filter.min_size.is_none_or(|s| info.size_bytes >= s)
+            && filter.max_size.is_none_or(|s| info.size_bytes <= s)
+            && function.is_none_or(|f| info.chip_function == format!("{f:?}"))
+            && filter.bit_mode.is_none_or(|m| info.bit_modes.contains(&m))
+            && filter
+                .configurable_cs
+                .is_none_or(|n| info.control_lines.iter().filter(|l| l.configurable).count() == n)
+            && board.is_none_or(|b| b.supports_chip_type(served_as))
+            && version.is_none_or(|v| {
+                served_as
+                    .min_supported_firmware_version()
+                    .is_some_and(|min| min <= v)
+            })
+            && search.as_deref().is_none_or(|s| {
+                info.name.to_lowercase().contains(s)
+                    || info.aliases.iter().any(|a| a.to_lowercase().contains(s))
+            })
+    };
+
+    Ok(CHIP_TYPES
+        .iter()
+        .map(|&t| (chip_info(t), t))
+        .chain(custom_chips::custom_chip_infos())
+        .filter(|(info, served_as)| matches(info, *served_as))
+        .map(|(info, _)| info)
+        .collect())
+}
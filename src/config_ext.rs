@@ -0,0 +1,511 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Per-ROM config fields handled by this crate rather than by `onerom-gen`.
+//!
+//! `onerom-gen` ignores config keys it does not recognise, so a config can
+//! carry extra per-ROM fields that only the web tooling acts on. They are
+//! parsed here from the same JSON, walking sets and ROMs in the order
+//! `onerom-gen` numbers them, so ROM indices line up with
+//! [`onerom_gen::Builder::file_id_map`] and everything is keyed by file ID.
+//!
+//! Files only this crate needs, such as patches and the second file of an
+//! interleaved pair, get file IDs of their own,
+//! numbered on from the last ROM file. They are fetched through the same file
+//! specs as ROM files, and a ROM file that depends on them is held back from
+//! the `onerom-gen` builder until they have all arrived.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Deserialize;
+
+use crate::digest;
+use crate::patch::{self, PatchFormat};
+use crate::transform::{RawTransform, Transform};
+
+/// Just enough of the config to reach the per-ROM extension fields.
+#[derive(Deserialize)]
+struct RawConfig {
+    #[serde(default, alias = "rom_sets")]
+    chip_sets: Vec<RawChipSet>,
+}
+
+#[derive(Deserialize)]
+struct RawChipSet {
+    #[serde(default, alias = "roms")]
+    chips: Vec<RawChip>,
+}
+
+#[derive(Deserialize)]
+struct RawChip {
+    #[serde(default)]
+    file: String,
+    extract: Option<String>,
+    #[serde(flatten)]
+    digests: RawDigests,
+    #[serde(default)]
+    patches: Vec<RawFileRef>,
+    #[serde(default)]
+    transforms: Vec<RawTransform>,
+    /// Line swaps putting the file in the order of the chip type it is
+    /// served as. Written by [`custom_chips`](crate::custom_chips), and
+    /// applied last, so transforms and patches see the file as published.
+    #[serde(default)]
+    reorder: Vec<RawTransform>,
+}
+
+#[derive(Default, Deserialize)]
+struct RawDigests {
+    sha256: Option<String>,
+    crc32: Option<RawCrc32>,
+    archive_sha256: Option<String>,
+}
+
+/// CRC32s are accepted either as a hex string or as a plain JSON number.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawCrc32 {
+    Number(u32),
+    Hex(String),
+}
+
+/// An extra file is either just its URL, or an object with the URL in `file`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawFileRef {
+    Url(String),
+    Spec(RawFileSpec),
+}
+
+impl RawFileRef {
+    fn into_spec(self) -> RawFileSpec {
+        match self {
+            RawFileRef::Url(file) => RawFileSpec {
+                file,
+                ..Default::default()
+            },
+            RawFileRef::Spec(spec) => spec,
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct RawFileSpec {
+    file: String,
+    extract: Option<String>,
+    /// Patch format; patches only.
+    format: Option<String>,
+    description: Option<String>,
+    #[serde(flatten)]
+    digests: RawDigests,
+}
+
+/// Digests a config declares for one file.
+///
+/// `sha256` and `crc32` cover the file itself as fetched - after extraction,
+/// where it comes from an archive, but before any transforms or patches are
+/// applied.
+/// `archive_sha256` covers the archive as downloaded, and is only meaningful
+/// alongside `extract`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ExpectedDigests {
+    pub sha256: Option<String>,
+    pub crc32: Option<u32>,
+    pub archive_sha256: Option<String>,
+}
+
+impl ExpectedDigests {
+    fn parse(raw: &RawDigests, extract: Option<&str>) -> Result<Self, String> {
+        let expected = Self {
+            sha256: raw
+                .sha256
+                .as_deref()
+                .map(digest::parse_sha256)
+                .transpose()?,
+            crc32: match &raw.crc32 {
+                None => None,
+                Some(RawCrc32::Number(n)) => Some(*n),
+                Some(RawCrc32::Hex(s)) => Some(digest::parse_crc32(s)?),
+            },
+            archive_sha256: raw
+                .archive_sha256
+                .as_deref()
+                .map(digest::parse_sha256)
+                .transpose()?,
+        };
+        if expected.archive_sha256.is_some() && extract.is_none() {
+            return Err("archive_sha256 is only valid with extract".to_string());
+        }
+        Ok(expected)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.sha256.is_none() && self.crc32.is_none() && self.archive_sha256.is_none()
+    }
+}
+
+/// What an extra (non-ROM) file is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExtraKind {
+    Patch,
+    Interleave,
+}
+
+impl ExtraKind {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Patch => "patch",
+            Self::Interleave => "interleave",
+        }
+    }
+}
+
+/// A file this crate needs beyond the ROM files `onerom-gen` knows about.
+#[derive(Debug, Clone)]
+pub(crate) struct ExtraFile {
+    pub id: usize,
+    pub kind: ExtraKind,
+    pub source: String,
+    pub extract: Option<String>,
+    pub description: Option<String>,
+    /// The (first) ROM file this one is needed for.
+    pub rom_file_id: usize,
+}
+
+/// A patch applied to a ROM file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PatchRef {
+    file_id: usize,
+    format: Option<PatchFormat>,
+}
+
+/// What happens to a ROM file between arriving and going to the builder:
+/// transforms, then patches, then reordering.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Pipeline {
+    transforms: Vec<Transform>,
+    patches: Vec<PatchRef>,
+    reorder: Vec<Transform>,
+}
+
+impl Pipeline {
+    fn is_empty(&self) -> bool {
+        self.transforms.is_empty() && self.patches.is_empty() && self.reorder.is_empty()
+    }
+
+    /// Extra files this pipeline needs.
+    fn file_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.transforms
+            .iter()
+            .filter_map(Transform::file_id)
+            .chain(self.patches.iter().map(|p| p.file_id))
+    }
+}
+
+/// Extension state carried alongside the `onerom-gen` builder.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ConfigExt {
+    /// Number of ROM files `onerom-gen` expects; extra file IDs follow on.
+    rom_file_count: usize,
+    /// Expected digests, by file ID. Files with none declared are absent.
+    digests: BTreeMap<usize, ExpectedDigests>,
+    /// Extra files, in file ID order.
+    extra_files: Vec<ExtraFile>,
+    /// Transforms and patches to apply, by ROM file ID. Files with neither are
+    /// absent.
+    pipelines: BTreeMap<usize, Pipeline>,
+    /// Files received but not yet passed on to the builder.
+    received: BTreeMap<usize, Vec<u8>>,
+    /// ROM files with dependencies that have been passed on to the builder.
+    completed: BTreeSet<usize>,
+}
+
+impl ConfigExt {
+    /// Parse the extension fields from `config_json`.
+    ///
+    /// `file_id_map` is the builder's ROM index to file ID map. ROMs sharing a
+    /// file must not declare conflicting digests, transforms or patches for it.
+    pub(crate) fn from_json(
+        config_json: &str,
+        file_id_map: &BTreeMap<usize, usize>,
+    ) -> Result<Self, String> {
+        let raw: RawConfig =
+            serde_json::from_str(config_json).map_err(|e| format!("Error parsing config: {e}"))?;
+
+        let rom_file_count = file_id_map.values().collect::<BTreeSet<_>>().len();
+        let mut ext = Self {
+            rom_file_count,
+            ..Self::default()
+        };
+        let mut extra_ids: BTreeMap<(String, Option<String>), usize> = BTreeMap::new();
+
+        // Pipelines by ROM file, including empty ones, so ROMs sharing a file
+        // can be checked for agreement.
+        let mut pipelines: BTreeMap<usize, Pipeline> = BTreeMap::new();
+
+        let chips = raw.chip_sets.into_iter().flat_map(|set| set.chips);
+        for (rom_id, chip) in chips.enumerate() {
+            let rom_err = |e: String| format!("ROM {rom_id}: {e}");
+
+            let expected =
+                ExpectedDigests::parse(&chip.digests, chip.extract.as_deref()).map_err(rom_err)?;
+            let Some(&file_id) = file_id_map.get(&rom_id) else {
+                if !expected.is_empty()
+                    || !chip.patches.is_empty()
+                    || !chip.transforms.is_empty()
+                    || !chip.reorder.is_empty()
+                {
+                    return Err(rom_err(
+                        "digests, transforms or patches given but no file".to_string(),
+                    ));
+                }
+                continue;
+            };
+            ext.add_digests(file_id, expected).map_err(rom_err)?;
+
+            let mut pipeline = Pipeline::default();
+            for raw in chip.transforms {
+                let transform = Transform::from_raw(raw, |with| {
+                    let with: RawFileRef = serde_json::from_value(with)
+                        .map_err(|e| format!("invalid interleave file: {e}"))?;
+                    ext.extra_file(&mut extra_ids, ExtraKind::Interleave, with, file_id)
+                        .map(|(id, _)| id)
+                })
+                .map_err(|e| rom_err(format!("transform: {e}")))?;
+                pipeline.transforms.push(transform);
+            }
+            for raw in chip.patches {
+                let (id, format) = ext
+                    .extra_file(&mut extra_ids, ExtraKind::Patch, raw, file_id)
+                    .map_err(|e| rom_err(format!("patch: {e}")))?;
+                let format = format
+                    .as_deref()
+                    .map(|f| {
+                        PatchFormat::try_from_str(f)
+                            .ok_or_else(|| rom_err(format!("unknown patch format '{f}'")))
+                    })
+                    .transpose()?;
+                pipeline.patches.push(PatchRef {
+                    file_id: id,
+                    format,
+                });
+            }
+            for raw in chip.reorder {
+                let transform = Transform::from_raw(raw, |_| {
+                    Err("reordering cannot interleave another file".to_string())
+                })
+                .map_err(|e| rom_err(format!("reorder: {e}")))?;
+                pipeline.reorder.push(transform);
+            }
+
+            match pipelines.get(&file_id) {
+                Some(existing) if *existing != pipeline => {
+                    return Err(rom_err(format!(
+                        "transforms or patches differ from another ROM using '{}'",
+                        chip.file
+                    )));
+                }
+                Some(_) => {}
+                None => {
+                    pipelines.insert(file_id, pipeline);
+                }
+            }
+        }
+
+        ext.pipelines = pipelines
+            .into_iter()
+            .filter(|(_, pipeline)| !pipeline.is_empty())
+            .collect();
+
+        Ok(ext)
+    }
+
+    /// Record digests for a file, checking they agree with any already given.
+    fn add_digests(&mut self, file_id: usize, expected: ExpectedDigests) -> Result<(), String> {
+        if expected.is_empty() {
+            return Ok(());
+        }
+        match self.digests.get(&file_id) {
+            Some(existing) if *existing != expected => {
+                Err("digests conflict with another use of the same file".to_string())
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.digests.insert(file_id, expected);
+                Ok(())
+            }
+        }
+    }
+
+    /// The file ID for an extra file, allocating one the first time the
+    /// file (and extract) is seen, and its patch format if given.
+    fn extra_file(
+        &mut self,
+        ids: &mut BTreeMap<(String, Option<String>), usize>,
+        kind: ExtraKind,
+        raw: RawFileRef,
+        rom_file_id: usize,
+    ) -> Result<(usize, Option<String>), String> {
+        let spec = raw.into_spec();
+        let expected = ExpectedDigests::parse(&spec.digests, spec.extract.as_deref())?;
+
+        let key = (spec.file.clone(), spec.extract.clone());
+        let id = match ids.get(&key) {
+            Some(&id) => id,
+            None => {
+                let id = self.rom_file_count + self.extra_files.len();
+                ids.insert(key, id);
+                self.extra_files.push(ExtraFile {
+                    id,
+                    kind,
+                    source: spec.file,
+                    extract: spec.extract,
+                    description: spec.description,
+                    rom_file_id,
+                });
+                id
+            }
+        };
+        self.add_digests(id, expected)?;
+
+        Ok((id, spec.format))
+    }
+
+    /// Expected digests for `file_id`, if the config declared any.
+    pub(crate) fn digests(&self, file_id: usize) -> Option<&ExpectedDigests> {
+        self.digests.get(&file_id)
+    }
+
+    /// Files needed beyond the ROM files, in file ID order.
+    pub(crate) fn extra_files(&self) -> &[ExtraFile] {
+        &self.extra_files
+    }
+
+    /// Check `data` (and `archive`, the download it was extracted from, if
+    /// any) against the digests declared for `file_id`.
+    ///
+    /// An `archive_sha256` with no archive supplied is an error rather than a
+    /// skipped check, so a caller that forgets to pass it finds out.
+    pub(crate) fn verify(
+        &self,
+        file_id: usize,
+        data: &[u8],
+        archive: Option<&[u8]>,
+    ) -> Result<(), String> {
+        let Some(expected) = self.digests(file_id) else {
+            return Ok(());
+        };
+
+        if let Some(want) = &expected.archive_sha256 {
+            let archive = archive.ok_or_else(|| {
+                format!("File {file_id}: archive_sha256 given but archive not supplied")
+            })?;
+            let got = digest::sha256_hex(archive);
+            if &got != want {
+                return Err(format!(
+                    "File {file_id}: archive sha256 mismatch (expected {want}, got {got})"
+                ));
+            }
+        }
+
+        if let Some(want) = &expected.sha256 {
+            let got = digest::sha256_hex(data);
+            if &got != want {
+                return Err(format!(
+                    "File {file_id}: sha256 mismatch (expected {want}, got {got})"
+                ));
+            }
+        }
+
+        if let Some(want) = expected.crc32 {
+            let got = digest::crc32(data);
+            if got != want {
+                return Err(format!(
+                    "File {file_id}: crc32 mismatch (expected {}, got {})",
+                    digest::crc32_hex(want),
+                    digest::crc32_hex(got)
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether `file_id` has to wait for other files before it can go to the
+    /// builder.
+    fn has_dependencies(&self, file_id: usize) -> bool {
+        self.pipelines.contains_key(&file_id)
+    }
+
+    /// Take in a verified file, returning every ROM file that is now ready for
+    /// the builder, as `(file ID, data)`.
+    ///
+    /// ROM files without dependencies pass straight through, as do IDs this
+    /// crate does not know, so the builder reports those.
+    pub(crate) fn receive(
+        &mut self,
+        file_id: usize,
+        data: Vec<u8>,
+    ) -> Result<Vec<(usize, Vec<u8>)>, String> {
+        let is_extra = file_id >= self.rom_file_count
+            && file_id < self.rom_file_count + self.extra_files.len();
+        if !is_extra && !self.has_dependencies(file_id) {
+            return Ok(vec![(file_id, data)]);
+        }
+        if self.received.contains_key(&file_id) || self.completed.contains(&file_id) {
+            return Err(format!("File {file_id} already added"));
+        }
+        self.received.insert(file_id, data);
+
+        let ready: Vec<usize> = self
+            .pipelines
+            .keys()
+            .copied()
+            .filter(|id| !self.completed.contains(id) && self.dependencies_received(*id))
+            .collect();
+
+        let mut out = Vec::new();
+        for rom_file_id in ready {
+            let data = self.prepare(rom_file_id)?;
+            self.received.remove(&rom_file_id);
+            self.completed.insert(rom_file_id);
+            out.push((rom_file_id, data));
+        }
+        Ok(out)
+    }
+
+    /// Whether a ROM file and everything it depends on have been received.
+    fn dependencies_received(&self, rom_file_id: usize) -> bool {
+        self.received.contains_key(&rom_file_id)
+            && self
+                .pipelines
+                .get(&rom_file_id)
+                .is_none_or(|p| p.file_ids().all(|id| self.received.contains_key(&id)))
+    }
+
+    /// Produce the data for a ROM file from it and its dependencies.
+    fn prepare(&self, rom_file_id: usize) -> Result<Vec<u8>, String> {
+        let mut data = self.received[&rom_file_id].clone();
+        let Some(pipeline) = self.pipelines.get(&rom_file_id) else {
+            return Ok(data);
+        };
+
+        for (n, t) in pipeline.transforms.iter().enumerate() {
+            let other = t.file_id().map(|id| self.received[&id].as_slice());
+            data = t
+                .apply(data, other)
+                .map_err(|e| format!("File {rom_file_id}: transform {n}: {e}"))?;
+        }
+        for p in &pipeline.patches {
+            data = patch::apply(&data, &self.received[&p.file_id], p.format)
+                .map_err(|e| format!("File {rom_file_id}: patch file {}: {e}", p.file_id))?;
+        }
+        for (n, t) in pipeline.reorder.iter().enumerate() {
+            data = t
+                .apply(data, None)
+                .map_err(|e| format!("File {rom_file_id}: reorder {n}: {e}"))?;
+        }
+        Ok(data)
+    }
+}
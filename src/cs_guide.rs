@@ -0,0 +1,614 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Chip select and jumper guide.
+//!
+//! Works in both directions between what a user wants and what they must
+//! set:
+//!
+//! - [`cs_jumper_guide`] takes a chip type, the CS polarities of the ROM being
+//!   replaced and the set to serve, and returns the SEL jumpers to close and
+//!   the config a chip set needs, checked by `onerom-gen`.
+//! - [`explain_cs_jumpers`] takes a config and the SEL jumpers that are
+//!   closed, and says which set will be served and how each chip is selected.
+//!
+//! A closed SEL jumper ties its pin to the opposite rail from its pull, and
+//! the firmware reads it as a 1: the jumpers form the set number, SEL0 being
+//! the least significant bit. In a multi-chip set the second and third chips
+//! are selected through X1 and X2, which must be wired to those chips' CS
+//! lines on the host board.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::{ChipType, ControlLineSpec};
+use onerom_config::fw::FirmwareVersion;
+use onerom_config::hw::Board;
+use onerom_gen::Builder as GenBuilder;
+
+use crate::board_pins::{BoardPinMapping, pin_mapping};
+use crate::custom_chips;
+use crate::level;
+
+const V2: FirmwareVersion = onerom_gen::MIN_SUPPORTED_FIRMWARE_VERSION_V2;
+
+/// What to set, or what will happen, from [`cs_jumper_guide`] or
+/// [`explain_cs_jumpers`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct CsGuide {
+    pub board: String,
+    /// The set the jumpers select.
+    pub set_id: usize,
+    pub sel_jumpers: Vec<SelJumper>,
+    /// The chips in the set, in config order.
+    pub chips: Vec<ChipSelectGuide>,
+    /// X pins used by the set. Empty for single-chip sets.
+    pub x_pins: Vec<XPinGuide>,
+    /// The set's `chip_sets` entry. From [`cs_jumper_guide`], with
+    /// placeholder `file`s to fill in.
+    pub chip_set_json: String,
+    /// Anything else the user needs to know, including why `onerom-gen`
+    /// rejects the set, if it does.
+    pub notes: Vec<String>,
+}
+
+/// A SEL jumper in a [`CsGuide`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct SelJumper {
+    /// 0 for SEL0, and so on.
+    pub sel: u8,
+    pub gpio: u8,
+    /// "down" or "up".
+    pub pull: String,
+    pub closed: bool,
+    /// "SWCLK" or "SWDIO" where the pin doubles as a debug pin.
+    pub shared_with: Option<String>,
+}
+
+/// How one chip of a set is selected, in a [`CsGuide`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct ChipSelectGuide {
+    pub chip_idx: usize,
+    pub chip_type: String,
+    /// Where the firmware reads this chip's CS1 from: "CS1" for the chip in
+    /// the One ROM's own socket, "X1" or "X2" otherwise.
+    pub select_pin: String,
+    pub lines: Vec<ControlLineGuide>,
+}
+
+/// A control line of a chip in a [`ChipSelectGuide`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct ControlLineGuide {
+    /// "CS1", "CS2", "CS3", "CE" or "OE".
+    pub name: String,
+    pub chip_pin: u8,
+    /// Socket pin on the board, where the chip does not overhang it.
+    pub socket_pin: Option<u8>,
+    pub gpio: Option<u8>,
+    /// "active_low", "active_high" or "ignore".
+    pub logic: String,
+    /// Whether the chip type fixes the polarity.
+    pub fixed: bool,
+    /// The value the config states for this line, if it states one.
+    pub config_value: Option<String>,
+    pub explanation: String,
+}
+
+/// An X pin used by a multi-chip set, in a [`CsGuide`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct XPinGuide {
+    /// "X1" or "X2".
+    pub name: String,
+    pub gpio: u8,
+    /// "down" or "up".
+    pub pull: String,
+    /// The chip it selects.
+    pub chip_idx: usize,
+    pub explanation: String,
+}
+
+/// Input to [`cs_jumper_guide`]. All optional, bar the polarity of any
+/// configurable CS line.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct CsRequest {
+    cs1: Option<String>,
+    cs2: Option<String>,
+    cs3: Option<String>,
+    ce: Option<String>,
+    oe: Option<String>,
+    /// Set to select with the SEL jumpers, default 0.
+    set_id: usize,
+    /// Chips in the set, default 1. More than one makes a multi-chip set.
+    chips: usize,
+    /// The line of each further chip in a multi-chip set wired to an X pin,
+    /// default CS1 (or the chip type's first control line).
+    select_line: Option<String>,
+    /// Firmware version the config is for, default 0.7.0.
+    version: Option<String>,
+}
+
+impl CsRequest {
+    fn logic(&self, line: &str) -> Option<&str> {
+        match line {
+            "cs1" => self.cs1.as_deref(),
+            "cs2" => self.cs2.as_deref(),
+            "cs3" => self.cs3.as_deref(),
+            "ce" => self.ce.as_deref(),
+            "oe" => self.oe.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Work out the jumpers and config to serve `chip_type` from `board`.
+///
+/// A custom chip type is served as its built-in base type, so the guide,
+/// and the CS lines in `request`, are in terms of that type's lines.
+///
+/// `request` may be omitted for chip types with no configurable CS lines,
+/// or an object with any of:
+/// - `cs1`, `cs2`, `cs3`, `ce`, `oe`: "active_low", "active_high" or
+///   "ignore". Required for CS lines the chip type leaves configurable (the
+///   23xx mask ROMs), which must match the ROM being replaced.
+/// - `set_id`: the set to select with the SEL jumpers, default 0.
+/// - `chips`: the number of chips in the set, default 1.
+/// - `select_line`: in a multi-chip set, the line of each further chip wired
+///   to X1 or X2, default "cs1". Its other lines are commoned with the first
+///   chip's. Always CS1 before firmware 0.7.0.
+/// - `version`: the firmware version to write the config for, default
+///   0.7.0.
+#[wasm_bindgen]
+pub fn cs_jumper_guide(
+    board: String,
+    chip_type: String,
+    request: JsValue,
+) -> Result<CsGuide, JsValue> {
+    let board = Board::try_from_str(&board)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown board: {}", board)))?;
+    let chip_type =
+        custom_chips::socket_chip_type(board, &chip_type).map_err(|e| JsValue::from_str(&e))?;
+    let request: CsRequest = crate::options(request)?;
+    guide(board, chip_type, &request).map_err(|e| JsValue::from_str(&e))
+}
+
+/// Explain what `board` will serve from `config_json` with SEL jumpers
+/// `closed_sels` (0 for SEL0, and so on) closed.
+#[wasm_bindgen]
+pub fn explain_cs_jumpers(
+    board: String,
+    config_json: &str,
+    closed_sels: Vec<u8>,
+) -> Result<CsGuide, JsValue> {
+    let board = Board::try_from_str(&board)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown board: {}", board)))?;
+    explain(board, config_json, &closed_sels).map_err(|e| JsValue::from_str(&e))
+}
+
+fn guide(board: Board, chip_type: ChipType, request: &CsRequest) -> Result<CsGuide, String> {
+    let version = match &request.version {
+        Some(v) => FirmwareVersion::try_from_str(v)
+            .map_err(|_| "Invalid firmware version format".to_string())?,
+        None => V2,
+    };
+    let chip_count = request.chips.max(1);
+    if chip_count > 3 {
+        return Err("A multi-chip set holds at most 3 chips".to_string());
+    }
+    if chip_count > 1 && !board.supports_multi_chip_sets() {
+        return Err(format!("{} does not support multi-chip sets", board.name()));
+    }
+    let sel_jumpers = sel_jumpers(board, request.set_id)?;
+    let mapping = pin_mapping(board, chip_type)?;
+
+    // Each line's logic, as wanted for the first chip.
+    let mut logics = Vec::new();
+    for line in chip_type.control_lines() {
+        let fixed = line.line_type.fixed_active_level();
+        let logic = match (request.logic(line.name), fixed) {
+            (Some(l @ ("active_low" | "active_high" | "ignore")), None) => l,
+            (Some("ignore"), Some(_)) => "ignore",
+            (Some(l), Some(high)) if l == level(high) => l,
+            (Some(l @ ("active_low" | "active_high")), Some(high)) => {
+                return Err(format!(
+                    "{} on a {} is fixed {}, not {l}",
+                    line.name.to_uppercase(),
+                    chip_type.name(),
+                    level(high)
+                ));
+            }
+            (Some(l), _) => {
+                return Err(format!(
+                    "Invalid {} logic '{l}': use active_low, active_high or ignore",
+                    line.name
+                ));
+            }
+            (None, Some(high)) => level(high),
+            (None, None) => {
+                return Err(format!(
+                    "{} polarity is needed: {} CS lines are set when the ROM is made, so \
+                     take it from the ROM being replaced",
+                    line.name.to_uppercase(),
+                    chip_type.name()
+                ));
+            }
+        };
+        logics.push((line, logic));
+    }
+
+    // The line fly-leaded from each further chip to an X pin. Before 0.7.0
+    // it is always CS1.
+    let select = match &request.select_line {
+        Some(name) if version >= V2 => logics
+            .iter()
+            .map(|(line, _)| line.name)
+            .find(|n| n == name)
+            .ok_or_else(|| format!("A {} has no {name} line", chip_type.name()))?,
+        _ => logics
+            .iter()
+            .map(|(line, _)| line.name)
+            .find(|&n| n == "cs1")
+            .or(logics.first().map(|(line, _)| line.name))
+            .unwrap_or("cs1"),
+    };
+
+    let mut chips_json = Vec::new();
+    let mut chips = Vec::new();
+    for idx in 0..chip_count {
+        let mut chip_json = Map::new();
+        chip_json.insert("file".to_string(), json!(format!("chip{idx}.bin")));
+        chip_json.insert("type".to_string(), json!(chip_type.name()));
+        let mut lines = Vec::new();
+        let mut allow_ignore = false;
+        for &(line, logic) in &logics {
+            let is_cs = is_cs_line(line);
+            // From 0.7.0 a further chip only watches its select line; the
+            // rest are commoned with the first chip's.
+            let logic = if idx > 0 && version >= V2 && logics.len() > 1 && line.name != select {
+                "ignore"
+            } else {
+                logic
+            };
+            let stated = if version >= V2 {
+                line.line_type.fixed_active_level().is_none() || logic == "ignore"
+            } else {
+                is_cs
+            };
+            if stated {
+                chip_json.insert(line.name.to_string(), json!(logic));
+            }
+            if version >= V2 && idx == 0 && logic == "ignore" && !line.allow_ignore {
+                allow_ignore = true;
+            }
+            // Before 0.7.0 the firmware does not look at CE/OE.
+            let logic = if is_cs || version >= V2 {
+                logic
+            } else {
+                "ignore"
+            };
+            lines.push(line_guide(
+                &mapping,
+                line,
+                logic,
+                stated.then(|| logic.to_string()),
+            ));
+        }
+        if allow_ignore {
+            chip_json.insert("allow_cs_ignore".to_string(), json!(true));
+        }
+        chips_json.push(Value::Object(chip_json));
+        chips.push(chip_guide(board, chip_type, idx, select, lines));
+    }
+    let set_type = if chip_count > 1 { "multi" } else { "single" };
+    let set_json = json!({ "type": set_type, "chips": chips_json });
+    let select_logic = logics
+        .iter()
+        .find(|(line, _)| line.name == select)
+        .map(|&(_, logic)| logic);
+
+    let mut notes = Vec::new();
+    if version < V2 && chip_type.control_lines().iter().any(|l| !is_cs_line(l)) {
+        notes.push(format!(
+            "Firmware {version} does not check CE or OE; from {V2} they can be honoured"
+        ));
+    }
+    if let Some(reason) = check_set(board, version, &set_json, request.set_id) {
+        notes.push(format!("onerom-gen rejects this set: {reason}"));
+    }
+
+    Ok(CsGuide {
+        board: board.name().to_string(),
+        set_id: request.set_id,
+        sel_jumpers,
+        x_pins: x_pins(board, chip_count, select, select_logic),
+        chips,
+        chip_set_json: serde_json::to_string_pretty(&set_json)
+            .map_err(|e| format!("Error writing config: {e}"))?,
+        notes,
+    })
+}
+
+fn explain(board: Board, config_json: &str, closed_sels: &[u8]) -> Result<CsGuide, String> {
+    // Custom chip types are served, and so selected, as their base types.
+    let resolved = custom_chips::resolve_config(config_json)?;
+    let config_json = resolved.as_deref().unwrap_or(config_json);
+    let pulls = board.sel_jumper_pulls();
+    let mut set_id = 0;
+    for &sel in closed_sels {
+        if sel as usize >= pulls.len() {
+            return Err(format!(
+                "{} has no SEL{sel}: it has {} SEL jumpers",
+                board.name(),
+                pulls.len()
+            ));
+        }
+        set_id |= 1 << sel;
+    }
+    let sel_jumpers = sel_jumpers(board, set_id)?;
+
+    let config: Value =
+        serde_json::from_str(config_json).map_err(|e| format!("Error parsing config: {e}"))?;
+    let sets = config
+        .get("chip_sets")
+        .or_else(|| config.get("rom_sets"))
+        .and_then(Value::as_array)
+        .ok_or("Config has no chip_sets")?;
+
+    let mut notes = Vec::new();
+    let Some(set) = sets.get(set_id) else {
+        notes.push(format!(
+            "The jumpers select set {set_id}, but the config only has {} set(s), so nothing \
+             will be served as intended",
+            sets.len()
+        ));
+        return Ok(CsGuide {
+            board: board.name().to_string(),
+            set_id,
+            sel_jumpers,
+            chips: Vec::new(),
+            x_pins: Vec::new(),
+            chip_set_json: String::new(),
+            notes,
+        });
+    };
+
+    let set_type = set.get("type").and_then(Value::as_str).unwrap_or("single");
+    let chips_json = set
+        .get("chips")
+        .or_else(|| set.get("roms"))
+        .and_then(Value::as_array)
+        .ok_or_else(|| format!("Set {set_id} has no chips"))?;
+    if set_type == "banked" {
+        notes.push("A banked set: X1 and X2 pick which chip is served, rather than selecting chips on the bus".to_string());
+    }
+
+    // A further chip's select line is the one it does not ignore.
+    let select = chips_json
+        .get(1)
+        .and_then(|chip| {
+            let chip_type = chip
+                .get("type")
+                .and_then(Value::as_str)
+                .and_then(ChipType::try_from_str)?;
+            let mut active = chip_type
+                .control_lines()
+                .iter()
+                .map(|line| line.name)
+                .filter(|&name| chip.get(name).and_then(Value::as_str) != Some("ignore"));
+            active.next().filter(|_| active.next().is_none())
+        })
+        .unwrap_or("cs1");
+
+    let mut chips = Vec::new();
+    let mut select_logic = None;
+    for (idx, chip) in chips_json.iter().enumerate() {
+        let name = chip.get("type").and_then(Value::as_str).unwrap_or_default();
+        let chip_type = ChipType::try_from_str(name)
+            .ok_or_else(|| format!("Set {set_id}, chip {idx}: unknown ROM type '{name}'"))?;
+        if chip_type.is_plugin() {
+            notes.push(format!(
+                "Chip {idx} is a plugin, which is not selected by CS lines"
+            ));
+            continue;
+        }
+        let mapping = pin_mapping(board, chip_type)?;
+        let mut lines = Vec::new();
+        for line in chip_type.control_lines() {
+            let value = chip.get(line.name).and_then(Value::as_str);
+            let logic = match (value, line.line_type.fixed_active_level()) {
+                (Some(v), _) => v,
+                (None, Some(high)) => level(high),
+                (None, None) => {
+                    notes.push(format!(
+                        "Chip {idx} does not set {}, which the {} needs",
+                        line.name.to_uppercase(),
+                        chip_type.name()
+                    ));
+                    continue;
+                }
+            };
+            if line.name == select && idx == 0 {
+                select_logic = Some(logic);
+            }
+            lines.push(line_guide(&mapping, line, logic, value.map(str::to_string)));
+        }
+        chips.push(chip_guide(board, chip_type, idx, select, lines));
+    }
+
+    let x_pins = if set_type == "multi" {
+        x_pins(board, chips_json.len(), select, select_logic)
+    } else {
+        Vec::new()
+    };
+
+    Ok(CsGuide {
+        board: board.name().to_string(),
+        set_id,
+        sel_jumpers,
+        chips,
+        x_pins,
+        chip_set_json: serde_json::to_string_pretty(set)
+            .map_err(|e| format!("Error writing config: {e}"))?,
+        notes,
+    })
+}
+
+/// The SEL jumpers, closed as needed to select `set_id`.
+fn sel_jumpers(board: Board, set_id: usize) -> Result<Vec<SelJumper>, String> {
+    let pulls = board.sel_jumper_pulls();
+    if set_id >> pulls.len() != 0 {
+        return Err(format!(
+            "{} has {} SEL jumpers, so can only select sets 0-{}",
+            board.name(),
+            pulls.len(),
+            (1usize << pulls.len()) - 1
+        ));
+    }
+    Ok(board
+        .sel_pins()
+        .iter()
+        .zip(pulls)
+        .enumerate()
+        .map(|(sel, (&gpio, &pull))| SelJumper {
+            sel: sel as u8,
+            gpio,
+            pull: pull_name(pull).to_string(),
+            closed: set_id & (1 << sel) != 0,
+            shared_with: if gpio == board.swclk_sel_pin() {
+                Some("SWCLK".to_string())
+            } else if gpio == board.swdio_sel_pin() {
+                Some("SWDIO".to_string())
+            } else {
+                None
+            },
+        })
+        .collect())
+}
+
+fn chip_guide(
+    board: Board,
+    chip_type: ChipType,
+    idx: usize,
+    select: &str,
+    mut lines: Vec<ControlLineGuide>,
+) -> ChipSelectGuide {
+    let select_pin = match idx {
+        0 => select.to_uppercase(),
+        n => format!("X{n}"),
+    };
+    // A further chip's select line is read from its X pin, not the socket.
+    if idx > 0
+        && let Some(line) = lines
+            .iter_mut()
+            .find(|l| l.name.eq_ignore_ascii_case(select))
+    {
+        let gpio = board.cs_pin_for_chip_in_set(chip_type, idx);
+        line.socket_pin = None;
+        line.gpio = (gpio != 255).then_some(gpio);
+        line.explanation = format!(
+            "Read from {select_pin}, wired to this chip's {} on the host board. {}",
+            line.name,
+            selected_while(&line.name, &line.logic)
+        );
+    }
+    ChipSelectGuide {
+        chip_idx: idx,
+        chip_type: chip_type.name().to_string(),
+        select_pin,
+        lines,
+    }
+}
+
+fn line_guide(
+    mapping: &BoardPinMapping,
+    line: &ControlLineSpec,
+    logic: &str,
+    config_value: Option<String>,
+) -> ControlLineGuide {
+    let name = line.name.to_uppercase();
+    let pin = mapping.pins.iter().find(|p| p.signal == name);
+    let where_ = match pin.and_then(|p| p.socket_pin) {
+        Some(socket_pin) => format!("socket pin {socket_pin}"),
+        None => format!("chip pin {}", line.pin),
+    };
+    let explanation = selected_while(&format!("{name} ({where_})"), logic);
+    ControlLineGuide {
+        name,
+        chip_pin: line.pin,
+        socket_pin: pin.and_then(|p| p.socket_pin),
+        gpio: pin.and_then(|p| p.gpios.first().copied()),
+        logic: logic.to_string(),
+        fixed: line.line_type.fixed_active_level().is_some(),
+        config_value,
+        explanation,
+    }
+}
+
+/// How a line with `logic` selects the chip.
+fn selected_while(line: &str, logic: &str) -> String {
+    match logic {
+        "active_low" => format!("Selected while {line} is low."),
+        "active_high" => format!("Selected while {line} is high."),
+        _ => format!("{line} is not checked: the chip responds whatever its level."),
+    }
+}
+
+/// The X pins selecting the second and third chips of a multi-chip set,
+/// given their select line and its logic, shared with the first chip.
+fn x_pins(board: Board, chip_count: usize, select: &str, logic: Option<&str>) -> Vec<XPinGuide> {
+    let pull = board.x_jumper_pull();
+    let select = select.to_uppercase();
+    let floating_active = match logic {
+        Some("active_low") => Some(pull == 0),
+        Some("active_high") => Some(pull == 1),
+        _ => None,
+    };
+    [(1, "X1", board.pin_x1()), (2, "X2", board.pin_x2())]
+        .into_iter()
+        .filter(|&(idx, _, gpio)| idx < chip_count && gpio != 255)
+        .map(|(idx, name, gpio)| XPinGuide {
+            name: name.to_string(),
+            gpio,
+            pull: pull_name(pull).to_string(),
+            chip_idx: idx,
+            explanation: match floating_active {
+                Some(true) => format!(
+                    "Wire {name} to chip {idx}'s {select} on the host board. Unconnected, its pull \
+                     reads as selected and chip {idx} clashes with every other access."
+                ),
+                Some(false) => format!(
+                    "Wire {name} to chip {idx}'s {select} on the host board. Unconnected, chip \
+                     {idx} is never served."
+                ),
+                None => format!("Wire {name} to chip {idx}'s {select} on the host board."),
+            },
+        })
+        .collect()
+}
+
+/// Why `onerom-gen` rejects `set`, placed at `set_id` in a config, if it
+/// does.
+fn check_set(board: Board, version: FirmwareVersion, set: &Value, set_id: usize) -> Option<String> {
+    // Earlier sets must be valid too, so repeat this one.
+    let sets = vec![set.clone(); set_id + 1];
+    let config = json!({ "version": 1, "description": "", "chip_sets": sets });
+    GenBuilder::from_json(version, board.mcu_family(), &config.to_string())
+        .err()
+        .map(|e| format!("{e:?}"))
+}
+
+fn is_cs_line(line: &ControlLineSpec) -> bool {
+    matches!(line.name, "cs1" | "cs2" | "cs3")
+}
+
+fn pull_name(pull: u8) -> &'static str {
+    if pull == 0 { "down" } else { "up" }
+}
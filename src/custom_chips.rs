@@ -0,0 +1,635 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! User-defined chip types.
+//!
+//! Lets a part `onerom-config` does not know about yet be described in JSON
+//! and used by name in configs, [`chip_type_info`](crate::chip_type_info)
+//! and the other chip type entry points.
+//!
+//! The firmware and `onerom-gen` only serve built-in chip types, so each
+//! custom type is served as a built-in "base" type with the same package,
+//! size and pin functions - given, or the first that fits. Where the two
+//! order their address or data lines differently, `gen_builder_from_json`
+//! has the ROM reordered with `swap_address_lines`/`swap_data_lines` once
+//! its own transforms and patches are applied, so the file is written in
+//! the custom chip's own order. CS settings, stated
+//! against the custom chip's line names, are carried across to the base
+//! type's lines at the same pins.
+
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::{CHIP_TYPES, ChipType};
+use onerom_config::hw::{BOARDS, Board};
+
+use crate::{AddressPin, ChipTypeInfo, ControlLine, DataPin, PowerPin, level};
+
+/// A custom chip type, as described in JSON.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CustomChipType {
+    name: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    /// Built-in type to serve this as. Found automatically if not given.
+    base: Option<String>,
+    /// "rom" (the default) or "ram".
+    #[serde(default = "default_function")]
+    chip_function: String,
+    chip_pins: u8,
+    /// Size in bytes. Defaults to what the address and data lines give.
+    size_bytes: Option<usize>,
+    /// Pin of each address line, A0 first.
+    address_pins: Vec<u8>,
+    /// Pin of each data line, D0 first.
+    data_pins: Vec<u8>,
+    #[serde(default)]
+    control_lines: Vec<CustomControlLine>,
+    #[serde(default)]
+    power_pins: Vec<CustomPowerPin>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CustomControlLine {
+    /// "cs1", "cs2", "cs3", "ce" or "oe".
+    name: String,
+    pin: u8,
+    /// "configurable", "active_low" or "active_high".
+    polarity: String,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CustomPowerPin {
+    /// "vcc" or "gnd".
+    name: String,
+    pin: u8,
+}
+
+fn default_function() -> String {
+    "rom".to_string()
+}
+
+/// A loaded custom chip type, as returned by [`custom_chip_types`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct CustomChipTypeInfo {
+    pub name: String,
+    /// The built-in type it is served as.
+    pub base: String,
+    /// Boards whose socket takes it.
+    pub boards: Vec<String>,
+    /// Whether ROM images are reordered to serve it as `base`.
+    pub reordered: bool,
+}
+
+/// How a custom chip type is served as its base type.
+#[derive(Clone)]
+struct Resolved {
+    def: CustomChipType,
+    base: ChipType,
+    /// Pairs for a `swap_address_lines` transform, empty if none is needed.
+    address_swaps: Vec<[u8; 2]>,
+    /// Pairs for a `swap_data_lines` transform, empty if none is needed.
+    data_swaps: Vec<[u8; 2]>,
+    /// Each base control line, and the custom line at its pin, if any.
+    lines: Vec<(&'static str, Option<CustomControlLine>)>,
+}
+
+/// Custom chip types loaded with [`load_custom_chip_types`].
+static LOADED: Mutex<Vec<Resolved>> = Mutex::new(Vec::new());
+
+fn find(name: &str) -> Option<Resolved> {
+    let loaded = LOADED.lock().unwrap();
+    loaded
+        .iter()
+        .find(|r| r.def.name == name || r.def.aliases.iter().any(|a| a == name))
+        .cloned()
+}
+
+/// Load custom chip types from a JSON array of descriptions. A type with
+/// the name of one already loaded replaces it. Returns the number loaded.
+///
+/// Each description has `name`, `chip_pins`, `address_pins` and `data_pins`
+/// (pin numbers, line 0 first), and optionally `aliases`, `base`,
+/// `chip_function` ("rom" or "ram"), `size_bytes`, `control_lines`
+/// (`name`, `pin` and `polarity`: "configurable", "active_low" or
+/// "active_high") and `power_pins` (`name` "vcc" or "gnd", and `pin`).
+#[wasm_bindgen]
+pub fn load_custom_chip_types(json: &str) -> Result<usize, JsValue> {
+    let defs: Vec<CustomChipType> =
+        serde_json::from_str(json).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let resolved = defs
+        .into_iter()
+        .map(|def| {
+            let name = def.name.clone();
+            resolve(def).map_err(|e| JsValue::from_str(&format!("{name}: {e}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let count = resolved.len();
+    let mut loaded = LOADED.lock().unwrap();
+    for r in resolved {
+        loaded.retain(|l| l.def.name != r.def.name);
+        loaded.push(r);
+    }
+    Ok(count)
+}
+
+/// Return every loaded custom chip type.
+#[wasm_bindgen]
+pub fn custom_chip_types() -> Vec<CustomChipTypeInfo> {
+    let loaded = LOADED.lock().unwrap();
+    loaded
+        .iter()
+        .map(|r| CustomChipTypeInfo {
+            name: r.def.name.clone(),
+            base: r.base.name().to_string(),
+            boards: BOARDS
+                .iter()
+                .filter(|b| b.supports_chip_type(r.base))
+                .map(|b| b.name().to_string())
+                .collect(),
+            reordered: !r.address_swaps.is_empty() || !r.data_swaps.is_empty(),
+        })
+        .collect()
+}
+
+/// Remove every loaded custom chip type.
+#[wasm_bindgen]
+pub fn clear_custom_chip_types() {
+    LOADED.lock().unwrap().clear();
+}
+
+/// The built-in type custom chip type `name` is served as, if one is loaded.
+pub(crate) fn custom_chip_base(name: &str) -> Option<ChipType> {
+    find(name).map(|r| r.base)
+}
+
+/// Built-in chip type `name`, or the base custom type `name` is served as.
+pub(crate) fn chip_type_or_base(name: &str) -> Option<ChipType> {
+    ChipType::try_from_str(name).or_else(|| custom_chip_base(name))
+}
+
+/// The primary name of chip type `name`, built-in or custom.
+pub(crate) fn chip_type_name(name: &str) -> Option<String> {
+    match ChipType::try_from_str(name) {
+        Some(chip_type) => Some(chip_type.name().to_string()),
+        None => find(name).map(|r| r.def.name),
+    }
+}
+
+/// The chip type to place chip type `name` in `board`'s socket as: a
+/// built-in type itself, or the base of a custom type the socket takes.
+pub(crate) fn socket_chip_type(board: Board, name: &str) -> Result<ChipType, String> {
+    if let Some(chip_type) = ChipType::try_from_str(name) {
+        return Ok(chip_type);
+    }
+    let r = find(name).ok_or_else(|| format!("Unknown ROM type: {name}"))?;
+    if !board.supports_chip_type(r.base) {
+        return Err(format!(
+            "{} does not take a {}, served as a {}",
+            board.name(),
+            r.def.name,
+            r.base.name()
+        ));
+    }
+    Ok(r.base)
+}
+
+/// The fixed level of control line `line` of chip type `name`, built-in or
+/// custom: `Some(true)` if active high. `None` if configurable or unknown.
+pub(crate) fn fixed_level(name: &str, line: &str) -> Option<bool> {
+    match ChipType::try_from_str(name) {
+        Some(chip_type) => chip_type
+            .control_lines()
+            .iter()
+            .find(|cl| cl.name == line)
+            .and_then(|cl| cl.line_type.fixed_active_level()),
+        None => find(name)?
+            .def
+            .control_lines
+            .iter()
+            .find(|cl| cl.name == line)
+            .and_then(|cl| polarity(&cl.polarity)),
+    }
+}
+
+/// Every loaded custom chip type's information, with the base it is served
+/// as.
+pub(crate) fn custom_chip_infos() -> Vec<(ChipTypeInfo, ChipType)> {
+    let names: Vec<String> = LOADED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|r| r.def.name.clone())
+        .collect();
+    names
+        .iter()
+        .filter_map(|name| Some((custom_chip_info(name)?, custom_chip_base(name)?)))
+        .collect()
+}
+
+/// Information on custom chip type `name`, if one is loaded, in the same
+/// shape as for built-in types.
+pub(crate) fn custom_chip_info(name: &str) -> Option<ChipTypeInfo> {
+    let r = find(name)?;
+    let def = r.def;
+    let data_width = def.data_pins.len();
+    Some(ChipTypeInfo {
+        name: def.name.clone(),
+        aliases: def.aliases.clone(),
+        chip_function: match def.chip_function.as_str() {
+            "ram" => "Ram",
+            _ => "Rom",
+        }
+        .to_string(),
+        is_plugin: false,
+        is_supported: true,
+        bit_modes: if data_width == 16 {
+            vec![8, 16]
+        } else {
+            vec![8]
+        },
+        size_bytes: size_bytes(&def),
+        chip_pins: def.chip_pins,
+        num_addr_lines: def.address_pins.len(),
+        address_pins: def
+            .address_pins
+            .iter()
+            .enumerate()
+            .map(|(line, &pin)| AddressPin { line, pin })
+            .collect(),
+        data_pins: def
+            .data_pins
+            .iter()
+            .enumerate()
+            .map(|(line, &pin)| DataPin { line, pin })
+            .collect(),
+        control_lines: def
+            .control_lines
+            .iter()
+            .map(|cl| ControlLine {
+                name: cl.name.clone(),
+                pin: cl.pin,
+                configurable: cl.polarity == "configurable",
+            })
+            .collect(),
+        programming_pins: None,
+        power_pins: def
+            .power_pins
+            .iter()
+            .map(|p| PowerPin {
+                name: p.name.clone(),
+                pin: p.pin,
+            })
+            .collect(),
+    })
+}
+
+/// Rewrite every chip of a custom type in `config_json` as its base type.
+///
+/// Returns `None` if the config uses no custom types, so it can be passed
+/// on untouched.
+pub(crate) fn resolve_config(config_json: &str) -> Result<Option<String>, String> {
+    if LOADED.lock().unwrap().is_empty() {
+        return Ok(None);
+    }
+    let mut config: Value =
+        serde_json::from_str(config_json).map_err(|e| format!("Error parsing config: {e}"))?;
+
+    let mut changed = false;
+    let sets_key = if config.get("chip_sets").is_some() {
+        "chip_sets"
+    } else {
+        "rom_sets"
+    };
+    let sets = config
+        .get_mut(sets_key)
+        .and_then(Value::as_array_mut)
+        .into_iter()
+        .flatten();
+    for (set_id, set) in sets.enumerate() {
+        let chips_key = if set.get("chips").is_some() {
+            "chips"
+        } else {
+            "roms"
+        };
+        let chips = set
+            .get_mut(chips_key)
+            .and_then(Value::as_array_mut)
+            .into_iter()
+            .flatten();
+        for (chip_idx, chip) in chips.enumerate() {
+            let Some(chip) = chip.as_object_mut() else {
+                continue;
+            };
+            let Some(r) = chip.get("type").and_then(Value::as_str).and_then(find) else {
+                continue;
+            };
+            rewrite_chip(chip, &r)
+                .map_err(|e| format!("Set {set_id}, chip {chip_idx} ({}): {e}", r.def.name))?;
+            changed = true;
+        }
+    }
+
+    if !changed {
+        return Ok(None);
+    }
+    serde_json::to_string(&config)
+        .map(Some)
+        .map_err(|e| format!("Error writing config: {e}"))
+}
+
+fn rewrite_chip(chip: &mut Map<String, Value>, r: &Resolved) -> Result<(), String> {
+    chip.insert("type".to_string(), json!(r.base.name()));
+
+    // Take the CS settings stated against the custom chip's lines.
+    let stated: Vec<(String, Value)> = r
+        .def
+        .control_lines
+        .iter()
+        .filter_map(|cl| chip.remove(&cl.name).map(|v| (cl.name.clone(), v)))
+        .collect();
+    let mut ignored = false;
+    for &(base_name, ref custom) in &r.lines {
+        let spec = r
+            .base
+            .control_lines()
+            .iter()
+            .find(|l| l.name == base_name)
+            .expect("resolved line is on the base type");
+        let base_fixed = spec.line_type.fixed_active_level();
+        let logic = match custom {
+            // Nothing on the custom chip at this pin.
+            None => Some("ignore".to_string()),
+            Some(cl) => {
+                let given = stated
+                    .iter()
+                    .find(|(name, _)| *name == cl.name)
+                    .and_then(|(_, v)| v.as_str());
+                match (polarity(&cl.polarity), given) {
+                    (_, Some(logic)) => Some(logic.to_string()),
+                    (Some(high), None) => Some(level(high).to_string()),
+                    (None, None) => {
+                        return Err(format!("{} polarity must be given", cl.name));
+                    }
+                }
+            }
+        };
+        match (logic.as_deref(), base_fixed) {
+            (Some("ignore"), _) => {
+                chip.insert(base_name.to_string(), json!("ignore"));
+                ignored |= !spec.allow_ignore;
+            }
+            (Some(logic), Some(high)) if logic != level(high) => {
+                return Err(format!(
+                    "{} is served as {} of a {}, which is fixed {}",
+                    custom.as_ref().map_or(base_name, |cl| cl.name.as_str()),
+                    base_name,
+                    r.base.name(),
+                    level(high)
+                ));
+            }
+            // The base type's polarity already matches.
+            (Some(_), Some(_)) => {}
+            (Some(logic), None) => {
+                chip.insert(base_name.to_string(), json!(logic));
+            }
+            (None, _) => {}
+        }
+    }
+    if ignored {
+        chip.insert("allow_cs_ignore".to_string(), json!(true));
+    }
+
+    // Reorder the image after its transforms and patches, which are written
+    // against the file as published.
+    let mut reorder = Vec::new();
+    if !r.address_swaps.is_empty() {
+        reorder.push(json!({ "op": "swap_address_lines", "lines": r.address_swaps }));
+    }
+    if !r.data_swaps.is_empty() {
+        reorder.push(json!({ "op": "swap_data_lines", "lines": r.data_swaps }));
+    }
+    if !reorder.is_empty() {
+        chip.insert("reorder".to_string(), Value::Array(reorder));
+    }
+    Ok(())
+}
+
+fn resolve(def: CustomChipType) -> Result<Resolved, String> {
+    validate(&def)?;
+    if ChipType::try_from_str(&def.name).is_some() {
+        return Err("name is already a built-in chip type".to_string());
+    }
+    if let Some(alias) = def
+        .aliases
+        .iter()
+        .find(|a| ChipType::try_from_str(a).is_some())
+    {
+        return Err(format!("alias {alias} is already a built-in chip type"));
+    }
+
+    match &def.base {
+        Some(base) => {
+            let base = ChipType::try_from_str(base)
+                .filter(|t| !t.is_plugin())
+                .ok_or_else(|| format!("unknown base chip type {base}"))?;
+            fit(&def, base).map_err(|e| format!("cannot be served as a {}: {e}", base.name()))
+        }
+        None => {
+            let mut fits: Vec<Resolved> = CHIP_TYPES
+                .iter()
+                .filter(|t| !t.is_plugin() && t.is_supported())
+                .filter_map(|&t| fit(&def, t).ok())
+                .collect();
+            // Prefer a base needing no reordering.
+            fits.sort_by_key(|r| r.address_swaps.len() + r.data_swaps.len());
+            fits.into_iter().next().ok_or_else(|| {
+                "no built-in chip type has a compatible pinout; give a base to see why".to_string()
+            })
+        }
+    }
+}
+
+fn validate(def: &CustomChipType) -> Result<(), String> {
+    if !matches!(def.chip_pins, 24 | 28 | 32 | 40) {
+        return Err(format!("{}-pin packages are not supported", def.chip_pins));
+    }
+    if !matches!(def.chip_function.as_str(), "rom" | "ram") {
+        return Err(format!(
+            "unknown chip function {}, use rom or ram",
+            def.chip_function
+        ));
+    }
+    if !matches!(def.data_pins.len(), 8 | 16) {
+        return Err("must have 8 or 16 data pins".to_string());
+    }
+    if let Some(size) = def.size_bytes
+        && size != (def.data_pins.len() / 8) << def.address_pins.len()
+    {
+        return Err(format!(
+            "size {size} does not match {} address and {} data lines",
+            def.address_pins.len(),
+            def.data_pins.len()
+        ));
+    }
+
+    let mut used: Vec<(u8, String)> = Vec::new();
+    let all = def
+        .address_pins
+        .iter()
+        .enumerate()
+        .map(|(l, &p)| (p, format!("A{l}")))
+        .chain(
+            def.data_pins
+                .iter()
+                .enumerate()
+                .map(|(l, &p)| (p, format!("D{l}"))),
+        )
+        .chain(def.control_lines.iter().map(|cl| (cl.pin, cl.name.clone())))
+        .chain(def.power_pins.iter().map(|p| (p.pin, p.name.clone())));
+    for (pin, signal) in all {
+        if !(1..=def.chip_pins).contains(&pin) {
+            return Err(format!("{signal} is on pin {pin}, outside the package"));
+        }
+        if let Some((_, other)) = used.iter().find(|(p, _)| *p == pin) {
+            return Err(format!("{signal} and {other} are both on pin {pin}"));
+        }
+        used.push((pin, signal));
+    }
+
+    for (i, cl) in def.control_lines.iter().enumerate() {
+        if !matches!(cl.name.as_str(), "cs1" | "cs2" | "cs3" | "ce" | "oe") {
+            return Err(format!(
+                "unknown control line {}, use cs1, cs2, cs3, ce or oe",
+                cl.name
+            ));
+        }
+        if def.control_lines[..i].iter().any(|o| o.name == cl.name) {
+            return Err(format!("{} is given twice", cl.name));
+        }
+        if cl.polarity != "configurable" && polarity(&cl.polarity).is_none() {
+            return Err(format!(
+                "unknown {} polarity {}, use configurable, active_low or active_high",
+                cl.name, cl.polarity
+            ));
+        }
+    }
+    for p in &def.power_pins {
+        if !matches!(p.name.as_str(), "vcc" | "gnd") {
+            return Err(format!("unknown power pin {}, use vcc or gnd", p.name));
+        }
+    }
+    Ok(())
+}
+
+/// How `def` is served as `base`, or why it cannot be.
+fn fit(def: &CustomChipType, base: ChipType) -> Result<Resolved, String> {
+    if base.chip_pins() != def.chip_pins {
+        return Err(format!("it has {} pins", base.chip_pins()));
+    }
+    if base.size_bytes() != size_bytes(def) {
+        return Err(format!("it is {} bytes", base.size_bytes()));
+    }
+
+    let address_swaps = line_swaps(&def.address_pins, base.address_pins())
+        .ok_or("its address lines are on different pins")?;
+    // Address swaps reorder the index and data swaps the value, so the data
+    // lines need the inverse: the same swaps in reverse.
+    let mut data_swaps = line_swaps(&def.data_pins, base.data_pins())
+        .ok_or("its data lines are on different pins")?;
+    data_swaps.reverse();
+    if def.data_pins.len() > 8 && !data_swaps.is_empty() {
+        return Err("its data lines are in a different order".to_string());
+    }
+
+    for p in &def.power_pins {
+        if !base
+            .power_pins()
+            .iter()
+            .any(|b| b.pin == p.pin && b.name == p.name)
+        {
+            return Err(format!("it has no {} on pin {}", p.name, p.pin));
+        }
+    }
+
+    let mut lines = Vec::new();
+    for spec in base.control_lines() {
+        let custom = def.control_lines.iter().find(|cl| cl.pin == spec.pin);
+        if let (Some(cl), Some(high)) = (custom, spec.line_type.fixed_active_level())
+            && polarity(&cl.polarity).is_some_and(|p| p != high)
+        {
+            return Err(format!(
+                "its {} on pin {} is fixed {}",
+                spec.name,
+                spec.pin,
+                level(high)
+            ));
+        }
+        lines.push((spec.name, custom.cloned()));
+    }
+    if let Some(cl) = def
+        .control_lines
+        .iter()
+        .find(|cl| !base.control_lines().iter().any(|l| l.pin == cl.pin))
+    {
+        return Err(format!("it has no control line on pin {}", cl.pin));
+    }
+
+    Ok(Resolved {
+        def: def.clone(),
+        base,
+        address_swaps,
+        data_swaps,
+        lines,
+    })
+}
+
+/// Swaps that put the base type's lines in the custom chip's order, where
+/// both use the same set of pins.
+///
+/// Line `i` of the result must carry custom line `i`, which is on the pin of
+/// some base line `j`. The swaps are found by moving each wanted line into
+/// place in turn, as the transforms apply them in order.
+fn line_swaps(custom: &[u8], base: &[u8]) -> Option<Vec<[u8; 2]>> {
+    if custom.len() != base.len() {
+        return None;
+    }
+    let want: Vec<usize> = custom
+        .iter()
+        .map(|pin| base.iter().position(|b| b == pin))
+        .collect::<Option<_>>()?;
+
+    let mut current: Vec<usize> = (0..base.len()).collect();
+    let mut swaps = Vec::new();
+    for (pos, &line) in want.iter().enumerate() {
+        let from = current.iter().position(|&l| l == line)?;
+        if from != pos {
+            current.swap(pos, from);
+            swaps.push([pos as u8, from as u8]);
+        }
+    }
+    Some(swaps)
+}
+
+fn size_bytes(def: &CustomChipType) -> usize {
+    def.size_bytes
+        .unwrap_or((def.data_pins.len() / 8) << def.address_pins.len())
+}
+
+fn polarity(polarity: &str) -> Option<bool> {
+    match polarity {
+        "active_low" => Some(false),
+        "active_high" => Some(true),
+        _ => None,
+    }
+}
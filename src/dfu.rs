@@ -0,0 +1,541 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! STM32 DfuSe flashing.
+//!
+//! Carries out a [`FlashPlan`] on an STM32F4 held in its ROM bootloader,
+//! which speaks DFU 1.1 with ST's DfuSe extensions: a DNLOAD of block 0
+//! carries a command (set the address pointer, erase a sector), and a DNLOAD
+//! of block `n >= 2` writes at the address pointer plus `(n - 2)` transfer
+//! sizes. Each DNLOAD only takes effect once the host polls GETSTATUS, which
+//! reports the device busy, with how long to wait, until it is idle again.
+//!
+//! The USB side is a JS callback making class control transfers to the DFU
+//! interface, as WebUSB's `controlTransferIn`/`controlTransferOut` do.
+//! The tests run plans against a simulated device in `dfu_sim`.
+//!
+//! A device found in, or left in, the error state is recovered with
+//! CLRSTATUS and the failed command retried. Transport errors are not
+//! retried: the device has most likely gone.
+
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::mcu::Family;
+
+use crate::flash_plan::FlashPlan;
+
+pub(crate) const DFU_DNLOAD: u8 = 1;
+pub(crate) const DFU_GETSTATUS: u8 = 3;
+pub(crate) const DFU_CLRSTATUS: u8 = 4;
+#[cfg(test)]
+pub(crate) const DFU_GETSTATE: u8 = 5;
+pub(crate) const DFU_ABORT: u8 = 6;
+
+/// DfuSe command: set the address pointer.
+pub(crate) const CMD_SET_ADDRESS: u8 = 0x21;
+/// DfuSe command: erase the sector containing an address.
+pub(crate) const CMD_ERASE: u8 = 0x41;
+
+/// DFU `bStatus` for success.
+pub(crate) const STATUS_OK: u8 = 0;
+
+/// Default `wTransferSize` of the STM32F4 ROM bootloader.
+pub(crate) const DEFAULT_TRANSFER_SIZE: u16 = 2048;
+/// Default number of times a failed command is retried.
+const DEFAULT_RETRIES: u32 = 2;
+/// GETSTATUS polls allowed for one command before giving up.
+const MAX_POLLS: u32 = 1000;
+
+/// DFU device states, `bState`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum State {
+    AppIdle = 0,
+    AppDetach = 1,
+    Idle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+}
+
+impl State {
+    fn from_u8(state: u8) -> Option<Self> {
+        Some(match state {
+            0 => Self::AppIdle,
+            1 => Self::AppDetach,
+            2 => Self::Idle,
+            3 => Self::DnloadSync,
+            4 => Self::DnBusy,
+            5 => Self::DnloadIdle,
+            6 => Self::ManifestSync,
+            7 => Self::Manifest,
+            8 => Self::ManifestWaitReset,
+            9 => Self::UploadIdle,
+            10 => Self::Error,
+            _ => return None,
+        })
+    }
+}
+
+/// A GETSTATUS response.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Status {
+    pub(crate) status: u8,
+    pub(crate) poll_timeout_ms: u32,
+    pub(crate) state: State,
+}
+
+impl Status {
+    #[cfg(test)]
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let timeout = self.poll_timeout_ms.to_le_bytes();
+        vec![
+            self.status,
+            timeout[0],
+            timeout[1],
+            timeout[2],
+            self.state as u8,
+            0,
+        ]
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 6 {
+            return Err(format!("Short GETSTATUS response: {} bytes", bytes.len()));
+        }
+        let state = State::from_u8(bytes[4])
+            .ok_or_else(|| format!("Unknown DFU state {} in GETSTATUS", bytes[4]))?;
+        Ok(Self {
+            status: bytes[0],
+            poll_timeout_ms: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], 0]),
+            state,
+        })
+    }
+}
+
+/// Name of a DFU `bStatus`.
+pub(crate) fn status_name(status: u8) -> &'static str {
+    match status {
+        0 => "OK",
+        1 => "errTARGET",
+        2 => "errFILE",
+        3 => "errWRITE",
+        4 => "errERASE",
+        5 => "errCHECK_ERASED",
+        6 => "errPROG",
+        7 => "errVERIFY",
+        8 => "errADDRESS",
+        9 => "errNOTDONE",
+        10 => "errFIRMWARE",
+        11 => "errVENDOR",
+        12 => "errUSBR",
+        13 => "errPOR",
+        15 => "errSTALLEDPKT",
+        _ => "errUNKNOWN",
+    }
+}
+
+/// The host side of a DFU interface's control transfers.
+pub(crate) trait DfuTransport {
+    /// Class OUT request to the DFU interface.
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> Result<(), String>;
+    /// Class IN request to the DFU interface, returning up to `length` bytes.
+    async fn control_in(&mut self, request: u8, value: u16, length: u16)
+    -> Result<Vec<u8>, String>;
+    /// Wait `ms` milliseconds, as GETSTATUS asks.
+    async fn delay(&mut self, ms: u32);
+}
+
+/// Why a DFU step failed.
+enum Failure {
+    /// The device reported an error status; worth a retry.
+    Device(u8, State),
+    /// Anything else.
+    Fatal(String),
+}
+
+impl From<String> for Failure {
+    fn from(e: String) -> Self {
+        Failure::Fatal(e)
+    }
+}
+
+impl Failure {
+    fn message(self, what: &str) -> String {
+        match self {
+            Failure::Device(status, state) => {
+                format!("{what} failed: {} in state {state:?}", status_name(status))
+            }
+            Failure::Fatal(e) => format!("{what} failed: {e}"),
+        }
+    }
+}
+
+/// Options for [`dfu_flash`]. All optional.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct DfuOptions {
+    /// The DFU interface's `wTransferSize`, default 2048.
+    pub(crate) transfer_size: Option<u16>,
+    /// Times a command failing with an error status is retried, default 2.
+    pub(crate) retries: Option<u32>,
+    /// Leave DFU mode and start the firmware once done, default true.
+    pub(crate) manifest: Option<bool>,
+}
+
+/// What a DFU flash did, from [`dfu_flash`].
+#[derive(Debug, Default, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct DfuReport {
+    pub mcu: String,
+    pub sectors_erased: u32,
+    pub chunks_written: u32,
+    /// Bytes written, padding included.
+    pub bytes_written: u32,
+    /// Commands retried after the device reported an error.
+    pub retries: u32,
+    /// Whether the device was told to leave DFU mode.
+    pub manifested: bool,
+    /// Erases, retries and recovery, in order.
+    pub log: Vec<String>,
+}
+
+struct Dfu<'a, T: DfuTransport> {
+    dev: &'a mut T,
+    transfer_size: u16,
+    retries: u32,
+    report: DfuReport,
+}
+
+impl<T: DfuTransport> Dfu<'_, T> {
+    async fn get_status(&mut self) -> Result<Status, String> {
+        let bytes = self.dev.control_in(DFU_GETSTATUS, 0, 6).await?;
+        Status::from_bytes(&bytes)
+    }
+
+    /// Bring the device to dfuIDLE, from wherever it was left.
+    async fn recover(&mut self) -> Result<(), String> {
+        let status = self.get_status().await?;
+        match status.state {
+            State::Idle => return Ok(()),
+            State::AppIdle | State::AppDetach => {
+                return Err("Device is not in DFU mode".to_string());
+            }
+            State::Error => {
+                self.report.log.push(format!(
+                    "Clearing {} from device",
+                    status_name(status.status)
+                ));
+                self.dev.control_out(DFU_CLRSTATUS, 0, &[]).await?;
+            }
+            state => {
+                self.report.log.push(format!("Aborting from {state:?}"));
+                self.dev.control_out(DFU_ABORT, 0, &[]).await?;
+            }
+        }
+        let status = self.get_status().await?;
+        if status.state != State::Idle {
+            return Err(format!(
+                "Device is in state {:?} after recovery, not dfuIDLE",
+                status.state
+            ));
+        }
+        Ok(())
+    }
+
+    /// DNLOAD block `block`, then poll until the device is idle again.
+    async fn download(&mut self, block: u16, data: &[u8]) -> Result<(), Failure> {
+        self.dev.control_out(DFU_DNLOAD, block, data).await?;
+        for _ in 0..MAX_POLLS {
+            let status = self.get_status().await?;
+            if status.status != STATUS_OK {
+                return Err(Failure::Device(status.status, status.state));
+            }
+            match status.state {
+                State::DnloadIdle | State::Idle => return Ok(()),
+                State::DnloadSync | State::DnBusy => {
+                    self.dev.delay(status.poll_timeout_ms).await;
+                }
+                state => {
+                    return Err(Failure::Fatal(format!(
+                        "Unexpected state {state:?} after DNLOAD"
+                    )));
+                }
+            }
+        }
+        Err(Failure::Fatal(format!(
+            "Device still busy after {MAX_POLLS} polls"
+        )))
+    }
+
+    async fn command(&mut self, command: u8, address: u32) -> Result<(), Failure> {
+        let mut data = vec![command];
+        data.extend_from_slice(&address.to_le_bytes());
+        self.download(0, &data).await
+    }
+
+    /// Carry out `step` once.
+    async fn attempt(&mut self, step: &Step<'_>) -> Result<(), Failure> {
+        match *step {
+            Step::Erase(address) => self.command(CMD_ERASE, address).await,
+            Step::Write {
+                address,
+                block,
+                data,
+            } => {
+                if block == 2 {
+                    self.command(CMD_SET_ADDRESS, address).await?;
+                }
+                self.download(block, data).await
+            }
+        }
+    }
+
+    /// Carry out `step`, recovering and retrying on device errors. Returns
+    /// the DNLOAD block number written, which is 2 after any retry, as the
+    /// address pointer is then set again.
+    async fn with_retries(&mut self, what: &str, mut step: Step<'_>) -> Result<u16, String> {
+        let mut attempt = 0;
+        loop {
+            match self.attempt(&step).await {
+                Ok(()) => {
+                    return Ok(match step {
+                        Step::Erase(_) => 0,
+                        Step::Write { block, .. } => block,
+                    });
+                }
+                Err(Failure::Device(status, _)) if attempt < self.retries => {
+                    attempt += 1;
+                    self.report.retries += 1;
+                    self.report
+                        .log
+                        .push(format!("{what}: {}, retrying", status_name(status)));
+                    self.recover().await?;
+                    if let Step::Write { block, .. } = &mut step {
+                        *block = 2;
+                    }
+                }
+                Err(e) => return Err(e.message(what)),
+            }
+        }
+    }
+}
+
+/// A retryable unit of work.
+enum Step<'a> {
+    Erase(u32),
+    Write {
+        address: u32,
+        /// DNLOAD block number; 2 sets the address pointer first.
+        block: u16,
+        data: &'a [u8],
+    },
+}
+
+/// Carry out `plan` over `dev`, reporting bytes written through `progress`.
+pub(crate) async fn run<T: DfuTransport>(
+    dev: &mut T,
+    plan: &FlashPlan,
+    options: &DfuOptions,
+    progress: &mut dyn FnMut(u32, u32),
+) -> Result<DfuReport, String> {
+    if plan.variant.family() != Family::Stm32f4 {
+        return Err(format!(
+            "DfuSe flashing is for STM32F4, not {}",
+            plan.variant
+        ));
+    }
+    let transfer_size = options.transfer_size.unwrap_or(DEFAULT_TRANSFER_SIZE);
+    if let Some(w) = plan
+        .writes
+        .iter()
+        .find(|w| w.data.len() > transfer_size as usize)
+    {
+        return Err(format!(
+            "Plan writes {} bytes at {:#010x}, more than the transfer size of {transfer_size}; plan with a chunk_size of {transfer_size}",
+            w.data.len(),
+            w.address
+        ));
+    }
+
+    let mut dfu = Dfu {
+        dev,
+        transfer_size,
+        retries: options.retries.unwrap_or(DEFAULT_RETRIES),
+        report: DfuReport {
+            mcu: plan.variant.to_string(),
+            ..Default::default()
+        },
+    };
+    dfu.recover().await?;
+
+    for sector in &plan.erase {
+        let what = format!(
+            "Erasing sector {} at {:#010x}",
+            sector.index, sector.address
+        );
+        dfu.with_retries(&what, Step::Erase(sector.address)).await?;
+        dfu.report.log.push(what);
+        dfu.report.sectors_erased += 1;
+    }
+
+    let total: u32 = plan.writes.iter().map(|w| w.data.len() as u32).sum();
+    progress(0, total);
+    // Block numbers continue from the address pointer while writes are
+    // contiguous and every block before is a full transfer.
+    let mut next: Option<(u32, u16)> = None;
+    for w in &plan.writes {
+        let block = match next {
+            Some((address, block)) if address == w.address => block,
+            _ => 2,
+        };
+        let what = format!("Writing {} bytes at {:#010x}", w.data.len(), w.address);
+        let block = dfu
+            .with_retries(
+                &what,
+                Step::Write {
+                    address: w.address,
+                    block,
+                    data: &w.data,
+                },
+            )
+            .await?;
+        next = (w.data.len() == dfu.transfer_size as usize && block < u16::MAX)
+            .then(|| (w.address + w.data.len() as u32, block + 1));
+        dfu.report.chunks_written += 1;
+        dfu.report.bytes_written += w.data.len() as u32;
+        progress(dfu.report.bytes_written, total);
+    }
+
+    if options.manifest.unwrap_or(true) {
+        leave(&mut dfu, plan).await?;
+    }
+    Ok(dfu.report)
+}
+
+/// Point at the firmware and leave DFU mode. The device resets during
+/// manifestation, so a failed or missing status afterwards is expected.
+async fn leave<T: DfuTransport>(dfu: &mut Dfu<'_, T>, plan: &FlashPlan) -> Result<(), String> {
+    let base = plan.variant.family().get_flash_base();
+    dfu.command(CMD_SET_ADDRESS, base)
+        .await
+        .map_err(|e| e.message("Setting the start address"))?;
+    dfu.dev
+        .control_out(DFU_DNLOAD, 0, &[])
+        .await
+        .map_err(|e| format!("Leaving DFU mode failed: {e}"))?;
+    match dfu.get_status().await {
+        Ok(status) if status.status != STATUS_OK => {
+            return Err(format!(
+                "Leaving DFU mode failed: {}",
+                status_name(status.status)
+            ));
+        }
+        Ok(_) => {}
+        Err(e) => dfu.report.log.push(format!(
+            "No status after leaving DFU mode, as expected: {e}"
+        )),
+    }
+    dfu.report.manifested = true;
+    dfu.report.log.push("Left DFU mode".to_string());
+    Ok(())
+}
+
+/// A [`DfuTransport`] over JS callbacks.
+struct JsDfuTransport {
+    /// JS `async (request, value, dataOrLength) => Uint8Array | undefined`.
+    control_cb: js_sys::Function,
+    /// JS `async (ms) => void`, if given.
+    delay_cb: Option<js_sys::Function>,
+}
+
+impl JsDfuTransport {
+    async fn call(&self, request: u8, value: u16, arg: &JsValue) -> Result<JsValue, String> {
+        let promise = self
+            .control_cb
+            .call3(
+                &JsValue::NULL,
+                &JsValue::from_f64(request as f64),
+                &JsValue::from_f64(value as f64),
+                arg,
+            )
+            .map_err(|e| format!("control callback threw: {e:?}"))?;
+        wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&promise))
+            .await
+            .map_err(|e| format!("control transfer failed: {e:?}"))
+    }
+}
+
+impl DfuTransport for JsDfuTransport {
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> Result<(), String> {
+        let data = js_sys::Uint8Array::from(data);
+        self.call(request, value, &data).await.map(|_| ())
+    }
+
+    async fn control_in(
+        &mut self,
+        request: u8,
+        value: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, String> {
+        let resolved = self
+            .call(request, value, &JsValue::from_f64(length as f64))
+            .await?;
+        Ok(js_sys::Uint8Array::new(&resolved).to_vec())
+    }
+
+    async fn delay(&mut self, ms: u32) {
+        if let Some(delay_cb) = &self.delay_cb
+            && let Ok(promise) = delay_cb.call1(&JsValue::NULL, &JsValue::from_f64(ms as f64))
+        {
+            let _ = wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&promise)).await;
+        }
+    }
+}
+
+/// Flash an STM32F4 in DFU mode according to `plan`.
+///
+/// `control_cb` is a JS `async (request: number, value: number,
+/// dataOrLength: Uint8Array | number) => Uint8Array | undefined` making a
+/// class control transfer to the DFU interface: OUT with the data given a
+/// `Uint8Array`, IN of up to that many bytes given a number, returning what
+/// was read. `delay_cb`, if given, is a JS `async (ms: number) => void`,
+/// used to wait as the device asks between status polls. `progress_cb`, if
+/// given, is called with bytes written so far and the total.
+///
+/// `options` may be omitted, or an object with any of `transfer_size` (the
+/// interface's `wTransferSize`, default 2048), `retries` (default 2) and
+/// `manifest` (leave DFU mode when done, default true).
+#[wasm_bindgen]
+pub async fn dfu_flash(
+    plan: &FlashPlan,
+    control_cb: js_sys::Function,
+    delay_cb: Option<js_sys::Function>,
+    progress_cb: Option<js_sys::Function>,
+    options: JsValue,
+) -> Result<DfuReport, JsValue> {
+    let options: DfuOptions = crate::options(options)?;
+    let mut transport = JsDfuTransport {
+        control_cb,
+        delay_cb,
+    };
+    let mut progress = |done: u32, total: u32| {
+        if let Some(cb) = &progress_cb {
+            let _ = cb.call2(
+                &JsValue::NULL,
+                &JsValue::from_f64(done as f64),
+                &JsValue::from_f64(total as f64),
+            );
+        }
+    };
+    run(&mut transport, plan, &options, &mut progress)
+        .await
+        .map_err(|e| JsValue::from_str(&e))
+}
@@ -0,0 +1,333 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! A simulated STM32F4 DfuSe bootloader.
+//!
+//! Behaves as the ROM bootloader does towards the host: commands and writes
+//! take effect on the GETSTATUS after their DNLOAD, which reports the device
+//! busy once before returning to dfuDNLOAD-IDLE, and failures leave it in
+//! dfuERROR until CLRSTATUS. Programming a byte that is not erased fails
+//! with errPROG, as does writing outside flash with errADDRESS.
+//!
+//! Test only: the tests below run [`FlashPlan`](crate::flash_plan::FlashPlan)s
+//! through [`run`](crate::dfu::run) against it, so the DfuSe sequence can be
+//! checked without hardware.
+
+use onerom_config::mcu::Variant;
+
+use crate::dfu::{
+    CMD_ERASE, CMD_SET_ADDRESS, DFU_ABORT, DFU_CLRSTATUS, DFU_DNLOAD, DFU_GETSTATE, DFU_GETSTATUS,
+    DfuTransport, STATUS_OK, State, Status,
+};
+use crate::flash_plan::{ERASED, Geometry};
+use crate::test_util::SimulatedFlash;
+
+const ERR_WRITE: u8 = 3;
+const ERR_PROG: u8 = 6;
+const ERR_ADDRESS: u8 = 8;
+const ERR_STALLEDPKT: u8 = 15;
+
+/// Poll timeouts the device reports, in ms.
+const ERASE_MS: u32 = 25;
+const WRITE_MS: u32 = 5;
+
+/// A DNLOAD waiting for the GETSTATUS that carries it out.
+enum Pending {
+    SetAddress(u32),
+    Erase(u32),
+    Write(u32, Vec<u8>),
+    Leave,
+}
+
+/// A simulated DfuSe device in dfuIDLE with erased flash.
+pub(crate) struct SimulatedDfuDevice {
+    geometry: Geometry,
+    flash: Vec<u8>,
+    transfer_size: u16,
+    state: State,
+    status: u8,
+    pointer: u32,
+    pending: Option<Pending>,
+    /// DNLOADs seen so far.
+    downloads: u32,
+    /// DNLOAD numbers that fail once with errWRITE.
+    faults: Vec<u32>,
+    /// Set once the device has left DFU mode.
+    left: bool,
+}
+
+impl SimulatedDfuDevice {
+    pub(crate) fn new(variant: Variant, transfer_size: u16, faults: Vec<u32>) -> Self {
+        let geometry = Geometry::of(variant);
+        Self {
+            flash: vec![ERASED; geometry.size as usize],
+            pointer: geometry.base,
+            geometry,
+            transfer_size,
+            state: State::Idle,
+            status: STATUS_OK,
+            pending: None,
+            downloads: 0,
+            faults,
+            left: false,
+        }
+    }
+
+    fn fail(&mut self, status: u8) {
+        self.status = status;
+        self.state = State::Error;
+        self.pending = None;
+    }
+
+    fn stall(&mut self, why: &str) -> String {
+        self.fail(ERR_STALLEDPKT);
+        format!("Control transfer stalled: {why}")
+    }
+
+    fn dnload(&mut self, block: u16, data: &[u8]) -> Result<(), String> {
+        if !matches!(self.state, State::Idle | State::DnloadIdle) {
+            return Err(self.stall("DNLOAD outside dfuIDLE or dfuDNLOAD-IDLE"));
+        }
+        if data.len() > self.transfer_size as usize {
+            return Err(self.stall("DNLOAD longer than the transfer size"));
+        }
+        let address = |data: &[u8]| -> Option<u32> {
+            Some(u32::from_le_bytes(data.get(1..5)?.try_into().ok()?))
+        };
+        let pending = match (block, data) {
+            (0, []) => Pending::Leave,
+            (0, [CMD_SET_ADDRESS, ..]) if data.len() == 5 => {
+                Pending::SetAddress(address(data).unwrap_or_default())
+            }
+            (0, [CMD_ERASE, ..]) if data.len() == 5 => {
+                Pending::Erase(address(data).unwrap_or_default())
+            }
+            (0, _) => return Err(self.stall("unknown DfuSe command")),
+            (1, _) => return Err(self.stall("DNLOAD of block 1")),
+            (block, data) => Pending::Write(
+                self.pointer + (block as u32 - 2) * self.transfer_size as u32,
+                data.to_vec(),
+            ),
+        };
+
+        let download = self.downloads;
+        self.downloads += 1;
+        if let Some(i) = self.faults.iter().position(|&f| f == download) {
+            self.faults.remove(i);
+            self.fail(ERR_WRITE);
+            self.state = State::DnloadSync;
+            return Ok(());
+        }
+        self.pending = Some(pending);
+        self.state = match self.pending {
+            Some(Pending::Leave) => State::ManifestSync,
+            _ => State::DnloadSync,
+        };
+        Ok(())
+    }
+
+    /// Carry out the pending DNLOAD, returning the poll timeout or the
+    /// error status.
+    fn execute(&mut self) -> Result<u32, u8> {
+        match self.pending.take() {
+            Some(Pending::SetAddress(address)) => {
+                self.pointer = address;
+                Ok(WRITE_MS)
+            }
+            Some(Pending::Erase(address)) => {
+                let sector = *self
+                    .geometry
+                    .sectors_for(address, 1)
+                    .next()
+                    .ok_or(ERR_ADDRESS)?;
+                let offset = (sector.address - self.geometry.base) as usize;
+                self.flash[offset..offset + sector.size as usize].fill(ERASED);
+                Ok(ERASE_MS)
+            }
+            Some(Pending::Write(address, data)) => {
+                let base = self.geometry.base;
+                let target = address
+                    .checked_sub(base)
+                    .and_then(|o| self.flash.get_mut(o as usize..o as usize + data.len()))
+                    .ok_or(ERR_ADDRESS)?;
+                if target.iter().any(|&b| b != ERASED) {
+                    return Err(ERR_PROG);
+                }
+                target.copy_from_slice(&data);
+                Ok(WRITE_MS)
+            }
+            Some(Pending::Leave) | None => Ok(0),
+        }
+    }
+
+    fn get_status(&mut self) -> Result<Vec<u8>, String> {
+        if self.status != STATUS_OK && self.state == State::DnloadSync {
+            // An injected fault, reported now.
+            self.state = State::Error;
+        }
+        let poll_timeout_ms = match self.state {
+            State::DnloadSync => match self.execute() {
+                Ok(ms) => {
+                    self.state = State::DnBusy;
+                    ms
+                }
+                Err(status) => {
+                    self.fail(status);
+                    0
+                }
+            },
+            State::DnBusy => {
+                self.state = State::DnloadIdle;
+                0
+            }
+            State::ManifestSync => {
+                // The bootloader jumps to the firmware rather than answer.
+                self.left = true;
+                return Err("Device disconnected".to_string());
+            }
+            _ => 0,
+        };
+        Ok(Status {
+            status: self.status,
+            poll_timeout_ms,
+            state: self.state,
+        }
+        .to_bytes())
+    }
+}
+
+impl DfuTransport for SimulatedDfuDevice {
+    async fn control_out(&mut self, request: u8, value: u16, data: &[u8]) -> Result<(), String> {
+        if self.left {
+            return Err("Device disconnected".to_string());
+        }
+        match request {
+            DFU_DNLOAD => self.dnload(value, data),
+            DFU_CLRSTATUS if self.state == State::Error => {
+                self.status = STATUS_OK;
+                self.state = State::Idle;
+                Ok(())
+            }
+            DFU_ABORT => {
+                self.pending = None;
+                self.state = State::Idle;
+                Ok(())
+            }
+            _ => Err(self.stall("unexpected OUT request")),
+        }
+    }
+
+    async fn control_in(
+        &mut self,
+        request: u8,
+        _value: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, String> {
+        if self.left {
+            return Err("Device disconnected".to_string());
+        }
+        let mut bytes = match request {
+            DFU_GETSTATUS => self.get_status()?,
+            DFU_GETSTATE => vec![self.state as u8],
+            _ => return Err(self.stall("unexpected IN request")),
+        };
+        bytes.truncate(length as usize);
+        Ok(bytes)
+    }
+
+    async fn delay(&mut self, _ms: u32) {}
+}
+
+impl SimulatedFlash for SimulatedDfuDevice {
+    fn flash(&self) -> (u32, &[u8]) {
+        (self.geometry.base, &self.flash)
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::dfu::{DEFAULT_TRANSFER_SIZE, DfuOptions, run};
+    use crate::flash_plan::FlashPlan;
+    use crate::test_util::{holds, run_ready};
+
+    fn test_plan() -> FlashPlan {
+        crate::test_util::test_plan(Variant::F411RE)
+    }
+
+    fn flash(
+        device: &mut SimulatedDfuDevice,
+        plan: &FlashPlan,
+        options: &DfuOptions,
+    ) -> Result<crate::DfuReport, String> {
+        run_ready(run(device, plan, options, &mut |_, _| {}))
+    }
+
+    #[test]
+    fn flashes_plan() {
+        let plan = test_plan();
+        let mut device = SimulatedDfuDevice::new(plan.variant, DEFAULT_TRANSFER_SIZE, vec![]);
+        let report = flash(&mut device, &plan, &DfuOptions::default()).unwrap();
+        assert!(holds(&device, &plan));
+        assert_eq!(report.sectors_erased, plan.erase.len() as u32);
+        assert_eq!(report.chunks_written, plan.writes.len() as u32);
+        assert_eq!(report.retries, 0);
+        assert!(report.manifested && device.left);
+    }
+
+    #[test]
+    fn retries_failed_downloads() {
+        let plan = test_plan();
+        let mut device =
+            SimulatedDfuDevice::new(plan.variant, DEFAULT_TRANSFER_SIZE, vec![0, 3, 7]);
+        let report = flash(&mut device, &plan, &DfuOptions::default()).unwrap();
+        assert!(holds(&device, &plan));
+        assert_eq!(report.retries, 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let plan = test_plan();
+        let mut device =
+            SimulatedDfuDevice::new(plan.variant, DEFAULT_TRANSFER_SIZE, vec![3, 4, 5]);
+        let options = DfuOptions {
+            retries: Some(2),
+            ..Default::default()
+        };
+        let e = flash(&mut device, &plan, &options).unwrap_err();
+        assert!(e.contains("errWRITE"), "{e}");
+        assert!(!device.left);
+    }
+
+    #[test]
+    fn recovers_device_left_in_error() {
+        let plan = test_plan();
+        let mut device = SimulatedDfuDevice::new(plan.variant, DEFAULT_TRANSFER_SIZE, vec![]);
+        device.fail(ERR_STALLEDPKT);
+        let report = flash(&mut device, &plan, &DfuOptions::default()).unwrap();
+        assert!(holds(&device, &plan));
+        assert!(report.log[0].contains("errSTALLEDPKT"), "{:?}", report.log);
+    }
+
+    #[test]
+    fn rejects_writes_to_unerased_flash() {
+        let mut plan = test_plan();
+        plan.erase.clear();
+        let mut device = SimulatedDfuDevice::new(plan.variant, DEFAULT_TRANSFER_SIZE, vec![]);
+        device.flash[0] = 0;
+        let e = flash(&mut device, &plan, &DfuOptions::default()).unwrap_err();
+        assert!(e.contains("errPROG"), "{e}");
+    }
+
+    #[test]
+    fn rejects_oversized_writes() {
+        let plan = test_plan();
+        let mut device = SimulatedDfuDevice::new(plan.variant, 1024, vec![]);
+        let options = DfuOptions {
+            transfer_size: Some(1024),
+            ..Default::default()
+        };
+        let e = flash(&mut device, &plan, &options).unwrap_err();
+        assert!(e.contains("chunk_size of 1024"), "{e}");
+    }
+}
@@ -0,0 +1,66 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Digest helpers shared by the file verification paths.
+//!
+//! Digests cross the JS boundary and live in config files as lowercase hex
+//! strings: 64 characters for SHA-256, 40 for SHA-1, 8 for CRC32. Parsing
+//! accepts either case and, for CRC32, an optional `0x` prefix, since that is
+//! how CRCs are usually quoted.
+
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+/// SHA-256 of `data`, as lowercase hex.
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// SHA-1 of `data`, as lowercase hex.
+pub(crate) fn sha1_hex(data: &[u8]) -> String {
+    hex::encode(Sha1::digest(data))
+}
+
+/// CRC32 (IEEE) of `data`.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+/// Format a CRC32 the way it is reported back to JS.
+pub(crate) fn crc32_hex(crc: u32) -> String {
+    format!("{crc:08x}")
+}
+
+/// Normalise a SHA-256 hex string to lowercase, rejecting anything that is not
+/// 64 hex digits.
+pub(crate) fn parse_sha256(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.len() != 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid sha256 '{s}': expected 64 hex digits"));
+    }
+    Ok(s.to_ascii_lowercase())
+}
+
+/// Normalise a SHA-1 hex string to lowercase, rejecting anything that is not
+/// 40 hex digits.
+pub(crate) fn parse_sha1(s: &str) -> Result<String, String> {
+    let s = s.trim();
+    if s.len() != 40 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid sha1 '{s}': expected 40 hex digits"));
+    }
+    Ok(s.to_ascii_lowercase())
+}
+
+/// Parse a CRC32 hex string, with or without a `0x` prefix.
+pub(crate) fn parse_crc32(s: &str) -> Result<u32, String> {
+    let s = s.trim();
+    let digits = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    if digits.is_empty() || digits.len() > 8 {
+        return Err(format!("Invalid crc32 '{s}': expected up to 8 hex digits"));
+    }
+    u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid crc32 '{s}': not hex"))
+}
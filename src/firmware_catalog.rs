@@ -0,0 +1,378 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Official firmware release catalogue.
+//!
+//! The firmware counterpart of [`PluginCatalog`](crate::PluginCatalog): a
+//! manifest, at a URL the caller supplies, lists every firmware release and,
+//! per release, a build for each board and MCU variant, with the build's
+//! SHA-256 and the range of chip types it serves. It is fetched once through
+//! the same JS callback the plugin catalogue uses, then queried in memory.
+//!
+//! A build's `min_chip_type` and `max_chip_type`, where given, bound the
+//! chip types that build can serve by ROM size - some boards and MCUs only
+//! serve part of what the firmware version supports. Either may be absent,
+//! leaving that end unbounded. Which chip types a firmware version supports
+//! at all is already checked by `onerom-gen` when validating a config.
+//!
+//! Manifest entries naming a board or MCU this crate does not know are
+//! skipped rather than rejected, so an older WASM build keeps working against
+//! a manifest that has grown newer hardware.
+
+use onerom_app::LocalPluginFetch;
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::ChipType;
+use onerom_config::fw::FirmwareVersion;
+use onerom_config::hw::Board;
+use onerom_config::mcu::Variant as McuVariant;
+use onerom_gen::Builder as GenBuilder;
+
+use crate::{JsFetch, digest};
+
+#[derive(Deserialize)]
+struct ManifestWire {
+    releases: Vec<ReleaseWire>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseWire {
+    version: String,
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+    builds: Vec<BuildWire>,
+}
+
+#[derive(Deserialize)]
+struct BuildWire {
+    board: String,
+    mcu: String,
+    url: String,
+    sha256: String,
+    #[serde(default)]
+    size: Option<usize>,
+    #[serde(default)]
+    min_chip_type: Option<String>,
+    #[serde(default)]
+    max_chip_type: Option<String>,
+}
+
+/// A firmware release and its builds.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FirmwareRelease {
+    /// Firmware version, `major.minor.patch`.
+    pub version: String,
+    /// Release date, as published.
+    pub date: Option<String>,
+    /// Short release notes.
+    pub notes: Option<String>,
+    /// One build per board and MCU variant.
+    pub builds: Vec<FirmwareBuild>,
+}
+
+/// One board/MCU build of a firmware release.
+#[derive(Debug, Clone, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FirmwareBuild {
+    /// Board name, as [`boards`](crate::boards) returns it.
+    pub board: String,
+    /// MCU variant, e.g. "F411RE".
+    pub mcu: String,
+    /// MCU family, e.g. "STM32F4".
+    pub mcu_family: String,
+    /// Fully-resolved binary URL.
+    pub url: String,
+    /// SHA-256 of the binary, as lowercase hex.
+    pub sha256: String,
+    /// Binary size in bytes, where published.
+    pub size: Option<usize>,
+    /// Smallest chip type this build serves; `None` if unbounded.
+    pub min_chip_type: Option<String>,
+    /// Largest chip type this build serves; `None` if unbounded.
+    pub max_chip_type: Option<String>,
+}
+
+impl FirmwareBuild {
+    /// Convert a manifest build, or `None` if its board or MCU is unknown.
+    /// A relative `url` is resolved against `base`.
+    fn from_wire(w: BuildWire, base: &str) -> Result<Option<Self>, String> {
+        let (Some(board), Some(mcu)) = (
+            Board::try_from_str(&w.board),
+            McuVariant::try_from_str(&w.mcu),
+        ) else {
+            return Ok(None);
+        };
+        if board.mcu_family() != mcu.family() {
+            return Err(format!(
+                "Firmware build for {} lists {mcu}, which is not a {} MCU",
+                board.name(),
+                board.mcu_family()
+            ));
+        }
+
+        let url = if w.url.starts_with("https://") || w.url.starts_with("http://") {
+            w.url
+        } else {
+            format!("{base}/{}", w.url.trim_start_matches('/'))
+        };
+
+        // Normalise the bounds to their primary name. Unlike an unknown board,
+        // an unknown bound can't be skipped without widening the range.
+        let chip_type = |t: Option<String>| {
+            t.map(|t| {
+                ChipType::try_from_str(&t)
+                    .map(|chip_type| chip_type.name().to_string())
+                    .ok_or_else(|| format!("Unknown chip type '{t}' in firmware build"))
+            })
+            .transpose()
+        };
+
+        Ok(Some(Self {
+            board: board.name().to_string(),
+            mcu: mcu.to_string(),
+            mcu_family: board.mcu_family().to_string(),
+            url,
+            sha256: digest::parse_sha256(&w.sha256)?,
+            size: w.size,
+            min_chip_type: chip_type(w.min_chip_type)?,
+            max_chip_type: chip_type(w.max_chip_type)?,
+        }))
+    }
+
+    fn is_for(&self, board: Board, mcu: McuVariant) -> bool {
+        self.board == board.name() && self.mcu == mcu.to_string()
+    }
+
+    /// Whether this build serves every chip type in `config`.
+    fn serves(&self, config: &onerom_gen::Config) -> bool {
+        // Bounds were checked to be known chip types when parsed.
+        let size = |t: &Option<String>| {
+            t.as_deref()
+                .and_then(ChipType::try_from_str)
+                .map(|t| t.size_bytes())
+        };
+        let (min, max) = (size(&self.min_chip_type), size(&self.max_chip_type));
+        config
+            .chip_sets
+            .iter()
+            .flat_map(|set| &set.chips)
+            .map(|chip| chip.chip_type.size_bytes())
+            .all(|s| min.is_none_or(|min| s >= min) && max.is_none_or(|max| s <= max))
+    }
+}
+
+/// The firmware build chosen for a board, as returned to JavaScript.
+///
+/// Mirrors [`WasmPluginRelease`](crate::WasmPluginRelease): the version to
+/// pass to `gen_builder_from_json`, and the binary's URL and SHA-256.
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct WasmFirmwareRelease {
+    pub version: String,
+    pub board: String,
+    pub mcu: String,
+    pub url: String,
+    pub sha256: String,
+}
+
+/// The catalogue of official firmware releases.
+///
+/// Constructed by [`firmware_catalog`]. Once built, it answers listing and
+/// compatibility queries entirely in memory.
+#[wasm_bindgen]
+pub struct FirmwareCatalog {
+    /// Releases, newest first, each with its parsed version.
+    releases: Vec<(FirmwareVersion, FirmwareRelease)>,
+}
+
+impl FirmwareCatalog {
+    fn from_wire(w: ManifestWire, base: &str) -> Result<Self, String> {
+        let mut releases = Vec::with_capacity(w.releases.len());
+        for r in w.releases {
+            let version = FirmwareVersion::try_from_str(&r.version)
+                .map_err(|_| format!("Invalid firmware version '{}' in manifest", r.version))?;
+            let builds = r
+                .builds
+                .into_iter()
+                .filter_map(|b| FirmwareBuild::from_wire(b, base).transpose())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| format!("Firmware {version}: {e}"))?;
+            releases.push((
+                version,
+                FirmwareRelease {
+                    version: version.to_string(),
+                    date: r.date,
+                    notes: r.notes,
+                    builds,
+                },
+            ));
+        }
+        releases.sort_by_key(|(version, _)| std::cmp::Reverse(*version));
+        Ok(Self { releases })
+    }
+
+    /// Releases with a build satisfying `f`, carrying only those builds.
+    fn filtered(&self, f: impl Fn(&FirmwareBuild) -> bool) -> Vec<FirmwareRelease> {
+        self.releases
+            .iter()
+            .filter_map(|(_, r)| {
+                let builds: Vec<_> = r.builds.iter().filter(|b| f(b)).cloned().collect();
+                (!builds.is_empty()).then(|| FirmwareRelease {
+                    builds,
+                    ..r.clone()
+                })
+            })
+            .collect()
+    }
+
+    fn build(&self, version: &str, board: &str, mcu: &str) -> Result<&FirmwareBuild, JsValue> {
+        let version = parse_version(version)?;
+        let (board, mcu) = parse_target(board, mcu)?;
+        self.releases
+            .iter()
+            .filter(|(v, _)| v.matches_release(&version))
+            .flat_map(|(_, r)| &r.builds)
+            .find(|b| b.is_for(board, mcu))
+            .ok_or_else(|| {
+                JsValue::from_str(&format!(
+                    "No firmware {version} build for {} with {mcu}",
+                    board.name()
+                ))
+            })
+    }
+}
+
+#[wasm_bindgen]
+impl FirmwareCatalog {
+    /// Every release, newest first, with all its builds.
+    pub fn releases(&self) -> Vec<FirmwareRelease> {
+        self.releases.iter().map(|(_, r)| r.clone()).collect()
+    }
+
+    /// Releases with a build for `board`, newest first, carrying only that
+    /// board's builds.
+    pub fn releases_for_board(&self, board: String) -> Result<Vec<FirmwareRelease>, JsValue> {
+        let board = Board::try_from_str(&board)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown board: {board}")))?;
+        Ok(self.filtered(|b| b.board == board.name()))
+    }
+
+    /// Releases with a build for an MCU family, newest first, carrying only
+    /// that family's builds.
+    pub fn releases_for_mcu_family(&self, family: String) -> Result<Vec<FirmwareRelease>, JsValue> {
+        let family = onerom_config::mcu::Family::try_from_str(&family)
+            .ok_or_else(|| JsValue::from_str(&format!("Unknown MCU family: {family}")))?;
+        Ok(self.filtered(|b| b.mcu_family == family.to_string()))
+    }
+
+    /// The newest firmware for `board` with `mcu` that can build `config_json`.
+    ///
+    /// A release qualifies if it has a build for the board and MCU, that build
+    /// serves every chip type in the config, and `onerom-gen` accepts the
+    /// config for that firmware version. With no config, the newest build for
+    /// the board is returned. Returns `undefined` if nothing qualifies.
+    pub fn newest_compatible(
+        &self,
+        board: String,
+        mcu: String,
+        config_json: Option<String>,
+    ) -> Result<Option<WasmFirmwareRelease>, JsValue> {
+        let (board, mcu) = parse_target(&board, &mcu)?;
+
+        let found = self.releases.iter().find_map(|(version, r)| {
+            let build = r.builds.iter().find(|b| b.is_for(board, mcu))?;
+            if let Some(json) = &config_json {
+                let builder = GenBuilder::from_json(*version, board.mcu_family(), json).ok()?;
+                if !build.serves(builder.config()) {
+                    return None;
+                }
+            }
+            Some((version, build))
+        });
+
+        Ok(found.map(|(version, build)| WasmFirmwareRelease {
+            version: version.to_string(),
+            board: build.board.clone(),
+            mcu: build.mcu.clone(),
+            url: build.url.clone(),
+            sha256: build.sha256.clone(),
+        }))
+    }
+
+    /// Check a downloaded firmware binary against the catalogue's SHA-256
+    /// (and size, where published) for that version, board and MCU.
+    pub fn verify(
+        &self,
+        version: String,
+        board: String,
+        mcu: String,
+        data: &[u8],
+    ) -> Result<(), JsValue> {
+        let build = self.build(&version, &board, &mcu)?;
+        if let Some(size) = build.size
+            && size != data.len()
+        {
+            return Err(JsValue::from_str(&format!(
+                "Firmware is {} bytes, expected {size}",
+                data.len()
+            )));
+        }
+        let actual = digest::sha256_hex(data);
+        if actual != build.sha256 {
+            return Err(JsValue::from_str(&format!(
+                "Firmware sha256 mismatch: expected {}, got {actual}",
+                build.sha256
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn parse_version(version: &str) -> Result<FirmwareVersion, JsValue> {
+    FirmwareVersion::try_from_str(version)
+        .map_err(|_| JsValue::from_str("invalid firmware version format"))
+}
+
+fn parse_target(board: &str, mcu: &str) -> Result<(Board, McuVariant), JsValue> {
+    let board = Board::try_from_str(board)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown board: {board}")))?;
+    let mcu = McuVariant::try_from_str(mcu)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown MCU: {mcu}")))?;
+    Ok((board, mcu))
+}
+
+/// Fetch the firmware release manifest at `manifest_url`, returning a
+/// catalogue handle.
+///
+/// Builds' relative URLs are resolved against the manifest's directory.
+/// `fetch_callback` is a JS async function `(url: string) => Promise<Uint8Array>`,
+/// as for [`plugin_catalog`](crate::plugin_catalog). Binaries are not fetched
+/// here: JS fetches the chosen build's `url` and checks it with
+/// [`FirmwareCatalog::verify`].
+#[wasm_bindgen]
+pub async fn firmware_catalog(
+    manifest_url: String,
+    fetch_callback: js_sys::Function,
+) -> Result<FirmwareCatalog, JsValue> {
+    let fetch = JsFetch {
+        callback: fetch_callback,
+    };
+
+    let bytes = fetch
+        .fetch(&manifest_url)
+        .await
+        .map_err(|e| JsValue::from_str(&e))?;
+    let manifest: ManifestWire = serde_json::from_slice(&bytes)
+        .map_err(|e| JsValue::from_str(&format!("Invalid firmware manifest: {e}")))?;
+
+    let base = manifest_url
+        .rsplit_once('/')
+        .map_or(manifest_url.as_str(), |(dir, _)| dir);
+    FirmwareCatalog::from_wire(manifest, base).map_err(|e| JsValue::from_str(&e))
+}
@@ -0,0 +1,231 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Incremental reflashing.
+//!
+//! A full [`FlashPlan`] erases and rewrites every sector the image touches.
+//! When only a ROM or two has changed, most of those sectors already hold
+//! what the plan would leave in them, and skipping them saves most of the
+//! time over DFU or SWD.
+//!
+//! After a plan is carried out, each sector it erases holds the plan's
+//! writes with erased bytes around them. A sector is skipped if the device
+//! already holds exactly that, judged either from a dump of the device's
+//! flash or from a SHA-256 of each sector the device reports. A write that
+//! crosses out of a changed sector pulls the sector it crosses into along
+//! with it, since a sector cannot be written without first being erased.
+
+use wasm_bindgen::prelude::*;
+
+use crate::digest::{parse_sha256, sha256_hex};
+use crate::flash_plan::{ERASED, FlashPlan, FlashSector};
+
+/// What `plan` leaves in each sector it erases.
+fn expected_sectors(plan: &FlashPlan) -> Vec<(FlashSector, Vec<u8>)> {
+    plan.erase
+        .iter()
+        .map(|sector| {
+            let mut content = vec![ERASED; sector.size as usize];
+            let end = sector.address + sector.size;
+            for w in &plan.writes {
+                let w_end = w.address + w.data.len() as u32;
+                if w.address >= end || w_end <= sector.address {
+                    continue;
+                }
+                let from = w.address.max(sector.address);
+                let to = w_end.min(end);
+                content[(from - sector.address) as usize..(to - sector.address) as usize]
+                    .copy_from_slice(
+                        &w.data[(from - w.address) as usize..(to - w.address) as usize],
+                    );
+            }
+            (*sector, content)
+        })
+        .collect()
+}
+
+/// The part of `plan` erasing and writing the `changed` sectors, and any
+/// sectors writes into them cross into.
+fn restrict(plan: &FlashPlan, mut changed: Vec<FlashSector>) -> FlashPlan {
+    let overlaps = |address: u32, len: u32, s: &FlashSector| {
+        s.address < address + len && address < s.address + s.size
+    };
+    loop {
+        let before = changed.len();
+        for w in &plan.writes {
+            let len = w.data.len() as u32;
+            if changed.iter().any(|s| overlaps(w.address, len, s)) {
+                for sector in plan.erase.iter().filter(|s| overlaps(w.address, len, s)) {
+                    if !changed.contains(sector) {
+                        changed.push(*sector);
+                    }
+                }
+            }
+        }
+        if changed.len() == before {
+            break;
+        }
+    }
+
+    FlashPlan {
+        variant: plan.variant,
+        erase: plan
+            .erase
+            .iter()
+            .filter(|s| changed.contains(s))
+            .copied()
+            .collect(),
+        writes: plan
+            .writes
+            .iter()
+            .filter(|w| {
+                changed
+                    .iter()
+                    .any(|s| overlaps(w.address, w.data.len() as u32, s))
+            })
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Reduce `plan` to the sectors a device's flash does not already match.
+///
+/// `plan` is a full plan from [`flash_plan`](crate::flash_plan) for the new
+/// image. `current` is the device's flash as read back from its base; a
+/// sector `current` does not reach counts as changed. The result erases and
+/// writes only the changed sectors, and can be carried out by any of the
+/// flashers. If nothing has changed it is empty.
+#[wasm_bindgen]
+pub fn incremental_flash_plan(plan: &FlashPlan, current: &[u8]) -> FlashPlan {
+    let base = plan.variant.family().get_flash_base();
+    let changed = expected_sectors(plan)
+        .into_iter()
+        .filter(|(sector, expected)| {
+            let offset = (sector.address - base) as usize;
+            current.get(offset..offset + expected.len()) != Some(&expected[..])
+        })
+        .map(|(sector, _)| sector)
+        .collect();
+    restrict(plan, changed)
+}
+
+/// Reduce `plan` to the sectors a device's flash does not already match,
+/// asking the device for a hash of each sector rather than reading it all.
+///
+/// `hash_cb` is a JS `async (address: number, length: number) => string`
+/// returning the SHA-256, as hex, of `length` bytes of device flash at
+/// `address`. It is called once per sector `plan` erases. Otherwise as for
+/// [`incremental_flash_plan`].
+#[wasm_bindgen]
+pub async fn incremental_flash_plan_by_hash(
+    plan: &FlashPlan,
+    hash_cb: js_sys::Function,
+) -> Result<FlashPlan, JsValue> {
+    let mut changed = Vec::new();
+    for (sector, expected) in expected_sectors(plan) {
+        let promise = hash_cb
+            .call2(
+                &JsValue::NULL,
+                &JsValue::from_f64(sector.address as f64),
+                &JsValue::from_f64(sector.size as f64),
+            )
+            .map_err(|e| JsValue::from_str(&format!("hash callback threw: {e:?}")))?;
+        let hash = wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&promise))
+            .await
+            .map_err(|e| {
+                JsValue::from_str(&format!("hash failed for sector {}: {e:?}", sector.index))
+            })?
+            .as_string()
+            .ok_or_else(|| {
+                JsValue::from_str(&format!(
+                    "hash callback returned a non-string for sector {}",
+                    sector.index
+                ))
+            })?;
+        let hash = parse_sha256(&hash).map_err(|e| JsValue::from_str(&e))?;
+        if hash != sha256_hex(&expected) {
+            changed.push(sector);
+        }
+    }
+    Ok(restrict(plan, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use onerom_config::mcu::Variant;
+
+    use super::*;
+    use crate::flash_plan::plan;
+
+    /// Firmware whose 2KB chunks cross from sector 0 into 1 and from 1 into
+    /// 2, and ROMs in sector 4 alone.
+    fn test_plan() -> FlashPlan {
+        plan(
+            Variant::F411RE,
+            vec![
+                ("firmware", 0x3C00, vec![1; 0x4800]),
+                ("roms", 0x10000, vec![2; 100]),
+            ],
+            None,
+        )
+        .unwrap()
+    }
+
+    fn sectors(plan: &FlashPlan, indices: &[u32]) -> Vec<FlashSector> {
+        indices
+            .iter()
+            .map(|&i| *plan.erase.iter().find(|s| s.index == i).unwrap())
+            .collect()
+    }
+
+    fn indices(plan: &FlashPlan) -> Vec<u32> {
+        plan.erase.iter().map(|s| s.index).collect()
+    }
+
+    #[test]
+    fn keeps_unchanged_sectors_out() {
+        let plan = test_plan();
+        assert_eq!(indices(&plan), [0, 1, 2, 4]);
+        let reduced = restrict(&plan, sectors(&plan, &[4]));
+        assert_eq!(indices(&reduced), [4]);
+        assert!(reduced.writes.iter().all(|w| w.region == "roms"));
+
+        let reduced = restrict(&plan, vec![]);
+        assert!(reduced.erase.is_empty() && reduced.writes.is_empty());
+    }
+
+    #[test]
+    fn pulls_in_crossed_sectors() {
+        let plan = test_plan();
+        // The chunk crossing 0 into 1 pulls in 1, and the one crossing 1
+        // into 2 then pulls in 2.
+        for changed in [0, 1, 2] {
+            let reduced = restrict(&plan, sectors(&plan, &[changed]));
+            assert_eq!(indices(&reduced), [0, 1, 2], "changed {changed}");
+            assert!(reduced.writes.iter().all(|w| w.region == "firmware"));
+            assert_eq!(reduced.writes.len(), 9);
+        }
+    }
+
+    #[test]
+    fn finds_changed_sectors_in_dump() {
+        let plan = test_plan();
+        let expected = expected_sectors(&plan);
+        let mut current = vec![ERASED; 0x20000];
+        for (sector, content) in &expected {
+            let offset = (sector.address - 0x0800_0000) as usize;
+            current[offset..offset + content.len()].copy_from_slice(content);
+        }
+        let reduced = incremental_flash_plan(&plan, &current);
+        assert!(reduced.erase.is_empty() && reduced.writes.is_empty());
+
+        current[0x10010] = 0;
+        assert_eq!(indices(&incremental_flash_plan(&plan, &current)), [4]);
+        // A dump that stops short counts the sectors past it as changed.
+        assert_eq!(
+            indices(&incremental_flash_plan(&plan, &current[..0x8000])),
+            [0, 1, 2, 4]
+        );
+    }
+}
@@ -0,0 +1,500 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Flash geometry and programming plans.
+//!
+//! One ROM flash holds the firmware binary at the flash base, the metadata
+//! [`WasmImages`] carries 48KB in, and the ROM images 64KB in. How that is
+//! erased and written depends on the MCU:
+//!
+//! - STM32F4 internal flash has four 16KB sectors, one of 64KB, then 128KB
+//!   sectors to the end, and is written through DfuSe in 2KB transfers.
+//! - RP2350 boots from external QSPI flash, erased in 4KB sectors and
+//!   programmed in 256-byte pages.
+//!
+//! A [`FlashPlan`] lists the sectors to erase and the chunks to write, each
+//! padded with erased bytes to the program alignment, so a flasher only has
+//! to carry them out.
+
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::mcu::{Family, Variant};
+use onerom_gen::{FIRMWARE_SIZE, MAX_METADATA_LEN};
+
+use crate::WasmImages;
+use crate::image_overrides::Metadata;
+
+/// Value of an erased flash byte.
+pub(crate) const ERASED: u8 = 0xFF;
+
+/// Offset of the metadata from the flash base.
+const METADATA_OFFSET: u32 = FIRMWARE_SIZE as u32;
+/// Offset of the ROM images from the flash base.
+const ROM_IMAGES_OFFSET: u32 = (FIRMWARE_SIZE + MAX_METADATA_LEN) as u32;
+
+/// An MCU's flash, from [`mcu_flash_layout`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FlashLayout {
+    pub mcu: String,
+    pub family: String,
+    /// Address flash is mapped at.
+    pub base: u32,
+    /// Flash size in bytes.
+    pub size: u32,
+    /// Value of an erased byte.
+    pub erased_value: u8,
+    /// Writes must start at, and be padded to, a multiple of this.
+    pub program_align: u32,
+    /// Write chunk size a plan uses by default.
+    pub default_chunk_size: u32,
+    /// Erase sectors, in address order.
+    pub sectors: Vec<FlashSector>,
+    /// Offsets of the metadata and ROM images from `base`.
+    pub metadata_offset: u32,
+    pub rom_images_offset: u32,
+}
+
+/// An erase sector.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FlashSector {
+    /// Sector number, counting from 0 at the flash base.
+    pub index: u32,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// A chunk to write in a [`FlashPlan`]. Its data is fetched with
+/// [`FlashPlan::write_data`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FlashWrite {
+    pub address: u32,
+    /// Length including padding.
+    pub length: u32,
+    /// Erased bytes appended to reach `program_align`.
+    pub padding: u32,
+    /// "firmware", "metadata" or "rom_images".
+    pub region: String,
+}
+
+/// Options for [`flash_plan`]. All optional.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct PlanOptions {
+    /// Maximum bytes per write; a multiple of the program alignment.
+    chunk_size: Option<u32>,
+}
+
+/// Flash geometry for an MCU variant.
+#[derive(Debug, Clone)]
+pub(crate) struct Geometry {
+    pub(crate) base: u32,
+    pub(crate) size: u32,
+    pub(crate) program_align: u32,
+    pub(crate) default_chunk_size: u32,
+    pub(crate) sectors: Vec<FlashSector>,
+}
+
+impl Geometry {
+    pub(crate) fn of(variant: Variant) -> Self {
+        let base = variant.family().get_flash_base();
+        let size = variant.flash_storage_bytes() as u32;
+        let (sector_sizes, program_align, default_chunk_size) = match variant.family() {
+            Family::Stm32f4 => {
+                let mut sizes = vec![16 * 1024; 4];
+                sizes.push(64 * 1024);
+                let rest = size.saturating_sub(128 * 1024) / (128 * 1024);
+                sizes.extend(std::iter::repeat_n(128 * 1024, rest as usize));
+                (sizes, 4, 2048)
+            }
+            Family::Rp2350 => (vec![4096; (size / 4096) as usize], 256, 4096),
+        };
+
+        let mut address = base;
+        let sectors = sector_sizes
+            .into_iter()
+            .enumerate()
+            .map(|(index, size)| {
+                let sector = FlashSector {
+                    index: index as u32,
+                    address,
+                    size,
+                };
+                address += size;
+                sector
+            })
+            .collect();
+        Self {
+            base,
+            size,
+            program_align,
+            default_chunk_size,
+            sectors,
+        }
+    }
+
+    /// Sectors overlapping `address..address + len`.
+    pub(crate) fn sectors_for(&self, address: u32, len: u32) -> impl Iterator<Item = &FlashSector> {
+        let end = address + len;
+        self.sectors
+            .iter()
+            .filter(move |s| len > 0 && s.address < end && address < s.address + s.size)
+    }
+}
+
+/// Return the flash geometry of MCU variant `name`.
+#[wasm_bindgen]
+pub fn mcu_flash_layout(name: String) -> Result<FlashLayout, JsValue> {
+    let variant = Variant::try_from_str(&name)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown MCU variant: {}", name)))?;
+    let geometry = Geometry::of(variant);
+    Ok(FlashLayout {
+        mcu: variant.to_string(),
+        family: variant.family().to_string(),
+        base: geometry.base,
+        size: geometry.size,
+        erased_value: ERASED,
+        program_align: geometry.program_align,
+        default_chunk_size: geometry.default_chunk_size,
+        sectors: geometry.sectors,
+        metadata_offset: METADATA_OFFSET,
+        rom_images_offset: ROM_IMAGES_OFFSET,
+    })
+}
+
+/// A write in a [`FlashPlan`], with its data.
+#[derive(Debug, Clone)]
+pub(crate) struct PlannedWrite {
+    pub(crate) address: u32,
+    pub(crate) padding: u32,
+    pub(crate) region: &'static str,
+    /// Data including padding.
+    pub(crate) data: Vec<u8>,
+}
+
+/// What to erase and write to flash a built image, from [`flash_plan`].
+#[wasm_bindgen]
+pub struct FlashPlan {
+    pub(crate) variant: Variant,
+    pub(crate) erase: Vec<FlashSector>,
+    pub(crate) writes: Vec<PlannedWrite>,
+}
+
+#[wasm_bindgen]
+impl FlashPlan {
+    #[wasm_bindgen(getter)]
+    pub fn mcu(&self) -> String {
+        self.variant.to_string()
+    }
+
+    /// Sectors to erase, in address order.
+    pub fn erase_sectors(&self) -> Vec<FlashSector> {
+        self.erase.clone()
+    }
+
+    /// Chunks to write, in address order.
+    pub fn writes(&self) -> Vec<FlashWrite> {
+        self.writes
+            .iter()
+            .map(|w| FlashWrite {
+                address: w.address,
+                length: w.data.len() as u32,
+                padding: w.padding,
+                region: w.region.to_string(),
+            })
+            .collect()
+    }
+
+    /// Data for write `index`, padding included.
+    pub fn write_data(&self, index: usize) -> Option<Vec<u8>> {
+        self.writes.get(index).map(|w| w.data.clone())
+    }
+
+    /// Total bytes written, padding included.
+    #[wasm_bindgen(getter)]
+    pub fn total_bytes(&self) -> u32 {
+        self.writes.iter().map(|w| w.data.len() as u32).sum()
+    }
+}
+
+/// Plan erasing and writing a built image to an MCU variant's flash.
+///
+/// `images` is the result of [`gen_build`](crate::gen_build). `firmware` is
+/// the firmware binary to write at the flash base, or omitted to leave the
+/// firmware in place and write only the metadata and ROM images.
+///
+/// `options` may be omitted, or an object with `chunk_size`, the most bytes
+/// a single write may carry (defaults to the layout's `default_chunk_size`).
+#[wasm_bindgen]
+pub fn flash_plan(
+    mcu: String,
+    images: &WasmImages,
+    firmware: Option<Vec<u8>>,
+    options: JsValue,
+) -> Result<FlashPlan, JsValue> {
+    let options: PlanOptions = crate::options(options)?;
+    let variant = Variant::try_from_str(&mcu)
+        .ok_or_else(|| JsValue::from_str(&format!("Unknown MCU variant: {}", mcu)))?;
+    check_images(variant, images).map_err(|e| JsValue::from_str(&e))?;
+
+    let mut regions = Vec::new();
+    if let Some(firmware) = firmware {
+        regions.push(("firmware", 0, firmware));
+    }
+    regions.push(("metadata", METADATA_OFFSET, images.0.clone()));
+    regions.push(("rom_images", ROM_IMAGES_OFFSET, images.1.clone()));
+
+    plan(variant, regions, options.chunk_size).map_err(|e| JsValue::from_str(&e))
+}
+
+/// Check `images` were built for `variant`'s MCU family: firmware 0.7.0+
+/// metadata only runs on RP2350, and 0.6.x metadata's pointers must be into
+/// the family's flash.
+fn check_images(variant: Variant, images: &WasmImages) -> Result<(), String> {
+    // Lay the images out as they will be in flash, for the pointers.
+    let mut image = vec![ERASED; ROM_IMAGES_OFFSET as usize];
+    let metadata = &images.0[..images.0.len().min(MAX_METADATA_LEN)];
+    image[METADATA_OFFSET as usize..][..metadata.len()].copy_from_slice(metadata);
+    image.extend_from_slice(&images.1);
+
+    let family = variant.family();
+    match Metadata::read(&image)? {
+        Metadata::V2(_) if family != Family::Rp2350 => Err(format!(
+            "Images are for firmware 0.7.0 or later, which only runs on RP2350, not {variant}"
+        )),
+        Metadata::V2(_) => Ok(()),
+        Metadata::V1(_) => {
+            let base = Metadata::flash_base(&image)?;
+            if base != family.get_flash_base() {
+                return Err(format!(
+                    "Images were built for flash at {base:#010x}, but {variant} flash is at {:#010x}",
+                    family.get_flash_base()
+                ));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Plan writing each `(region, offset from the flash base, data)`.
+pub(crate) fn plan(
+    variant: Variant,
+    regions: Vec<(&'static str, u32, Vec<u8>)>,
+    chunk_size: Option<u32>,
+) -> Result<FlashPlan, String> {
+    let geometry = Geometry::of(variant);
+    let align = geometry.program_align;
+    let chunk_size = chunk_size.unwrap_or(geometry.default_chunk_size);
+    if chunk_size == 0 || !chunk_size.is_multiple_of(align) {
+        return Err(format!(
+            "Chunk size {chunk_size} is not a multiple of the {variant} program alignment, {align}"
+        ));
+    }
+
+    let mut erase: Vec<FlashSector> = Vec::new();
+    let mut writes = Vec::new();
+    let mut previous_end = 0;
+    for (region, offset, data) in regions {
+        let len = data.len() as u32;
+        if len == 0 {
+            continue;
+        }
+        if offset % align != 0 {
+            return Err(format!(
+                "{region} at {offset:#x} is not {align}-byte aligned"
+            ));
+        }
+        if offset < previous_end {
+            return Err(format!(
+                "{region} at {offset:#x} overlaps the region before it, which ends at {previous_end:#x}"
+            ));
+        }
+        let padded = len.div_ceil(align) * align;
+        previous_end = offset + padded;
+        if previous_end > geometry.size {
+            return Err(format!(
+                "{region} ends {} bytes past the end of {variant} flash",
+                previous_end - geometry.size
+            ));
+        }
+
+        let address = geometry.base + offset;
+        for sector in geometry.sectors_for(address, padded) {
+            if !erase.contains(sector) {
+                erase.push(*sector);
+            }
+        }
+
+        for start in (0..padded).step_by(chunk_size as usize) {
+            let end = (start + chunk_size).min(padded);
+            let mut chunk = data[start as usize..(end.min(len)) as usize].to_vec();
+            let padding = (end - start) - chunk.len() as u32;
+            chunk.resize((end - start) as usize, ERASED);
+            writes.push(PlannedWrite {
+                address: address + start,
+                padding,
+                region,
+                data: chunk,
+            });
+        }
+    }
+
+    Ok(FlashPlan {
+        variant,
+        erase,
+        writes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STM32_BASE: u32 = 0x0800_0000;
+    const RP2350_BASE: u32 = 0x1000_0000;
+
+    fn indices(plan: &FlashPlan) -> Vec<u32> {
+        plan.erase.iter().map(|s| s.index).collect()
+    }
+
+    #[test]
+    fn maps_stm32f4_sectors() {
+        let sizes: Vec<u32> = Geometry::of(Variant::F411RE)
+            .sectors
+            .iter()
+            .map(|s| s.size / 1024)
+            .collect();
+        assert_eq!(sizes, [16, 16, 16, 16, 64, 128, 128, 128]);
+
+        let geometry = Geometry::of(Variant::F401RB);
+        assert_eq!(geometry.sectors.len(), 5);
+        let last = geometry.sectors.last().unwrap();
+        assert_eq!(last.address + last.size, STM32_BASE + geometry.size);
+        assert_eq!(last.address, STM32_BASE + 0x10000);
+    }
+
+    #[test]
+    fn erases_sectors_written() {
+        let plan = plan(
+            Variant::F411RE,
+            vec![
+                ("firmware", 0, vec![0; 20000]),
+                ("metadata", 0xC000, vec![0; 100]),
+                ("roms", 0x10000, vec![0; 0x10001]),
+            ],
+            None,
+        )
+        .unwrap();
+        // 20000 bytes cross into sector 1, and the ROMs from the 64KB
+        // sector 4 into sector 5.
+        assert_eq!(indices(&plan), [0, 1, 3, 4, 5]);
+    }
+
+    #[test]
+    fn chunks_and_pads_writes() {
+        let data: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+        let plan = plan(
+            Variant::RP2350,
+            vec![("roms", 0x1000, data.clone())],
+            Some(512),
+        )
+        .unwrap();
+        let writes: Vec<(u32, usize, u32)> = plan
+            .writes
+            .iter()
+            .map(|w| (w.address, w.data.len(), w.padding))
+            .collect();
+        assert_eq!(
+            writes,
+            [
+                (RP2350_BASE + 0x1000, 512, 0),
+                (RP2350_BASE + 0x1200, 256, 168)
+            ]
+        );
+        assert_eq!(plan.writes[1].data[..88], data[512..]);
+        assert!(plan.writes[1].data[88..].iter().all(|&b| b == ERASED));
+        assert_eq!(indices(&plan), [1]);
+    }
+
+    #[test]
+    fn skips_empty_regions() {
+        let plan = plan(
+            Variant::RP2350,
+            vec![("firmware", 0, vec![]), ("roms", 0x2000, vec![1])],
+            None,
+        )
+        .unwrap();
+        assert_eq!(plan.writes.len(), 1);
+        assert_eq!(indices(&plan), [2]);
+    }
+
+    #[test]
+    fn rejects_unaligned_regions() {
+        let e = plan(Variant::RP2350, vec![("roms", 0x10, vec![0; 4])], None)
+            .err()
+            .unwrap();
+        assert_eq!(e, "roms at 0x10 is not 256-byte aligned");
+        let e = plan(Variant::F411RE, vec![("roms", 2, vec![0; 4])], None)
+            .err()
+            .unwrap();
+        assert_eq!(e, "roms at 0x2 is not 4-byte aligned");
+    }
+
+    #[test]
+    fn rejects_bad_chunk_sizes() {
+        for chunk_size in [0, 100] {
+            let e = plan(
+                Variant::RP2350,
+                vec![("roms", 0, vec![0; 4])],
+                Some(chunk_size),
+            )
+            .err()
+            .unwrap();
+            assert!(e.starts_with(&format!("Chunk size {chunk_size} ")), "{e}");
+        }
+    }
+
+    #[test]
+    fn rejects_overlapping_regions() {
+        // Padding counts: 300 bytes at 0 take the RP2350 page to 0x200.
+        let e = plan(
+            Variant::RP2350,
+            vec![("firmware", 0, vec![0; 300]), ("roms", 0x100, vec![0; 4])],
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(
+            e.contains("overlaps the region before it, which ends at 0x200"),
+            "{e}"
+        );
+        assert!(
+            plan(
+                Variant::RP2350,
+                vec![("firmware", 0, vec![0; 300]), ("roms", 0x200, vec![0; 4])],
+                None,
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn rejects_regions_past_flash() {
+        let size = Geometry::of(Variant::F401RB).size;
+        let e = plan(Variant::F401RB, vec![("roms", size - 4, vec![0; 8])], None)
+            .err()
+            .unwrap();
+        assert_eq!(
+            e,
+            format!(
+                "roms ends 4 bytes past the end of {} flash",
+                Variant::F401RB
+            )
+        );
+    }
+}
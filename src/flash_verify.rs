@@ -0,0 +1,263 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Device flash verification.
+//!
+//! Reads back what a [`FlashPlan`] writes, a block at a time through the
+//! same read callback as [`parse_firmware`](crate::parse_firmware), and
+//! reports each run of differing bytes with the region it falls in, down to
+//! the ROM set (firmware 0.6.x) or slot (0.7.0+) the plan's metadata places
+//! there, and the erase sector holding it.
+
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_gen::FIRMWARE_SIZE;
+
+use crate::CallbackReader;
+use crate::flash_plan::{ERASED, FlashPlan, Geometry};
+use crate::image_overrides::Metadata;
+
+/// Default bytes read per callback.
+const DEFAULT_BLOCK_SIZE: u32 = 4096;
+/// Default number of mismatches reported before giving up.
+const DEFAULT_MAX_MISMATCHES: usize = 64;
+
+/// Options for [`verify_flash`]. All optional.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct VerifyOptions {
+    /// Bytes read per callback, default 4096.
+    block_size: Option<u32>,
+    /// Mismatches reported before stopping, default 64.
+    max_mismatches: Option<usize>,
+}
+
+/// Result of [`verify_flash`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FlashVerification {
+    pub mcu: String,
+    /// Whether every byte checked matched.
+    pub ok: bool,
+    pub bytes_checked: u32,
+    pub blocks_read: u32,
+    /// Runs of differing bytes, in address order.
+    pub mismatches: Vec<FlashMismatch>,
+    /// Indexes of the erase sectors holding a mismatch.
+    pub bad_sectors: Vec<u32>,
+    /// Set if checking stopped at `max_mismatches`.
+    pub truncated: bool,
+}
+
+/// A run of differing bytes in a [`FlashVerification`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct FlashMismatch {
+    pub address: u32,
+    pub length: u32,
+    /// "firmware", "metadata" or "rom_images".
+    pub region: String,
+    /// What in the region, e.g. "ROM slot 2 (kernal.bin)", where known.
+    pub detail: Option<String>,
+    /// Erase sector holding the first byte.
+    pub sector: u32,
+    /// First byte expected and read.
+    pub expected: u8,
+    pub actual: u8,
+}
+
+/// A named part of the image.
+struct Part {
+    address: u32,
+    size: u32,
+    name: String,
+}
+
+/// ROM sets or slots the plan's metadata describes, by address.
+fn rom_parts(plan: &FlashPlan, base: u32) -> Vec<Part> {
+    // Reassemble the image the metadata's pointers are into.
+    let end = plan
+        .writes
+        .iter()
+        .map(|w| w.address - base + w.data.len() as u32)
+        .max()
+        .unwrap_or(0);
+    let mut image = vec![ERASED; end.max(FIRMWARE_SIZE as u32) as usize];
+    for w in &plan.writes {
+        let at = (w.address - base) as usize;
+        image[at..at + w.data.len()].copy_from_slice(&w.data);
+    }
+
+    match Metadata::read(&image) {
+        Ok(Metadata::V1(sets)) => sets
+            .iter()
+            .enumerate()
+            .map(|(id, set)| {
+                let (address, size) = set.data(&image);
+                Part {
+                    address,
+                    size,
+                    name: format!("ROM set {id}"),
+                }
+            })
+            .collect(),
+        Ok(Metadata::V2(header)) => header
+            .rom_slots
+            .iter()
+            .enumerate()
+            .filter_map(|(id, slot)| {
+                let files: Vec<&str> = slot
+                    .roms
+                    .iter()
+                    .filter_map(|r| r.filename.as_deref())
+                    .collect();
+                let name = if files.is_empty() {
+                    format!("ROM slot {id}")
+                } else {
+                    format!("ROM slot {id} ({})", files.join(", "))
+                };
+                Some(Part {
+                    address: slot.data.addr()?,
+                    size: slot.size,
+                    name,
+                })
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Compare device flash with what `plan` writes.
+///
+/// `read_cb` is a JS `async (addr: number, len: number) => Uint8Array`
+/// returning exactly `len` bytes at `addr`, as for
+/// [`parse_firmware`](crate::parse_firmware).
+///
+/// `options` may be omitted, or an object with `block_size` (bytes per
+/// read, default 4096) and `max_mismatches` (default 64).
+#[wasm_bindgen]
+pub async fn verify_flash(
+    plan: &FlashPlan,
+    read_cb: js_sys::Function,
+    options: JsValue,
+) -> Result<FlashVerification, JsValue> {
+    let options: VerifyOptions = crate::options(options)?;
+    let block_size = options.block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
+    if block_size == 0 {
+        return Err(JsValue::from_str("block_size must be non-zero"));
+    }
+    let max_mismatches = options.max_mismatches.unwrap_or(DEFAULT_MAX_MISMATCHES);
+
+    let geometry = Geometry::of(plan.variant);
+    let parts = rom_parts(plan, geometry.base);
+    let reader = CallbackReader::new(Vec::new(), 0, read_cb);
+    let mut result = FlashVerification {
+        mcu: plan.variant.to_string(),
+        ok: true,
+        bytes_checked: 0,
+        blocks_read: 0,
+        mismatches: Vec::new(),
+        bad_sectors: Vec::new(),
+        truncated: false,
+    };
+
+    'writes: for w in &plan.writes {
+        for (i, expected) in w.data.chunks(block_size as usize).enumerate() {
+            let address = w.address + i as u32 * block_size;
+            let actual = reader
+                .fetch(address, expected.len() as u32)
+                .await
+                .map_err(|e| JsValue::from_str(&e))?;
+            if actual.len() != expected.len() {
+                return Err(JsValue::from_str(&format!(
+                    "Short read at {address:#010x}: got {}, need {}",
+                    actual.len(),
+                    expected.len()
+                )));
+            }
+            result.blocks_read += 1;
+            result.bytes_checked += expected.len() as u32;
+
+            for (start, len) in differing_runs(expected, &actual) {
+                let at = address + start as u32;
+                // A run carrying on from the previous block extends it.
+                let extends = result
+                    .mismatches
+                    .last()
+                    .is_some_and(|last| last.address + last.length == at);
+                if !extends && result.mismatches.len() == max_mismatches {
+                    result.truncated = true;
+                    break 'writes;
+                }
+                for sector in geometry.sectors_for(at, len as u32) {
+                    if !result.bad_sectors.contains(&sector.index) {
+                        result.bad_sectors.push(sector.index);
+                    }
+                }
+                if let Some(last) = result.mismatches.last_mut()
+                    && extends
+                {
+                    last.length += len as u32;
+                    continue;
+                }
+                let sector = geometry.sectors_for(at, 1).next().map_or(0, |s| s.index);
+                result.mismatches.push(FlashMismatch {
+                    address: at,
+                    length: len as u32,
+                    region: w.region.to_string(),
+                    detail: parts
+                        .iter()
+                        .find(|p| at >= p.address && at - p.address < p.size)
+                        .map(|p| p.name.clone()),
+                    sector,
+                    expected: expected[start],
+                    actual: actual[start],
+                });
+            }
+        }
+    }
+
+    result.ok = result.mismatches.is_empty();
+    Ok(result)
+}
+
+/// Runs of differing bytes between `a` and `b`, as `(offset, length)`.
+fn differing_runs(a: &[u8], b: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in a.iter().zip(b).enumerate().filter(|(_, (x, y))| x != y) {
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == i => *len += 1,
+            _ => runs.push((i, 1)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_differing_runs() {
+        let a = [0u8; 16];
+        assert!(differing_runs(&a, &a).is_empty());
+
+        let mut b = a;
+        b[0] = 1;
+        b[5..9].fill(2);
+        b[10] = 3;
+        b[15] = 4;
+        assert_eq!(differing_runs(&a, &b), [(0, 1), (5, 4), (10, 1), (15, 1)]);
+    }
+
+    #[test]
+    fn compares_common_length() {
+        let a = [0u8; 8];
+        let b = [0, 0, 1, 1, 0, 0, 0, 0, 1, 1];
+        assert_eq!(differing_runs(&a, &b), [(2, 2)]);
+        assert_eq!(differing_runs(&b[..3], &a), [(2, 1)]);
+    }
+}
@@ -0,0 +1,494 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! ROM slot editing of an existing image.
+//!
+//! Removes, reorders, replaces and appends ROM slots in a complete flash
+//! image, rewriting the metadata and repacking the ROM data behind it, so
+//! swapping one ROM does not mean reconstructing the whole config.
+//!
+//! New slot data is never laid out here. A replacement or appended slot is
+//! taken from images built by `gen_build` for the same board and firmware
+//! version, typically from a one-set config holding just the new ROM. A
+//! single ROM within a slot is replaced by having `onerom-gen` lay the slot
+//! out afresh around the new file, and taking just that ROM's entries. So
+//! `onerom-gen` remains the only thing that knows how to mangle ROM data
+//! for a board.
+//!
+//! Only firmware 0.7.0+ images can be edited. 0.6.x metadata has no
+//! serialiser to re-emit it with, so those images must be rebuilt from a
+//! config.
+
+use serde::Serialize;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::ChipType;
+use onerom_config::fw::{FirmwareProperties, FirmwareVersion, ServeAlg};
+use onerom_config::hw::Board;
+use onerom_config::mcu::{Family, Variant as McuVariant};
+use onerom_gen::{Builder as GenBuilder, FIRMWARE_SIZE, FileData};
+use onerom_metadata::{
+    DeviceMemoryView, METADATA_BASE, METADATA_SIZE, OneromMetadataHeader, OneromRomSlot, Pointer,
+    RomSlotType, Rp235xVariant,
+};
+
+use crate::WasmImages;
+use crate::image_overrides::{Metadata, WasmPatchedImage, write_v2_metadata};
+
+/// Where `gen_build` places ROM data: straight after the metadata.
+const ROM_DATA_BASE: u32 = METADATA_BASE + METADATA_SIZE as u32;
+
+/// A ROM slot, as listed by [`ImageEditor::slots`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct EditorSlot {
+    /// "system_plugin", "user_plugin", "pio_plugin", "single", "multi",
+    /// "banked" or "ram".
+    pub slot_type: String,
+    /// Size of the slot's data in the image, in bytes.
+    pub size: u32,
+    pub roms: Vec<EditorRom>,
+}
+
+/// A ROM within an [`EditorSlot`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct EditorRom {
+    pub rom_type: String,
+    pub filename: Option<String>,
+}
+
+/// An image being edited.
+///
+/// Created by [`image_editor`]. Edits apply in memory, and [`build`]
+/// produces the new image.
+///
+/// [`build`]: ImageEditor::build
+#[wasm_bindgen]
+pub struct ImageEditor {
+    /// Everything before the metadata: the firmware itself.
+    firmware: Vec<u8>,
+    header: OneromMetadataHeader,
+    /// Each slot's data, in slot order.
+    data: Vec<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl ImageEditor {
+    /// The image's slots, in order.
+    pub fn slots(&self) -> Vec<EditorSlot> {
+        self.header
+            .rom_slots
+            .iter()
+            .map(|slot| EditorSlot {
+                slot_type: slot_type_name(slot.slot_type).to_string(),
+                size: slot.size,
+                roms: slot
+                    .roms
+                    .iter()
+                    .map(|rom| EditorRom {
+                        rom_type: rom.rom_type.clone(),
+                        filename: rom.filename.clone(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Remove slot `index`.
+    pub fn remove_slot(&mut self, index: usize) -> Result<(), String> {
+        self.check_index(index)?;
+        self.header.rom_slots.remove(index);
+        self.data.remove(index);
+        Ok(())
+    }
+
+    /// Move slot `from` to position `to`, shifting the slots in between.
+    pub fn move_slot(&mut self, from: usize, to: usize) -> Result<(), String> {
+        self.check_index(from)?;
+        self.check_index(to)?;
+        let slot = self.header.rom_slots.remove(from);
+        self.header.rom_slots.insert(to, slot);
+        let data = self.data.remove(from);
+        self.data.insert(to, data);
+        Ok(())
+    }
+
+    /// Replace slot `index` with slot `source_index` of `source`.
+    ///
+    /// The new slot must hold the same number of ROMs, of the same types, as
+    /// the one it replaces; its filenames, CS settings and overrides come
+    /// from `source`.
+    pub fn replace_slot(
+        &mut self,
+        index: usize,
+        source: &WasmImages,
+        source_index: usize,
+    ) -> Result<(), String> {
+        self.check_index(index)?;
+        let (slot, data) = self.take_slot(source, source_index)?;
+
+        let old = &self.header.rom_slots[index];
+        let old_types = old.roms.iter().map(|r| &r.rom_type);
+        let new_types = slot.roms.iter().map(|r| &r.rom_type);
+        if old.slot_type != slot.slot_type || !old_types.eq(new_types) {
+            return Err(format!(
+                "Slot {index} holds {}, which cannot be replaced with {}",
+                describe(old),
+                describe(&slot)
+            ));
+        }
+
+        self.header.rom_slots[index] = slot;
+        self.data[index] = data;
+        Ok(())
+    }
+
+    /// Replace ROM `rom_index` of slot `index` with `data`, a ROM file.
+    ///
+    /// `chip_json` describes the new ROM as a chip in a config's
+    /// `chip_sets`, e.g. `{ "file": "kernal.bin", "type": "2364", "cs1":
+    /// "active_low" }`. Its type must have the same pinout as the ROM it
+    /// replaces, and its CS settings must be those the slot was built with:
+    /// the edit is refused if they would have the slot served differently.
+    /// `version` is the image's firmware version. The rest of the slot, and
+    /// its settings, are left as they are.
+    pub fn replace_rom(
+        &mut self,
+        index: usize,
+        rom_index: usize,
+        version: String,
+        chip_json: &str,
+        data: Vec<u8>,
+    ) -> Result<(), String> {
+        self.check_index(index)?;
+        let slot = &self.header.rom_slots[index];
+        let old = slot.roms.get(rom_index).ok_or_else(|| {
+            format!(
+                "Slot {index} has no ROM {rom_index}: it holds {} ROMs",
+                slot.roms.len()
+            )
+        })?;
+
+        let chip: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(chip_json).map_err(|e| format!("Invalid chip: {e}"))?;
+        let new_type = chip
+            .get("type")
+            .and_then(|t| t.as_str())
+            .ok_or("Chip has no type")?;
+        // The other ROMs are laid out from placeholder files, and each file
+        // matched back to its ROM by position, so the new file must differ.
+        let file = chip
+            .get("file")
+            .and_then(|f| f.as_str())
+            .ok_or("Chip has no file")?;
+        if (0..slot.roms.len()).any(|id| id != rom_index && file == placeholder(id)) {
+            return Err(format!("Chip file {file} is reserved; rename it"));
+        }
+        let new_type = ChipType::try_from_str(new_type)
+            .ok_or_else(|| format!("Unknown chip type: {new_type}"))?;
+        let old_type = ChipType::try_from_str(&old.rom_type)
+            .ok_or_else(|| format!("Slot {index} holds unknown chip type {}", old.rom_type))?;
+        if !same_pinout(old_type, new_type) {
+            return Err(format!(
+                "A {} cannot replace the {} in slot {index}: their pinouts differ",
+                new_type.name(),
+                old_type.name()
+            ));
+        }
+        let set_type = match slot.slot_type {
+            RomSlotType::RomSlotTypeSingleRom | RomSlotType::RomSlotTypeSingleRam => "single",
+            RomSlotType::RomSlotTypeMultiRom => "multi",
+            RomSlotType::RomSlotTypeBankedRom => "banked",
+            _ => return Err(format!("Slot {index} holds a plugin, not ROMs")),
+        };
+
+        let version = FirmwareVersion::try_from_str(&version)
+            .map_err(|_| "Invalid firmware version format".to_string())?;
+        let board = Board::try_from_str(&self.header.hw.hw_rev)
+            .ok_or_else(|| format!("Image is for unknown board {}", self.header.hw.hw_rev))?;
+
+        // The slot's other ROMs, of their own types, with the new ROM's CS
+        // settings. Their contents don't matter: their entries are kept.
+        let chips: Vec<serde_json::Value> = slot
+            .roms
+            .iter()
+            .enumerate()
+            .map(|(id, rom)| {
+                if id == rom_index {
+                    return serde_json::Value::Object(chip.clone());
+                }
+                let mut other: serde_json::Map<_, _> = chip
+                    .iter()
+                    .filter(|(key, _)| key.starts_with("cs"))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                other.insert("file".into(), placeholder(id).into());
+                other.insert("type".into(), rom.rom_type.clone().into());
+                serde_json::Value::Object(other)
+            })
+            .collect();
+        let config = serde_json::json!({
+            "version": 1,
+            "description": "ROM replacement",
+            "chip_sets": [{ "type": set_type, "chips": chips }],
+        })
+        .to_string();
+
+        // Lay the slot out with the new ROM, then with its position all set
+        // and all clear: the entries that differ between those two are the
+        // new ROM's.
+        let layout = |fill: Option<u8>| -> Result<(OneromRomSlot, Vec<u8>), String> {
+            let mut builder = GenBuilder::from_json(version, Family::Rp2350, &config)
+                .map_err(|e| format!("Error laying out slot {index}: {e:?}"))?;
+            let specs = builder.file_specs();
+            if specs.len() != chips.len() {
+                return Err(format!(
+                    "Slot {index} laid out with {} files for {} ROMs",
+                    specs.len(),
+                    chips.len()
+                ));
+            }
+            for (id, spec) in specs.into_iter().enumerate() {
+                let data = match fill {
+                    None if id == rom_index => data.clone(),
+                    Some(byte) if id == rom_index => vec![byte; new_type.size_bytes()],
+                    _ => vec![0; spec.chip_type.size_bytes()],
+                };
+                builder
+                    .add_file(FileData { id: spec.id, data })
+                    .map_err(|e| format!("Error laying out slot {index}: {e:?}"))?;
+            }
+            let props = FirmwareProperties::new(
+                version,
+                board,
+                McuVariant::RP2350,
+                ServeAlg::Default,
+                false,
+            )
+            .map_err(|e| format!("Error laying out slot {index}: {e:?}"))?;
+            let (metadata, roms) = builder
+                .build(props)
+                .map_err(|e| format!("Error laying out slot {index}: {e:?}"))?;
+            take(&metadata, &roms, 0)
+        };
+        let (new_slot, new_data) = layout(None)?;
+        if new_slot.alg != slot.alg {
+            return Err(format!(
+                "The chip's CS settings would change how slot {index} is served; give it the \
+                 settings the slot was built with"
+            ));
+        }
+        let (_, set) = layout(Some(0xFF))?;
+        let (_, clear) = layout(Some(0x00))?;
+
+        let data = &mut self.data[index];
+        if new_data.len() != data.len() {
+            return Err(format!(
+                "A {} lays out to {} bytes, but slot {index} is {} bytes",
+                new_type.name(),
+                new_data.len(),
+                data.len()
+            ));
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            if set[i] != clear[i] {
+                *byte = new_data[i];
+            }
+        }
+        self.header.rom_slots[index].roms[rom_index] = new_slot
+            .roms
+            .into_iter()
+            .nth(rom_index)
+            .ok_or("Laid out slot is missing the new ROM")?;
+        Ok(())
+    }
+
+    /// Append slot `source_index` of `source` after the existing slots.
+    pub fn append_slot(&mut self, source: &WasmImages, source_index: usize) -> Result<(), String> {
+        let (slot, data) = self.take_slot(source, source_index)?;
+        self.header.rom_slots.push(slot);
+        self.data.push(data);
+        Ok(())
+    }
+
+    /// Produce the edited image: the original firmware, rewritten metadata,
+    /// then every slot's data packed in slot order.
+    pub fn build(&self) -> Result<WasmPatchedImage, String> {
+        check_plugin_slots(&self.header.rom_slots)?;
+
+        let mut header = self.header.clone();
+        let mut addr = ROM_DATA_BASE;
+        for (slot, data) in header.rom_slots.iter_mut().zip(&self.data) {
+            if !slot.data.is_null() {
+                slot.data = Pointer::Addr32(addr);
+                addr += data.len() as u32;
+            }
+        }
+
+        let mut image = self.firmware.clone();
+        image.extend_from_slice(&write_v2_metadata(&header)?);
+        for data in &self.data {
+            image.extend_from_slice(data);
+        }
+
+        let mcu = match self.header.hw.rp235x {
+            Rp235xVariant::Rp235xa => McuVariant::RP2350,
+            Rp235xVariant::Rp235xb => McuVariant::RP2350B,
+        };
+        if image.len() > mcu.flash_storage_bytes() {
+            return Err(format!(
+                "Image is {} bytes, more than the {} bytes of {mcu} flash",
+                image.len(),
+                mcu.flash_storage_bytes()
+            ));
+        }
+        Ok(WasmPatchedImage::new(image))
+    }
+}
+
+impl ImageEditor {
+    fn check_index(&self, index: usize) -> Result<(), String> {
+        let count = self.header.rom_slots.len();
+        if index >= count {
+            return Err(format!(
+                "Slot {index} out of range: image has {count} slots"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Copy slot `index` and its data out of images built by `gen_build`.
+    fn take_slot(
+        &self,
+        source: &WasmImages,
+        index: usize,
+    ) -> Result<(OneromRomSlot, Vec<u8>), String> {
+        let view = DeviceMemoryView::new(&source.0, METADATA_BASE);
+        let header = OneromMetadataHeader::parse(&view, METADATA_BASE)
+            .map_err(|e| format!("Error parsing source metadata: {e:?}"))?;
+        if header.hw != self.header.hw {
+            return Err("Source images were built for a different board".to_string());
+        }
+        // The metadata version is what the firmware reads the slot with.
+        if header.version != self.header.version {
+            return Err(format!(
+                "Source images have metadata version {}, but the image has {}; build them \
+                 for the image's firmware version",
+                header.version, self.header.version
+            ));
+        }
+        take(&source.0, &source.1, index)
+    }
+}
+
+/// Slot `index` and its data, from `gen_build`'s metadata and ROM images.
+fn take(metadata: &[u8], roms: &[u8], index: usize) -> Result<(OneromRomSlot, Vec<u8>), String> {
+    let view = DeviceMemoryView::new(metadata, METADATA_BASE);
+    let header = OneromMetadataHeader::parse(&view, METADATA_BASE)
+        .map_err(|e| format!("Error parsing source metadata: {e:?}"))?;
+    let slot = header
+        .rom_slots
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| format!("Source images have no slot {index}"))?;
+    let data = slot_data(roms, ROM_DATA_BASE, &slot)
+        .ok_or_else(|| format!("Source slot {index} data is outside the images"))?;
+    Ok((slot, data.to_vec()))
+}
+
+/// Name of the file standing in for ROM `id` of a slot being laid out.
+fn placeholder(id: usize) -> String {
+    format!("rom{id}.bin")
+}
+
+/// Whether a chip of type `b` can stand in for one of type `a` in the same
+/// socket: the same pins, doing the same things.
+fn same_pinout(a: ChipType, b: ChipType) -> bool {
+    a.chip_pins() == b.chip_pins()
+        && a.address_pins() == b.address_pins()
+        && a.data_pins() == b.data_pins()
+        && a.control_lines() == b.control_lines()
+}
+
+/// Open a firmware 0.7.0+ image for slot editing.
+#[wasm_bindgen]
+pub fn image_editor(image: &[u8]) -> Result<ImageEditor, String> {
+    let header = match Metadata::read(image)? {
+        Metadata::V1(_) => {
+            return Err(
+                "Firmware 0.6.x images cannot be edited; rebuild from a config instead".to_string(),
+            );
+        }
+        Metadata::V2(header) => *header,
+    };
+
+    let flash_base = METADATA_BASE - FIRMWARE_SIZE as u32;
+    let data = header
+        .rom_slots
+        .iter()
+        .enumerate()
+        .map(|(id, slot)| {
+            slot_data(image, flash_base, slot)
+                .map(<[u8]>::to_vec)
+                .ok_or_else(|| format!("Slot {id} data is outside the image"))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(ImageEditor {
+        firmware: image[..FIRMWARE_SIZE].to_vec(),
+        header,
+        data,
+    })
+}
+
+/// A slot's data within `buf`, which starts at address `base`. Slots with
+/// no data (RAM) have an empty slice.
+fn slot_data<'a>(buf: &'a [u8], base: u32, slot: &OneromRomSlot) -> Option<&'a [u8]> {
+    let Some(addr) = slot.data.addr() else {
+        return Some(&[]);
+    };
+    let start = addr.checked_sub(base)? as usize;
+    buf.get(start..start + slot.size as usize)
+}
+
+/// The firmware only looks for a system plugin in the first slot, and a user
+/// plugin in the second, after a system plugin.
+fn check_plugin_slots(slots: &[OneromRomSlot]) -> Result<(), String> {
+    for (id, slot) in slots.iter().enumerate() {
+        match slot.slot_type {
+            RomSlotType::RomSlotTypePluginSystem if id != 0 => {
+                return Err("System plugins must be in the first slot".to_string());
+            }
+            RomSlotType::RomSlotTypePluginUser
+                if id != 1 || slots[0].slot_type != RomSlotType::RomSlotTypePluginSystem =>
+            {
+                return Err(
+                    "User plugins must be in the second slot, after a system plugin".to_string(),
+                );
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn slot_type_name(slot_type: RomSlotType) -> &'static str {
+    match slot_type {
+        RomSlotType::RomSlotTypePluginSystem => "system_plugin",
+        RomSlotType::RomSlotTypePluginUser => "user_plugin",
+        RomSlotType::RomSlotTypePluginPio => "pio_plugin",
+        RomSlotType::RomSlotTypeSingleRom => "single",
+        RomSlotType::RomSlotTypeMultiRom => "multi",
+        RomSlotType::RomSlotTypeBankedRom => "banked",
+        RomSlotType::RomSlotTypeSingleRam => "ram",
+    }
+}
+
+/// "single 2364" or "multi 2364, 2364" style description of a slot.
+fn describe(slot: &OneromRomSlot) -> String {
+    let types: Vec<&str> = slot.roms.iter().map(|r| r.rom_type.as_str()).collect();
+    format!("{} {}", slot_type_name(slot.slot_type), types.join(", "))
+}
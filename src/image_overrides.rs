@@ -0,0 +1,577 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Firmware overrides in an existing image.
+//!
+//! Reads the per-set firmware overrides (and, for 0.7.0+ images, the global
+//! settings) out of a complete flash image, and writes edited ones back
+//! without rebuilding from the config.
+//!
+//! The two metadata formats are handled differently:
+//!
+//! - Metadata v1 (firmware 0.6.x) is edited in place. A set can only be given
+//!   overrides if it was built with an override block, as there is nowhere to
+//!   put a new one; removing a set's overrides just nulls its pointer.
+//! - Metadata v2 (firmware 0.7.0+) is parsed with `onerom-metadata`, edited
+//!   and re-serialised, so overrides can be added and removed freely.
+//!
+//! Overrides use the same JSON shape as a config's `firmware_overrides`.
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use onerom_config::mcu::RP235X_BASE_FLASH;
+use onerom_gen::{
+    DebugConfig, FIRMWARE_SIZE, FireConfig, FireCpuFreq, FireServeMode, FireVreg, FirmwareConfig,
+    LedConfig, ServeAlgParams,
+};
+use onerom_metadata::{
+    DeviceMemoryView, FireVreg as MetaFireVreg, MAX_SERIAL_NUMBER_LEN, MAX_UNIT_NAME_LEN,
+    METADATA_BASE, METADATA_SIZE, OneromFirmwareOverrides, OneromMetadataHeader,
+};
+
+use crate::digest;
+
+const METADATA_MAGIC: &[u8; 16] = b"ONEROM_METADATA\0";
+
+// Metadata v1 layout: onerom_metadata_header_t, then sdrr_rom_set_t entries.
+const V1_SET_COUNT: usize = 20;
+const V1_SETS_PTR: usize = 24;
+const V1_SET_LEN: usize = 16;
+const V1_SET_EXTRA_LEN: usize = 48;
+const V1_SET_EXTRA_INFO: usize = 15;
+const V1_SET_SERVE_CONFIG_PTR: usize = 16;
+const V1_SET_OVERRIDES_PTR: usize = 20;
+const V1_BLOCK_LEN: usize = 64;
+const PAD_BYTE: u8 = 0xFF;
+
+// Bits in override_present[0] / override_value[0], shared by both formats.
+const PRESENT_ICE_FREQ: u8 = 1 << 0;
+const PRESENT_ICE_OVERCLOCK: u8 = 1 << 1;
+const PRESENT_FIRE_FREQ: u8 = 1 << 2;
+const PRESENT_FIRE_OVERCLOCK: u8 = 1 << 3;
+const PRESENT_FIRE_VREG: u8 = 1 << 4;
+const PRESENT_LED: u8 = 1 << 5;
+const PRESENT_SWD: u8 = 1 << 6;
+const PRESENT_FIRE_SERVE_MODE: u8 = 1 << 7;
+const VALUE_ICE_OVERCLOCK: u8 = 1 << 0;
+const VALUE_FIRE_OVERCLOCK: u8 = 1 << 1;
+const VALUE_LED: u8 = 1 << 2;
+const VALUE_SWD: u8 = 1 << 3;
+const VALUE_FIRE_PIO: u8 = 1 << 4;
+const VALUE_FIRE_DMA_PRELOAD: u8 = 1 << 5;
+const VALUE_FIRE_16_BIT: u8 = 1 << 6;
+// Bits in override_present[1], v1 only.
+const PRESENT_FIRE_DMA_PRELOAD: u8 = 1 << 0;
+const PRESENT_FIRE_16_BIT: u8 = 1 << 1;
+
+/// An image's editable overrides.
+///
+/// `sets` has one entry per ROM set (or slot), `null` where the set has no
+/// overrides. The global fields are only present for metadata v2 images;
+/// when patching, a `null` boolean leaves the setting as it is, while a
+/// `null` name or serial clears it.
+#[derive(Serialize, Deserialize)]
+pub struct ImageOverrides {
+    /// Metadata format version: 1 for firmware 0.6.x, 2 for 0.7.0+.
+    pub metadata_version: u32,
+    pub sets: Vec<Option<FirmwareConfig>>,
+    #[serde(default)]
+    pub boot_logging: Option<bool>,
+    #[serde(default)]
+    pub swd_enabled: Option<bool>,
+    #[serde(default)]
+    pub turbo_boot: Option<bool>,
+    #[serde(default)]
+    pub instance_name: Option<String>,
+    #[serde(default)]
+    pub serial_override: Option<String>,
+}
+
+/// A patched image, with its digests for verifying what gets flashed.
+///
+/// The image format carries no checksums of its own, so nothing inside the
+/// image needs recomputing; these cover the whole patched image.
+#[wasm_bindgen]
+pub struct WasmPatchedImage {
+    image: Vec<u8>,
+    sha256: String,
+    crc32: String,
+}
+
+#[wasm_bindgen]
+impl WasmPatchedImage {
+    #[wasm_bindgen(getter)]
+    pub fn image(&self) -> Vec<u8> {
+        self.image.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn sha256(&self) -> String {
+        self.sha256.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn crc32(&self) -> String {
+        self.crc32.clone()
+    }
+}
+
+impl WasmPatchedImage {
+    pub(crate) fn new(image: Vec<u8>) -> Self {
+        Self {
+            sha256: digest::sha256_hex(&image),
+            crc32: digest::crc32_hex(digest::crc32(&image)),
+            image,
+        }
+    }
+}
+
+/// Return the overrides in a complete flash image, as [`ImageOverrides`].
+#[wasm_bindgen]
+pub fn image_overrides(image: &[u8]) -> Result<JsValue, String> {
+    let overrides = match Metadata::read(image)? {
+        Metadata::V1(sets) => ImageOverrides {
+            metadata_version: 1,
+            sets: sets
+                .iter()
+                .map(|set| set.overrides(image))
+                .collect::<Result<_, _>>()?,
+            boot_logging: None,
+            swd_enabled: None,
+            turbo_boot: None,
+            instance_name: None,
+            serial_override: None,
+        },
+        Metadata::V2(header) => ImageOverrides {
+            metadata_version: 2,
+            sets: header
+                .rom_slots
+                .iter()
+                .map(|slot| slot.firmware_overrides.as_ref().map(from_v2).transpose())
+                .collect::<Result<_, _>>()?,
+            boot_logging: Some(header.boot_logging != 0),
+            swd_enabled: Some(header.swd_enabled != 0),
+            turbo_boot: Some(header.turbo_boot != 0),
+            instance_name: header.fw.name.clone(),
+            serial_override: header.fw.serial_number.clone(),
+        },
+    };
+    serde_wasm_bindgen::to_value(&overrides).map_err(|e| e.to_string())
+}
+
+/// Write edited overrides, in the shape [`image_overrides`] returns, into a
+/// copy of `image`.
+#[wasm_bindgen]
+pub fn patch_image_overrides(
+    image: Vec<u8>,
+    overrides: JsValue,
+) -> Result<WasmPatchedImage, String> {
+    let overrides: ImageOverrides =
+        serde_wasm_bindgen::from_value(overrides).map_err(|e| format!("Invalid overrides: {e}"))?;
+    let mut image = image;
+
+    match Metadata::read(&image)? {
+        Metadata::V1(sets) => {
+            if overrides.boot_logging.is_some()
+                || overrides.swd_enabled.is_some()
+                || overrides.turbo_boot.is_some()
+                || overrides.instance_name.is_some()
+                || overrides.serial_override.is_some()
+            {
+                return Err("Global settings need a firmware 0.7.0 or later image".to_string());
+            }
+            check_set_count(sets.len(), overrides.sets.len())?;
+            for (id, (set, config)) in sets.iter().zip(&overrides.sets).enumerate() {
+                set.write(&mut image, config.as_ref())
+                    .map_err(|e| format!("Set {id}: {e}"))?;
+            }
+        }
+        Metadata::V2(mut header) => {
+            check_set_count(header.rom_slots.len(), overrides.sets.len())?;
+            for (id, (slot, config)) in header.rom_slots.iter_mut().zip(&overrides.sets).enumerate()
+            {
+                slot.firmware_overrides = config
+                    .as_ref()
+                    .map(to_v2)
+                    .transpose()
+                    .map_err(|e| format!("Slot {id}: {e}"))?;
+            }
+            patch_v2_globals(&mut header, &overrides)?;
+
+            let metadata = write_v2_metadata(&header)?;
+            image[FIRMWARE_SIZE..FIRMWARE_SIZE + METADATA_SIZE].copy_from_slice(&metadata);
+        }
+    }
+
+    Ok(WasmPatchedImage::new(image))
+}
+
+/// Serialise v2 metadata into its 16KB region, which follows the firmware.
+pub(crate) fn write_v2_metadata(header: &OneromMetadataHeader) -> Result<Vec<u8>, String> {
+    let mut metadata = vec![0u8; METADATA_SIZE];
+    onerom_metadata::serialize(header, METADATA_BASE, &mut metadata)
+        .map_err(|e| format!("Error writing metadata: {e:?}"))?;
+    Ok(metadata)
+}
+
+fn check_set_count(actual: usize, given: usize) -> Result<(), String> {
+    if actual != given {
+        return Err(format!("Image has {actual} sets, but {given} were given"));
+    }
+    Ok(())
+}
+
+pub(crate) enum Metadata {
+    V1(Vec<V1Set>),
+    V2(Box<OneromMetadataHeader>),
+}
+
+impl Metadata {
+    pub(crate) fn read(image: &[u8]) -> Result<Self, String> {
+        let header = Self::header(image)?;
+
+        match read_u32(header, 16) {
+            1 => {
+                let count = header[V1_SET_COUNT] as usize;
+                let ptr = read_u32(header, V1_SETS_PTR);
+                let base = Self::flash_base(image)?;
+                let mut offset = resolve(image, base, ptr, 0).ok_or("Invalid ROM set pointer")?;
+                let mut sets = Vec::with_capacity(count);
+                for id in 0..count {
+                    let set = V1Set::read(image, base, offset)
+                        .ok_or_else(|| format!("ROM set {id} is outside the image"))?;
+                    offset += if set.extra_info {
+                        V1_SET_LEN + V1_SET_EXTRA_LEN
+                    } else {
+                        V1_SET_LEN
+                    };
+                    sets.push(set);
+                }
+                Ok(Metadata::V1(sets))
+            }
+            2 => {
+                // v2 metadata fills its region, and is written back whole.
+                if image.len() < FIRMWARE_SIZE + METADATA_SIZE {
+                    return Err(format!(
+                        "Image is {} bytes, too short for its metadata ({} bytes needed)",
+                        image.len(),
+                        FIRMWARE_SIZE + METADATA_SIZE
+                    ));
+                }
+                let view = DeviceMemoryView::new(image, RP235X_BASE_FLASH);
+                let header = OneromMetadataHeader::parse(&view, METADATA_BASE)
+                    .map_err(|e| format!("Error parsing metadata: {e:?}"))?;
+                Ok(Metadata::V2(Box::new(header)))
+            }
+            version => Err(format!("Unsupported metadata version {version}")),
+        }
+    }
+
+    /// The flash base an image's metadata pointers are into.
+    pub(crate) fn flash_base(image: &[u8]) -> Result<u32, String> {
+        let header = Self::header(image)?;
+        Ok(match read_u32(header, 16) {
+            // v1 pointers are absolute; the flash base is in their top byte
+            // (0x08 for STM32, 0x10 for RP2350).
+            1 => read_u32(header, V1_SETS_PTR) & 0xFF00_0000,
+            _ => RP235X_BASE_FLASH,
+        })
+    }
+
+    /// The start of the metadata header, checked for its magic.
+    fn header(image: &[u8]) -> Result<&[u8], String> {
+        image
+            .get(FIRMWARE_SIZE..FIRMWARE_SIZE + 32)
+            .filter(|h| h.starts_with(METADATA_MAGIC))
+            .ok_or_else(|| "Image has no One ROM metadata".to_string())
+    }
+}
+
+/// A v1 ROM set header, as image offsets.
+pub(crate) struct V1Set {
+    header: usize,
+    extra_info: bool,
+    overrides: Option<usize>,
+    serve_config: Option<usize>,
+}
+
+impl V1Set {
+    fn read(image: &[u8], base: u32, header: usize) -> Option<Self> {
+        let bytes = image.get(header..header + V1_SET_LEN)?;
+        let extra_info = bytes[V1_SET_EXTRA_INFO] == 1;
+        let (overrides, serve_config) = if extra_info {
+            let extra = image.get(header..header + V1_SET_LEN + V1_SET_EXTRA_LEN)?;
+            let ptr = |at| resolve(image, base, read_u32(extra, at), V1_BLOCK_LEN);
+            (ptr(V1_SET_OVERRIDES_PTR), ptr(V1_SET_SERVE_CONFIG_PTR))
+        } else {
+            (None, None)
+        };
+        Some(Self {
+            header,
+            extra_info,
+            overrides,
+            serve_config,
+        })
+    }
+
+    /// The set's ROM data, as `(address, size)`.
+    pub(crate) fn data(&self, image: &[u8]) -> (u32, u32) {
+        (
+            read_u32(image, self.header),
+            read_u32(image, self.header + 4),
+        )
+    }
+
+    fn overrides(&self, image: &[u8]) -> Result<Option<FirmwareConfig>, String> {
+        let Some(offset) = self.overrides else {
+            return Ok(None);
+        };
+        let mut config = FirmwareConfig::from_bytes(&image[offset..offset + V1_BLOCK_LEN])?;
+        config.serve_alg_params = self.serve_config.map(|offset| ServeAlgParams {
+            params: image[offset..offset + V1_BLOCK_LEN].to_vec(),
+        });
+        Ok(Some(config))
+    }
+
+    fn write(&self, image: &mut [u8], config: Option<&FirmwareConfig>) -> Result<(), String> {
+        let Some(config) = config else {
+            if self.overrides.is_some() {
+                let at = self.header + V1_SET_OVERRIDES_PTR;
+                image[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            }
+            return Ok(());
+        };
+
+        let offset = self
+            .overrides
+            .ok_or("built without overrides, so they can only be added by rebuilding")?;
+        image[offset..offset + 24].copy_from_slice(&to_v1(config));
+        image[offset + 24..offset + V1_BLOCK_LEN].fill(PAD_BYTE);
+
+        if let Some(params) = &config.serve_alg_params {
+            let offset = self.serve_config.ok_or(
+                "built without serve algorithm parameters, so they can only be added by rebuilding",
+            )?;
+            let len = params.params.len().min(V1_BLOCK_LEN);
+            image[offset..offset + len].copy_from_slice(&params.params[..len]);
+            image[offset + len..offset + V1_BLOCK_LEN].fill(PAD_BYTE);
+        }
+        Ok(())
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+}
+
+/// Image offset of `ptr`, if it is non-null and `len` bytes from it are in
+/// the image.
+fn resolve(image: &[u8], base: u32, ptr: u32, len: usize) -> Option<usize> {
+    if ptr == 0 || ptr == u32::MAX {
+        return None;
+    }
+    let offset = ptr.checked_sub(base)? as usize;
+    (offset + len <= image.len()).then_some(offset)
+}
+
+/// Encode overrides as the 24-byte core of a v1 `onerom_firmware_overrides_t`.
+fn to_v1(config: &FirmwareConfig) -> [u8; 24] {
+    let mut present = [0u8; 8];
+    let mut value = [0u8; 8];
+    let mut ice_freq = u16::MAX;
+    let mut fire_freq = u16::MAX;
+    let mut fire_vreg = PAD_BYTE;
+
+    if let Some(ice) = &config.ice {
+        if let Some(freq) = ice.cpu_freq {
+            present[0] |= PRESENT_ICE_FREQ;
+            ice_freq = freq.get();
+        }
+        if let Some(overclock) = ice.overclock {
+            present[0] |= PRESENT_ICE_OVERCLOCK;
+            if overclock {
+                value[0] |= VALUE_ICE_OVERCLOCK;
+            }
+        }
+    }
+    if let Some(fire) = &config.fire {
+        if let Some(freq) = fire.cpu_freq {
+            present[0] |= PRESENT_FIRE_FREQ;
+            fire_freq = freq.get();
+        }
+        if let Some(overclock) = fire.overclock {
+            present[0] |= PRESENT_FIRE_OVERCLOCK;
+            if overclock {
+                value[0] |= VALUE_FIRE_OVERCLOCK;
+            }
+        }
+        if let Some(vreg) = &fire.vreg {
+            present[0] |= PRESENT_FIRE_VREG;
+            fire_vreg = vreg.clone() as u8;
+        }
+        if let Some(mode) = &fire.serve_mode {
+            present[0] |= PRESENT_FIRE_SERVE_MODE;
+            if *mode == FireServeMode::Pio {
+                value[0] |= VALUE_FIRE_PIO;
+            }
+        }
+        // The firmware always takes DMA preload from a Fire override.
+        present[1] |= PRESENT_FIRE_DMA_PRELOAD;
+        if fire.rom_dma_preload {
+            value[0] |= VALUE_FIRE_DMA_PRELOAD;
+        }
+        if fire.force_16_bit {
+            present[1] |= PRESENT_FIRE_16_BIT;
+            value[0] |= VALUE_FIRE_16_BIT;
+        }
+    }
+    if let Some(led) = &config.led {
+        present[0] |= PRESENT_LED;
+        if led.enabled {
+            value[0] |= VALUE_LED;
+        }
+    }
+    if let Some(swd) = &config.swd {
+        present[0] |= PRESENT_SWD;
+        if swd.swd_enabled {
+            value[0] |= VALUE_SWD;
+        }
+    }
+
+    let mut out = [PAD_BYTE; 24];
+    out[..8].copy_from_slice(&present);
+    out[8..10].copy_from_slice(&ice_freq.to_le_bytes());
+    out[10..12].copy_from_slice(&fire_freq.to_le_bytes());
+    out[12] = fire_vreg;
+    out[16..].copy_from_slice(&value);
+    out
+}
+
+/// Convert overrides to v2 form, rejecting what v2 firmware does not support.
+fn to_v2(config: &FirmwareConfig) -> Result<OneromFirmwareOverrides, String> {
+    if config.ice.is_some() {
+        return Err("Ice overrides are not supported by firmware 0.7.0+".to_string());
+    }
+    if config.serve_alg_params.is_some() {
+        return Err("Serve algorithm parameters are not supported by firmware 0.7.0+".to_string());
+    }
+
+    let mut present = [0u8; 8];
+    let mut value = [0u8; 8];
+    let mut fire_freq = onerom_metadata::FIRE_FREQ_NONE;
+    let mut fire_vreg = MetaFireVreg::FireVregNone;
+
+    if let Some(fire) = &config.fire {
+        if fire.serve_mode.is_some() {
+            return Err("Firmware 0.7.0+ serves with PIO only".to_string());
+        }
+        if !fire.rom_dma_preload {
+            return Err(
+                "Disabling ROM DMA preload is not supported by firmware 0.7.0+".to_string(),
+            );
+        }
+        if fire.force_16_bit {
+            return Err("16-bit mode changes the ROM layout, so needs a rebuild".to_string());
+        }
+        if let Some(freq) = fire.cpu_freq {
+            present[0] |= PRESENT_FIRE_FREQ;
+            fire_freq = freq.get();
+        }
+        if let Some(overclock) = fire.overclock {
+            present[0] |= PRESENT_FIRE_OVERCLOCK;
+            if overclock {
+                value[0] |= VALUE_FIRE_OVERCLOCK;
+            }
+        }
+        if let Some(vreg) = &fire.vreg {
+            present[0] |= PRESENT_FIRE_VREG;
+            fire_vreg = MetaFireVreg::try_from(vreg.clone() as u8)
+                .map_err(|_| format!("Unsupported VREG setting {vreg:?}"))?;
+        }
+    }
+    if let Some(led) = &config.led {
+        present[0] |= PRESENT_LED;
+        if led.enabled {
+            value[0] |= VALUE_LED;
+        }
+    }
+    if let Some(swd) = &config.swd {
+        present[0] |= PRESENT_SWD;
+        if swd.swd_enabled {
+            value[0] |= VALUE_SWD;
+        }
+    }
+
+    Ok(OneromFirmwareOverrides {
+        override_present: present,
+        ice_freq: 0,
+        fire_freq,
+        fire_vreg,
+        override_value: value,
+    })
+}
+
+fn from_v2(o: &OneromFirmwareOverrides) -> Result<FirmwareConfig, String> {
+    let cpu_freq = o
+        .cpu_freq()
+        .map(|f| FireCpuFreq::try_from(f).map_err(|_| format!("Invalid CPU frequency {f}")))
+        .transpose()?;
+    let vreg = o
+        .vreg()
+        .map(|v| FireVreg::try_from(v as u8).map_err(|_| format!("Invalid VREG setting {v:?}")))
+        .transpose()?;
+    let overclock = o.overclock_enabled();
+
+    let fire = (cpu_freq.is_some() || vreg.is_some() || overclock.is_some()).then(|| FireConfig {
+        cpu_freq,
+        overclock,
+        vreg,
+        ..Default::default()
+    });
+
+    Ok(FirmwareConfig {
+        ice: None,
+        fire,
+        led: o.led_enabled().map(|enabled| LedConfig { enabled }),
+        swd: o
+            .swd_enabled()
+            .map(|swd_enabled| DebugConfig { swd_enabled }),
+        serve_alg_params: None,
+    })
+}
+
+fn patch_v2_globals(
+    header: &mut OneromMetadataHeader,
+    overrides: &ImageOverrides,
+) -> Result<(), String> {
+    for (name, value, max) in [
+        ("Instance name", &overrides.instance_name, MAX_UNIT_NAME_LEN),
+        (
+            "Serial override",
+            &overrides.serial_override,
+            MAX_SERIAL_NUMBER_LEN,
+        ),
+    ] {
+        if value.as_ref().is_some_and(|v| v.len() > max) {
+            return Err(format!("{name} is longer than {max} bytes"));
+        }
+    }
+    header.fw.name = overrides.instance_name.clone();
+    header.fw.serial_number = overrides.serial_override.clone();
+
+    if let Some(v) = overrides.boot_logging {
+        header.boot_logging = v as u8;
+    }
+    if let Some(v) = overrides.swd_enabled {
+        header.swd_enabled = v as u8;
+    }
+    if let Some(v) = overrides.turbo_boot {
+        header.turbo_boot = v as u8;
+    }
+    if header.boot_logging != 0 && header.swd_enabled == 0 {
+        return Err("Boot logging cannot be enabled when SWD is disabled".to_string());
+    }
+    Ok(())
+}
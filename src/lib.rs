@@ -14,6 +14,79 @@ use onerom_fw_parser::{
 };
 use onerom_gen::{Builder as GenBuilder, FileData};
 
+mod airfrog;
+#[cfg(test)]
+mod airfrog_mock;
+mod board_matrix;
+mod board_pins;
+mod chip_compat;
+mod chip_query;
+mod config_ext;
+mod cs_guide;
+mod custom_chips;
+mod dfu;
+#[cfg(test)]
+mod dfu_sim;
+mod digest;
+mod firmware_catalog;
+mod flash_delta;
+mod flash_plan;
+mod flash_verify;
+mod image_editor;
+mod image_overrides;
+mod matrix;
+mod mcu_detect;
+mod migrate;
+mod patch;
+mod picoboot;
+#[cfg(test)]
+mod picoboot_sim;
+mod pinout_svg;
+mod plugin_snapshot;
+mod rom_db;
+#[cfg(test)]
+mod test_util;
+mod transform;
+
+pub use airfrog::{AirfrogReport, airfrog_flash, airfrog_parse_firmware, airfrog_rpc_request};
+pub use board_matrix::{
+    BoardCapabilities, FirmwareSupport, board_matrix, board_matrix_csv, board_matrix_json,
+};
+pub use board_pins::{BoardPinMapping, PinMapping, board_pin_map};
+pub use chip_compat::{AdapterWire, ChipCompatibility, PinComparison, chip_compatibility};
+pub use chip_query::query_chip_types;
+use config_ext::ConfigExt;
+pub use cs_guide::{
+    ChipSelectGuide, ControlLineGuide, CsGuide, SelJumper, XPinGuide, cs_jumper_guide,
+    explain_cs_jumpers,
+};
+pub use custom_chips::{
+    CustomChipTypeInfo, clear_custom_chip_types, custom_chip_types, load_custom_chip_types,
+};
+pub use dfu::{DfuReport, dfu_flash};
+pub use firmware_catalog::{
+    FirmwareBuild, FirmwareCatalog, FirmwareRelease, WasmFirmwareRelease, firmware_catalog,
+};
+pub use flash_delta::{incremental_flash_plan, incremental_flash_plan_by_hash};
+pub use flash_plan::{
+    FlashLayout, FlashPlan, FlashSector, FlashWrite, flash_plan, mcu_flash_layout,
+};
+pub use flash_verify::{FlashMismatch, FlashVerification, verify_flash};
+pub use image_editor::{EditorRom, EditorSlot, ImageEditor, image_editor};
+pub use image_overrides::{
+    ImageOverrides, WasmPatchedImage, image_overrides, patch_image_overrides,
+};
+use matrix::GenInputs;
+pub use matrix::{WasmTargetBuild, gen_build_matrix};
+pub use mcu_detect::{McuDetection, detect_mcu};
+pub use migrate::{ConfigMigration, migrate_config};
+pub use picoboot::{PicobootReport, picoboot_flash};
+pub use pinout_svg::chip_pinout_svg;
+pub use plugin_snapshot::{plugin_catalog_from_snapshot, revalidate_plugin_catalog};
+pub use rom_db::{
+    KnownRom, autofill_config, identify_rom, known_rom_by_digest, known_roms, load_known_roms,
+};
+
 /// Initialize logging and panic hook
 #[wasm_bindgen(start)]
 pub fn init() {
@@ -129,6 +202,13 @@ pub struct RomSummary {
     pub active: bool,
     /// User-facing ROM number (plugins excluded); `None` for plugins.
     pub index: Option<usize>,
+    /// Name of the ROM from the known-ROM database, where the recorded filename
+    /// carries a known part number. Always `None` for plugins.
+    ///
+    /// This matches on the filename only, not the ROM's contents, so a renamed
+    /// or modified image can be misnamed. Use [`identify_rom`] on the image
+    /// itself to identify it by digest.
+    pub known_by_name: Option<String>,
 }
 
 /// Number of bytes fetched per RAM cache miss.
@@ -293,10 +373,15 @@ fn device_summary(dev: &ParsedDevice) -> Result<DeviceSummary, String> {
                 (Some(f), SlotKind::Plugin) => f.to_string(),
                 (None, _) => rom.rom_type.into_owned(),
             };
+            let known_by_name = match (rom.filename, kind) {
+                (Some(f), SlotKind::Rom) => rom_db::identify_filename(f).map(|r| r.name),
+                _ => None,
+            };
             let entry = RomSummary {
                 label,
                 active,
                 index,
+                known_by_name,
             };
             match kind {
                 SlotKind::Plugin => plugins.push(entry),
@@ -369,6 +454,24 @@ pub async fn parse_all(flash_data: Vec<u8>, rom_data: Vec<u8>) -> Result<JsValue
     serde_wasm_bindgen::to_value(&info).map_err(|e| JsValue::from_str(&e.to_string()))
 }
 
+/// Deserialize an optional JS options object, falling back to `T::default()`
+/// when it is `undefined` or `null`.
+pub(crate) fn options<T: serde::de::DeserializeOwned + Default>(
+    value: JsValue,
+) -> Result<T, JsValue> {
+    if value.is_undefined() || value.is_null() {
+        Ok(T::default())
+    } else {
+        serde_wasm_bindgen::from_value(value)
+            .map_err(|e| JsValue::from_str(&format!("Invalid options: {e}")))
+    }
+}
+
+/// The config file name for a chip select level.
+pub(crate) fn level(high: bool) -> &'static str {
+    if high { "active_high" } else { "active_low" }
+}
+
 // MCU
 
 /// Basic MCU information structure
@@ -440,6 +543,53 @@ pub struct ChipTypeInfo {
     power_pins: Vec<PowerPin>,
 }
 
+impl ChipTypeInfo {
+    /// Every signal on chip pin `pin`, in upper case ("A3", "D0", "CS1",
+    /// "VPP", "VCC"), in the order address, data, control, programming,
+    /// power. Empty for an unconnected pin.
+    pub(crate) fn pin_signals(&self, pin: u8) -> Vec<String> {
+        let address = self
+            .address_pins
+            .iter()
+            .filter(|p| p.pin == pin)
+            .map(|p| format!("A{}", p.line));
+        let data = self
+            .data_pins
+            .iter()
+            .filter(|p| p.pin == pin)
+            .map(|p| format!("D{}", p.line));
+        let control = self
+            .control_lines
+            .iter()
+            .filter(|c| c.pin == pin)
+            .map(|c| c.name.to_uppercase());
+        let programming = self
+            .programming_pins
+            .iter()
+            .flatten()
+            .filter(|p| p.pin == pin)
+            .map(|p| p.name.to_uppercase());
+        let power = self
+            .power_pins
+            .iter()
+            .filter(|p| p.pin == pin)
+            .map(|p| p.name.to_uppercase());
+
+        let mut signals: Vec<String> = Vec::new();
+        for signal in address
+            .chain(data)
+            .chain(control)
+            .chain(programming)
+            .chain(power)
+        {
+            if !signals.contains(&signal) {
+                signals.push(signal);
+            }
+        }
+        signals
+    }
+}
+
 /// Address pin mapping
 #[derive(Serialize, Tsify)]
 #[tsify(into_wasm_abi)]
@@ -541,9 +691,15 @@ pub fn extra_chip_types_for_board(board_name: String) -> Vec<String> {
 /// Return detailed information about a specific ROM type
 #[wasm_bindgen]
 pub fn chip_type_info(name: String) -> Result<ChipTypeInfo, JsValue> {
+    if let Some(info) = custom_chips::custom_chip_info(&name) {
+        return Ok(info);
+    }
     let chip_type = onerom_config::chip::ChipType::try_from_str(&name)
         .ok_or_else(|| JsValue::from_str(&format!("Unknown ROM type: {}", name)))?;
+    Ok(chip_info(chip_type))
+}
 
+pub(crate) fn chip_info(chip_type: onerom_config::chip::ChipType) -> ChipTypeInfo {
     let address_pins = chip_type
         .address_pins()
         .iter()
@@ -595,7 +751,7 @@ pub fn chip_type_info(name: String) -> Result<ChipTypeInfo, JsValue> {
         })
         .collect();
 
-    let info = ChipTypeInfo {
+    ChipTypeInfo {
         name: chip_type.name().to_string(),
         aliases: chip_type.aliases().iter().map(|s| s.to_string()).collect(),
         chip_function: format!("{:?}", chip_type.chip_function()),
@@ -610,9 +766,7 @@ pub fn chip_type_info(name: String) -> Result<ChipTypeInfo, JsValue> {
         control_lines,
         programming_pins,
         power_pins,
-    };
-
-    Ok(info)
+    }
 }
 
 // PCB/Board
@@ -789,14 +943,26 @@ pub fn mcu_chip_id(variant_name: String) -> Result<String, JsValue> {
 }
 
 /// Builder for generating firmware images
+///
+/// Wraps the `onerom-gen` builder together with the config fields this crate
+/// handles itself (see [`config_ext`]) and the inputs it was populated from
+/// (see [`matrix`]). The inputs hold the only copy of each file: the wrapped
+/// builder is given an empty placeholder, so it still checks file IDs, and
+/// every build is from a builder replayed from the inputs.
 #[wasm_bindgen]
-pub struct WasmGenBuilder(GenBuilder);
+pub struct WasmGenBuilder(GenBuilder, ConfigExt, GenInputs);
 
 /// Specification for a file that needs to be retrieved and added to the builder
-#[derive(Serialize, Tsify)]
+///
+/// Files other than ROM images (see `kind`) carry the ROM fields of the ROM
+/// they are for.
+#[derive(Clone, Serialize, Tsify)]
 #[tsify(into_wasm_abi)]
 pub struct WasmFileSpec {
     pub id: usize,
+    /// What the file is: "rom" for a ROM image, "patch" for a patch to one,
+    /// "interleave" for a file to interleave with one.
+    pub kind: String,
     pub source: String,
     pub extract: Option<String>,
     pub size_handling: String,
@@ -809,6 +975,24 @@ pub struct WasmFileSpec {
     pub cs3: Option<String>,
     pub set_type: String,
     pub set_description: Option<String>,
+    /// Expected SHA-256 of the file (after any extract), if configured.
+    pub sha256: Option<String>,
+    /// Expected CRC32 of the file (after any extract), as 8 hex digits.
+    pub crc32: Option<String>,
+    /// Expected SHA-256 of the downloaded archive, if configured.
+    pub archive_sha256: Option<String>,
+}
+
+impl WasmFileSpec {
+    /// Fill in the digests the config declares for this file.
+    fn with_digests(mut self, ext: &ConfigExt) -> Self {
+        if let Some(digests) = ext.digests(self.id) {
+            self.sha256 = digests.sha256.clone();
+            self.crc32 = digests.crc32.map(digest::crc32_hex);
+            self.archive_sha256 = digests.archive_sha256.clone();
+        }
+        self
+    }
 }
 
 /// Result of building a firmware image: (metadata_json, firmware_image)
@@ -843,22 +1027,28 @@ pub fn gen_builder_from_json(
     let version = FirmwareVersion::try_from_str(&version)
         .map_err(|_| "Invalid firmware version format".to_string())?;
     let family = Family::try_from_str(&family).ok_or("Unknown MCU family".to_string())?;
+    let resolved = custom_chips::resolve_config(config_json)?;
+    let config_json = resolved.as_deref().unwrap_or(config_json);
+
+    let builder = GenBuilder::from_json(version, family, config_json)
+        .map_err(|e| format!("Error creating GenBuilder: {e:?}"))?;
+    let ext = ConfigExt::from_json(config_json, builder.file_id_map())
+        .map_err(|e| format!("Error creating GenBuilder: {e}"))?;
+    let inputs = GenInputs::new(version, family, config_json);
 
-    Ok(WasmGenBuilder(
-        GenBuilder::from_json(version, family, config_json)
-            .map_err(|e| format!("Error creating GenBuilder: {e:?}"))?,
-    ))
+    Ok(WasmGenBuilder(builder, ext, inputs))
 }
 
 /// Get the list of file specifications from the builder
 #[wasm_bindgen]
 pub fn gen_file_specs(builder: &WasmGenBuilder) -> Vec<WasmFileSpec> {
-    builder
+    let mut specs: Vec<WasmFileSpec> = builder
         .0
         .file_specs()
         .into_iter()
         .map(|spec| WasmFileSpec {
             id: spec.id,
+            kind: "rom".to_string(),
             source: spec.source,
             extract: spec.extract,
             size_handling: serde_json::to_string(&spec.size_handling)
@@ -886,8 +1076,37 @@ pub fn gen_file_specs(builder: &WasmGenBuilder) -> Vec<WasmFileSpec> {
                 .trim_matches('"')
                 .to_string(),
             set_description: spec.set_description,
+            sha256: None,
+            crc32: None,
+            archive_sha256: None,
         })
-        .collect()
+        .map(|spec| spec.with_digests(&builder.1))
+        .collect();
+
+    let extras: Vec<WasmFileSpec> = builder
+        .1
+        .extra_files()
+        .iter()
+        .filter_map(|extra| {
+            let rom = specs.iter().find(|s| s.id == extra.rom_file_id)?;
+            let spec = WasmFileSpec {
+                id: extra.id,
+                kind: extra.kind.as_str().to_string(),
+                source: extra.source.clone(),
+                extract: extra.extract.clone(),
+                description: extra.description.clone(),
+                // Digests belong to the file fetched, not the ROM it alters.
+                sha256: None,
+                crc32: None,
+                archive_sha256: None,
+                ..rom.clone()
+            };
+            Some(spec.with_digests(&builder.1))
+        })
+        .collect();
+    specs.extend(extras);
+
+    specs
 }
 
 /// License
@@ -921,17 +1140,49 @@ pub fn accept_license(builder: &mut WasmGenBuilder, license: WasmLicense) -> Res
     builder
         .0
         .accept_license(&license)
-        .map_err(|e| format!("Error accepting license: {e:?}"))
+        .map_err(|e| format!("Error accepting license: {e:?}"))?;
+    builder.2.accept_license(license);
+    Ok(())
 }
 
 /// Add a retrieved file to the builder
+///
+/// `data` is the file, already extracted where the spec has `extract`.
+/// `archive` is the file as downloaded, before extraction; pass it whenever
+/// the spec has `extract` so its `archive_sha256` can be checked. Data that
+/// does not match a digest from the config is rejected.
+///
+/// A ROM image with transforms or patches is held until any files they need
+/// have been added too, then transformed, patched and added to the builder;
+/// the call that completes the set reports any error applying them.
 #[wasm_bindgen]
-pub fn gen_add_file(builder: &mut WasmGenBuilder, id: usize, data: Vec<u8>) -> Result<(), String> {
-    let file_data = FileData { id, data };
+pub fn gen_add_file(
+    builder: &mut WasmGenBuilder,
+    id: usize,
+    data: Vec<u8>,
+    archive: Option<Vec<u8>>,
+) -> Result<(), String> {
     builder
-        .0
-        .add_file(file_data)
-        .map_err(|e| format!("Error adding file: {e:?}"))
+        .1
+        .verify(id, &data, archive.as_deref())
+        .map_err(|e| format!("Error adding file: {e}"))?;
+
+    let ready = builder
+        .1
+        .receive(id, data)
+        .map_err(|e| format!("Error adding file: {e}"))?;
+    for (id, data) in ready {
+        let placeholder = FileData {
+            id,
+            data: Vec::new(),
+        };
+        builder
+            .0
+            .add_file(placeholder)
+            .map_err(|e| format!("Error adding file: {e:?}"))?;
+        builder.2.add_file(id, data);
+    }
+    Ok(())
 }
 
 /// Build the firmware image from the builder and properties.
@@ -948,7 +1199,8 @@ pub fn gen_build(builder: &WasmGenBuilder, properties: JsValue) -> Result<WasmIm
         .map_err(|e| format!("Error deserializing properties: {}", e))?;
 
     builder
-        .0
+        .2
+        .builder()?
         .build(props)
         .map(|(firmware_image, metadata_json)| WasmImages(firmware_image, metadata_json))
         .map_err(|e| format!("Error building firmware image: {e:?}"))
@@ -973,7 +1225,8 @@ pub fn gen_build_validation(builder: &WasmGenBuilder, properties: JsValue) -> Re
         .map_err(|e| format!("Error deserializing properties: {}", e))?;
 
     builder
-        .0
+        .2
+        .builder()?
         .build_validation(&props)
         .map_err(|e| format!("Not ready to build: {e:?}"))
 }
@@ -989,8 +1242,8 @@ pub fn gen_build_validation(builder: &WasmGenBuilder, properties: JsValue) -> Re
 // so `onerom-app` orchestrates the manifest fetches while JS performs them. The
 // plugin *binaries* are not fetched here - they are fetched by the existing
 // build pipeline (`gen_file_specs` yields a spec per plugin binary URL, which
-// JS fetches and passes to `gen_add_file`), with SHA-256 verification done in
-// JS against the digest returned by `newest_compatible`.
+// JS fetches and passes to `gen_add_file`), after checking each against its
+// manifest with `PluginCatalog::verify_plugin`.
 
 /// A JavaScript-backed [`onerom_app::PluginFetch`] implementation.
 ///
@@ -1038,14 +1291,53 @@ pub struct WasmPluginRelease {
     pub min_fw_version: String,
 }
 
+/// A plugin whose releases could not be loaded, from
+/// [`PluginCatalog::failures`].
+#[derive(Clone, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct WasmPluginFailure {
+    pub name: String,
+    /// Why, e.g. the fetch or manifest parse error.
+    pub reason: String,
+}
+
+/// A plugin binary checked by [`PluginCatalog::verify_plugin`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct WasmPluginVerification {
+    pub name: String,
+    /// Version and type read from the binary header.
+    pub version: String,
+    pub plugin_type: String,
+    pub size: usize,
+    pub sha256: String,
+}
+
 /// The catalogue of available plugins, with every plugin's releases loaded.
 ///
 /// Constructed by [`plugin_catalog`] (which fetches the manifests through the
 /// JS callback). Once built, [`PluginCatalog::plugins`] fills the dropdowns and
 /// [`PluginCatalog::newest_compatible`] answers per-selection compatibility
-/// queries entirely in memory, with no further fetching.
+/// queries entirely in memory, with no further fetching. Plugins whose
+/// releases could not be loaded are listed by [`PluginCatalog::failures`].
+///
+/// It can be saved with [`PluginCatalog::export_snapshot`] and restored
+/// offline with [`plugin_catalog_from_snapshot`].
 #[wasm_bindgen]
-pub struct PluginCatalog(onerom_app::Catalogue);
+pub struct PluginCatalog {
+    catalogue: onerom_app::Catalogue,
+    failures: Vec<WasmPluginFailure>,
+    /// Manifests as fetched, `(url, bytes)`, kept for snapshots.
+    manifests: Vec<(String, Vec<u8>)>,
+    /// Plugin binaries added with [`PluginCatalog::add_binary`], by URL.
+    binaries: Vec<(String, Vec<u8>)>,
+    /// When the manifests were fetched, in ms since the epoch.
+    fetched_at: f64,
+    /// Whether any manifest came from a snapshot rather than the network.
+    offline: bool,
+    /// Plugins whose manifests differ from the snapshot last revalidated.
+    changed: Vec<String>,
+}
 
 #[wasm_bindgen]
 impl PluginCatalog {
@@ -1055,7 +1347,7 @@ impl PluginCatalog {
     /// `display_name`, `description`, and `releases` (each with `version`,
     /// `sha256`, `min_fw_version`, `incompatible_from`, ...).
     pub fn plugins(&self) -> Result<JsValue, JsValue> {
-        serde_wasm_bindgen::to_value(self.0.plugins())
+        serde_wasm_bindgen::to_value(self.catalogue.plugins())
             .map_err(|e| JsValue::from_str(&e.to_string()))
     }
 
@@ -1067,7 +1359,7 @@ impl PluginCatalog {
     /// name is unknown or `fw` is malformed.
     pub fn newest_compatible(&self, name: String, fw: String) -> Result<JsValue, JsValue> {
         let plugin = self
-            .0
+            .catalogue
             .plugin_by_name(&name)
             .ok_or_else(|| JsValue::from_str(&format!("unknown plugin '{name}'")))?;
 
@@ -1087,6 +1379,67 @@ impl PluginCatalog {
             None => Ok(JsValue::NULL),
         }
     }
+
+    /// Plugins left without releases because their release manifest could not
+    /// be fetched or parsed, with the reason, so they can be shown as
+    /// unavailable rather than silently missing.
+    pub fn failures(&self) -> Vec<WasmPluginFailure> {
+        self.failures.clone()
+    }
+
+    /// Check a fetched plugin binary against release `version` of `name`.
+    ///
+    /// Verifies the binary's SHA-256 against the manifest, that its header
+    /// type matches the manifest's, and that it fits a plugin slot. Errors
+    /// name the first check to fail, or an unknown plugin or version.
+    pub fn verify_plugin(
+        &self,
+        name: String,
+        version: String,
+        bytes: &[u8],
+    ) -> Result<WasmPluginVerification, JsValue> {
+        let (plugin, release) = self.release(&name, &version)?;
+        let target = onerom_app::VerifyTarget::Release {
+            release,
+            expected_type: plugin.plugin_type,
+        };
+        let verified = onerom_app::verify_binary(bytes, target, &plugin.binary_url(release))
+            .map_err(|e| JsValue::from_str(&e.to_string()))?;
+
+        Ok(WasmPluginVerification {
+            name,
+            version: verified.version.to_string(),
+            plugin_type: verified.plugin_type.to_string(),
+            size: verified.size,
+            sha256: release.sha256.to_lowercase(),
+        })
+    }
+}
+
+impl PluginCatalog {
+    /// Release `version` of plugin `name`.
+    fn release(
+        &self,
+        name: &str,
+        version: &str,
+    ) -> Result<(&onerom_app::Plugin, &onerom_app::Release), JsValue> {
+        let plugin = self
+            .catalogue
+            .plugin_by_name(name)
+            .ok_or_else(|| JsValue::from_str(&format!("unknown plugin '{name}'")))?;
+        let wanted = onerom_app::PluginVersion::try_from_str(version)
+            .ok_or_else(|| JsValue::from_str(&format!("invalid plugin version '{version}'")))?;
+        let release = plugin
+            .releases
+            .iter()
+            .find(|r| r.version == wanted)
+            .ok_or_else(|| {
+                JsValue::from_str(
+                    &onerom_app::PluginError::VersionNotFound(name.to_string(), wanted).to_string(),
+                )
+            })?;
+        Ok((plugin, release))
+    }
 }
 
 /// Fetch the plugin catalogue and every plugin's releases, returning a handle.
@@ -1100,17 +1453,9 @@ pub async fn plugin_catalog(fetch_callback: js_sys::Function) -> Result<PluginCa
         callback: fetch_callback,
     };
 
-    let mut catalogue = onerom_app::Catalogue::fetch(&fetch)
+    PluginCatalog::load(&fetch, js_sys::Date::now())
         .await
-        .map_err(plugin_err_to_js)?;
-
-    // Tolerate an individual plugin's releases being unreachable: such plugins
-    // keep empty releases (and the JS side omits them from the dropdown, since
-    // a plugin with no releases cannot be selected). Only the initial catalogue
-    // fetch above is fatal - without it there is nothing to show.
-    let _failures = catalogue.load_all_releases_resilient(&fetch).await;
-
-    Ok(PluginCatalog(catalogue))
+        .map_err(plugin_err_to_js)
 }
 /// A plugin's resolved display information, as returned to JavaScript.
 ///
@@ -1176,4 +1521,4 @@ pub async fn resolve_plugin_label(
     };
 
     serde_wasm_bindgen::to_value(&out).map_err(|e| JsValue::from_str(&e.to_string()))
-}
\ No newline at end of file
+}
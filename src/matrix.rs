@@ -0,0 +1,198 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Building one populated config for several board/MCU/firmware targets.
+//!
+//! An `onerom-gen` builder is validated against a single firmware version and
+//! MCU family when it is created, so a target that differs in either needs a
+//! builder of its own. Rather than have JS create and populate one per target,
+//! the inputs that went into the original builder are kept here and replayed
+//! into a fresh builder for each version and family built for. The inputs
+//! hold the only copy of each file, so every build replays them, including
+//! [`gen_build`](crate::gen_build)'s.
+
+use std::collections::BTreeMap;
+
+use wasm_bindgen::prelude::*;
+
+use onerom_config::fw::{FirmwareProperties, FirmwareVersion};
+use onerom_config::mcu::Family;
+use onerom_gen::{Builder as GenBuilder, FileData, License};
+
+use crate::WasmGenBuilder;
+
+/// Everything a [`WasmGenBuilder`] was created and populated from.
+#[derive(Debug, Clone)]
+pub(crate) struct GenInputs {
+    version: FirmwareVersion,
+    family: Family,
+    config_json: String,
+    licenses: Vec<License>,
+    files: BTreeMap<usize, Vec<u8>>,
+}
+
+impl GenInputs {
+    pub(crate) fn new(version: FirmwareVersion, family: Family, config_json: &str) -> Self {
+        Self {
+            version,
+            family,
+            config_json: config_json.to_string(),
+            licenses: Vec::new(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    /// Record a license accepted on the original builder.
+    pub(crate) fn accept_license(&mut self, license: License) {
+        self.licenses.push(license);
+    }
+
+    /// Record a file added to the original builder.
+    pub(crate) fn add_file(&mut self, id: usize, data: Vec<u8>) {
+        self.files.insert(id, data);
+    }
+
+    /// A populated builder for the original version and family.
+    pub(crate) fn builder(&self) -> Result<GenBuilder, String> {
+        self.replay(self.version, self.family)
+    }
+
+    /// Create a builder for `version`/`family` and replay the recorded
+    /// licenses and files into it.
+    fn replay(&self, version: FirmwareVersion, family: Family) -> Result<GenBuilder, String> {
+        let mut builder = GenBuilder::from_json(version, family, &self.config_json)
+            .map_err(|e| format!("Error creating GenBuilder: {e:?}"))?;
+
+        // Populates the builder's license map, which accept_license needs.
+        builder.licenses();
+        for license in &self.licenses {
+            builder
+                .accept_license(license)
+                .map_err(|e| format!("Error accepting license: {e:?}"))?;
+        }
+
+        for (&id, data) in &self.files {
+            builder
+                .add_file(FileData {
+                    id,
+                    data: data.clone(),
+                })
+                .map_err(|e| format!("Error adding file: {e:?}"))?;
+        }
+
+        Ok(builder)
+    }
+}
+
+/// Outcome of building for one target in [`gen_build_matrix`].
+///
+/// Exactly one of `error` and the image pair is set.
+#[wasm_bindgen]
+pub struct WasmTargetBuild {
+    board: String,
+    mcu: String,
+    version: String,
+    error: Option<String>,
+    images: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+#[wasm_bindgen]
+impl WasmTargetBuild {
+    #[wasm_bindgen(getter)]
+    pub fn board(&self) -> String {
+        self.board.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn mcu(&self) -> String {
+        self.mcu.clone()
+    }
+
+    /// Firmware version, "major.minor.patch".
+    #[wasm_bindgen(getter)]
+    pub fn version(&self) -> String {
+        self.version.clone()
+    }
+
+    /// Why this target could not be built, or `undefined` if it was.
+    #[wasm_bindgen(getter)]
+    pub fn error(&self) -> Option<String> {
+        self.error.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn metadata(&self) -> Option<Vec<u8>> {
+        self.images.as_ref().map(|(m, _)| m.clone())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn firmware_images(&self) -> Option<Vec<u8>> {
+        self.images.as_ref().map(|(_, i)| i.clone())
+    }
+}
+
+/// Build a populated builder for each of several targets.
+///
+/// `targets` is an array of properties objects, each the same shape
+/// [`gen_build`](crate::gen_build) takes. Every file and license must already
+/// have been added to or accepted on `builder`.
+///
+/// Each firmware version and MCU family among the targets gets a builder
+/// re-created from the same config, licenses and files, shared by the targets
+/// with that version and family. A target that fails - its config validation, or the
+/// build itself - reports its error in its own result without affecting the
+/// rest. Results are in target order.
+#[wasm_bindgen]
+pub fn gen_build_matrix(
+    builder: &WasmGenBuilder,
+    targets: JsValue,
+) -> Result<Vec<WasmTargetBuild>, String> {
+    let targets: Vec<FirmwareProperties> = serde_wasm_bindgen::from_value(targets)
+        .map_err(|e| format!("Error deserializing targets: {}", e))?;
+
+    // Re-created builders, shared between targets with the same version and
+    // family.
+    let mut replayed: Vec<(FirmwareVersion, Family, Result<GenBuilder, String>)> = Vec::new();
+
+    let mut results = Vec::with_capacity(targets.len());
+    for props in targets {
+        let version = props.version();
+        let family = props.board().mcu_family();
+
+        let index = match replayed
+            .iter()
+            .position(|(v, f, _)| *v == version && *f == family)
+        {
+            Some(index) => index,
+            None => {
+                replayed.push((version, family, builder.2.replay(version, family)));
+                replayed.len() - 1
+            }
+        };
+        let built = match &replayed[index].2 {
+            Ok(target_builder) => build(target_builder, props),
+            Err(e) => Err(e.clone()),
+        };
+
+        let (error, images) = match built {
+            Ok(images) => (None, Some(images)),
+            Err(e) => (Some(e), None),
+        };
+        results.push(WasmTargetBuild {
+            board: props.board().name().to_string(),
+            mcu: props.mcu_variant().to_string(),
+            version: version.to_string(),
+            error,
+            images,
+        });
+    }
+
+    Ok(results)
+}
+
+fn build(builder: &GenBuilder, props: FirmwareProperties) -> Result<(Vec<u8>, Vec<u8>), String> {
+    builder
+        .build(props)
+        .map_err(|e| format!("Error building firmware image: {e:?}"))
+}
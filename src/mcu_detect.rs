@@ -0,0 +1,228 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! MCU and board identification from a device's ID registers.
+//!
+//! [`parse_firmware`](crate::parse_firmware) needs One ROM firmware on the
+//! device to say what it is. A factory-fresh or foreign board has none, so
+//! this reads the MCU's own identification registers through the same read
+//! callback instead, and maps them to an MCU variant and the boards built
+//! around it:
+//!
+//! - RP2350: SYSINFO `CHIP_ID` and `PACKAGE_SEL`, which give the A (QFN-60)
+//!   or B (QFN-80) package.
+//! - STM32F4: DBGMCU `IDCODE`, which gives the line, and the flash size
+//!   register, which gives the variant within it.
+//!
+//! RP2350 is tried first, as its SYSINFO address is an ordinary peripheral
+//! on STM32F4, whereas the DBGMCU address may fault on RP2350.
+
+use serde::Serialize;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::hw::BOARDS;
+use onerom_config::mcu::{Family, MCU_VARIANTS, Processor, RpVariant, Variant};
+
+use crate::CallbackReader;
+
+/// RP2350 SYSINFO `CHIP_ID`.
+pub(crate) const RP2350_CHIP_ID: u32 = 0x4000_0000;
+/// RP2350 SYSINFO `PACKAGE_SEL`.
+const RP2350_PACKAGE_SEL: u32 = 0x4000_0004;
+/// `CHIP_ID` part number field for RP2350.
+const RP2350_PART: u32 = 0x0004;
+/// JEDEC manufacturer ID of Raspberry Pi, in `CHIP_ID`.
+const RPI_MANUFACTURER: u32 = 0x493;
+
+/// STM32F4 DBGMCU `IDCODE`.
+const STM32F4_IDCODE: u32 = 0xE004_2000;
+/// STM32F4 flash size register, in KB.
+const STM32F4_FLASH_SIZE: u32 = 0x1FFF_7A22;
+
+/// What a device's ID registers say it is, from [`detect_mcu`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct McuDetection {
+    pub mcu_family: String,
+    /// MCU variant, as `FirmwareBuild::mcu`, where only one matches.
+    pub mcu: Option<String>,
+    /// Every MCU variant the registers match.
+    pub candidates: Vec<String>,
+    /// Raw `CHIP_ID` (RP2350) or `IDCODE` (STM32F4).
+    pub id_register: u32,
+    /// Silicon revision from `id_register`.
+    pub revision: u16,
+    /// Flash size in KB, where the MCU reports it. RP2350 flash is external.
+    pub flash_kb: Option<u16>,
+    /// Boards built around a candidate MCU.
+    pub boards: Vec<String>,
+    pub notes: Vec<String>,
+}
+
+/// Identify the MCU on a device from its ID registers, without firmware.
+///
+/// `read_cb` is a JS `async (addr: number, len: number) => Uint8Array`
+/// returning exactly `len` bytes at `addr`, as for
+/// [`parse_firmware`](crate::parse_firmware). A read that throws or
+/// rejects is taken to mean the register is not there.
+#[wasm_bindgen]
+pub async fn detect_mcu(read_cb: js_sys::Function) -> Result<McuDetection, JsValue> {
+    let reader = CallbackReader::new(Vec::new(), 0, read_cb);
+    let read_u32 = async |addr: u32| {
+        let bytes = reader.fetch(addr, 4).await.ok()?;
+        Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+    };
+
+    let chip_id = read_u32(RP2350_CHIP_ID).await;
+    if let Some(chip_id) = chip_id
+        && is_rp2350(chip_id)
+    {
+        let package_sel = read_u32(RP2350_PACKAGE_SEL).await;
+        return Ok(rp2350(chip_id, package_sel));
+    }
+
+    let idcode = read_u32(STM32F4_IDCODE).await;
+    if let Some(idcode) = idcode {
+        // The flash size register is 16 bits, at a 2-byte aligned address.
+        let flash_kb = match reader.fetch(STM32F4_FLASH_SIZE, 2).await {
+            Ok(bytes) if bytes.len() >= 2 => Some(u16::from_le_bytes([bytes[0], bytes[1]])),
+            _ => None,
+        };
+        if let Some(detection) = stm32f4(idcode, flash_kb) {
+            return Ok(detection);
+        }
+    }
+
+    let seen = |v: Option<u32>| v.map_or("unreadable".to_string(), |v| format!("{v:#010x}"));
+    Err(JsValue::from_str(&format!(
+        "No supported MCU identified: CHIP_ID {}, IDCODE {}",
+        seen(chip_id),
+        seen(idcode)
+    )))
+}
+
+pub(crate) fn is_rp2350(chip_id: u32) -> bool {
+    chip_id & 1 == 1
+        && (chip_id >> 1) & 0x7ff == RPI_MANUFACTURER
+        && (chip_id >> 12) & 0xffff == RP2350_PART
+}
+
+fn rp2350(chip_id: u32, package_sel: Option<u32>) -> McuDetection {
+    let mut notes = Vec::new();
+    let package = match package_sel {
+        Some(sel) if sel & 1 == RpVariant::Rp235xA as u32 => Some(RpVariant::Rp235xA),
+        Some(_) => Some(RpVariant::Rp235xB),
+        None => {
+            notes.push("PACKAGE_SEL could not be read, so the package is unknown".to_string());
+            None
+        }
+    };
+    let candidates: Vec<Variant> = match package {
+        Some(RpVariant::Rp235xA) => vec![Variant::RP2350],
+        Some(RpVariant::Rp235xB) => vec![Variant::RP2350B],
+        None => vec![Variant::RP2350, Variant::RP2350B],
+    };
+    let boards = BOARDS
+        .iter()
+        .filter(|b| b.mcu_family() == Family::Rp2350)
+        .filter(|b| package.is_none() || b.rp_variant() == package)
+        .map(|b| b.name().to_string())
+        .collect();
+
+    detection(
+        Family::Rp2350,
+        &candidates,
+        chip_id,
+        (chip_id >> 28) as u16,
+        None,
+        boards,
+        notes,
+    )
+}
+
+fn stm32f4(idcode: u32, flash_kb: Option<u16>) -> Option<McuDetection> {
+    let dev_id = (idcode & 0xfff) as u16;
+    let in_line: Vec<Variant> = MCU_VARIANTS
+        .iter()
+        .copied()
+        .filter(|v| stm32_dev_id(v.processor()) == Some(dev_id))
+        .collect();
+    if in_line.is_empty() {
+        return None;
+    }
+
+    let mut notes = Vec::new();
+    let candidates: Vec<Variant> = match flash_kb {
+        Some(kb) => {
+            let sized: Vec<Variant> = in_line
+                .iter()
+                .copied()
+                .filter(|v| v.flash_storage_kb() == kb as usize)
+                .collect();
+            if sized.is_empty() {
+                notes.push(format!(
+                    "No supported MCU with DEV_ID {dev_id:#05x} has {kb}KB of flash"
+                ));
+            }
+            sized
+        }
+        None => {
+            notes.push("The flash size could not be read, so the variant is unknown".to_string());
+            in_line
+        }
+    };
+    notes.push("The package cannot be read; One ROM boards use 64-pin (R) parts".to_string());
+    let boards = BOARDS
+        .iter()
+        .filter(|b| b.mcu_family() == Family::Stm32f4)
+        .map(|b| b.name().to_string())
+        .collect();
+
+    Some(detection(
+        Family::Stm32f4,
+        &candidates,
+        idcode,
+        (idcode >> 16) as u16,
+        flash_kb,
+        boards,
+        notes,
+    ))
+}
+
+fn detection(
+    family: Family,
+    candidates: &[Variant],
+    id_register: u32,
+    revision: u16,
+    flash_kb: Option<u16>,
+    boards: Vec<String>,
+    notes: Vec<String>,
+) -> McuDetection {
+    McuDetection {
+        mcu_family: family.to_string(),
+        mcu: match candidates {
+            [only] => Some(only.to_string()),
+            _ => None,
+        },
+        candidates: candidates.iter().map(|v| v.to_string()).collect(),
+        id_register,
+        revision,
+        flash_kb,
+        boards,
+        notes,
+    }
+}
+
+/// DBGMCU `IDCODE` `DEV_ID` for an STM32F4 line.
+fn stm32_dev_id(processor: Processor) -> Option<u16> {
+    match processor {
+        Processor::F401BC => Some(0x423),
+        Processor::F401DE => Some(0x433),
+        Processor::F405 => Some(0x413),
+        Processor::F411 => Some(0x431),
+        Processor::F446 => Some(0x421),
+        Processor::RP2350 => None,
+    }
+}
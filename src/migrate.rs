@@ -0,0 +1,317 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Config migration between firmware versions.
+//!
+//! Configs are rewritten as raw JSON rather than through `onerom-gen`'s
+//! `Config`, so fields `onerom-gen` does not know about - digests, patches,
+//! transforms - survive. The rules follow what `onerom-gen` validates for the
+//! target version:
+//!
+//! - `rom_sets`/`roms` become `chip_sets`/`chips` (always).
+//! - Chip types newer than the target are dropped, with their sets.
+//! - Firmware overrides need 0.6.0, instance name, boot logging, turbo boot
+//!   and disabling SWD need 0.7.0, as do CE/OE.
+//! - From 0.7.0, fixed-polarity CS lines must not be stated, Ice overrides and
+//!   Fire CPU serving are gone, and ignoring a CS line needs
+//!   `allow_cs_ignore`. Before 0.7.0 every CS line must be stated.
+//!
+//! Anything that changes behaviour, rather than just spelling, is reported as
+//! a warning as well as a change.
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::{ChipType, ControlLineType};
+use onerom_config::fw::FirmwareVersion;
+use onerom_config::mcu::Family;
+use onerom_gen::{Builder as GenBuilder, MIN_FIRMWARE_OVERRIDES_VERSION};
+
+use crate::custom_chips;
+
+const V2: FirmwareVersion = onerom_gen::MIN_SUPPORTED_FIRMWARE_VERSION_V2;
+
+/// The result of [`migrate_config`].
+#[derive(Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct ConfigMigration {
+    /// The rewritten config.
+    pub config_json: String,
+    /// Every change made, in order.
+    pub changes: Vec<String>,
+    /// Changes that alter behaviour, and anything that could not be carried
+    /// over.
+    pub warnings: Vec<String>,
+    /// Why `onerom-gen` still rejects the rewritten config for the target, if
+    /// it does.
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct Log {
+    changes: Vec<String>,
+    warnings: Vec<String>,
+}
+
+impl Log {
+    fn change(&mut self, msg: String) {
+        self.changes.push(msg);
+    }
+
+    fn warn(&mut self, msg: String) {
+        self.warnings.push(msg.clone());
+        self.changes.push(msg);
+    }
+}
+
+/// Upgrade (or downgrade) a config to firmware `version` for MCU `family`.
+///
+/// Version and family are as for `gen_builder_from_json`. The rewritten
+/// config is returned even if `onerom-gen` still rejects it, with the reason
+/// in `error`, so the user can see what remains to fix by hand.
+#[wasm_bindgen]
+pub fn migrate_config(
+    config_json: &str,
+    version: String,
+    family: String,
+) -> Result<ConfigMigration, String> {
+    let version = FirmwareVersion::try_from_str(&version)
+        .map_err(|_| "Invalid firmware version format".to_string())?;
+    let family = Family::try_from_str(&family).ok_or("Unknown MCU family".to_string())?;
+    let mut config: Value =
+        serde_json::from_str(config_json).map_err(|e| format!("Error parsing config: {e}"))?;
+    let root = config
+        .as_object_mut()
+        .ok_or("Config is not a JSON object")?;
+
+    let mut log = Log::default();
+    if version >= V2 && family != Family::Rp2350 {
+        log.warnings.push(format!(
+            "Firmware {version} only supports RP2350 (Fire) boards, not {family}"
+        ));
+    }
+
+    rename(root, "rom_sets", "chip_sets", &mut log);
+    let sets = match root.get_mut("chip_sets") {
+        Some(Value::Array(sets)) => sets,
+        _ => return Err("Config has no chip_sets".to_string()),
+    };
+    for (set_id, set) in sets.iter_mut().enumerate() {
+        if let Some(set) = set.as_object_mut() {
+            rename(set, "roms", "chips", &mut log);
+            migrate_set(set, set_id, version, &mut log);
+        }
+    }
+    // A set left with no chips is dropped along with them.
+    sets.retain(|set| {
+        set.get("chips")
+            .and_then(Value::as_array)
+            .is_none_or(|chips| !chips.is_empty())
+    });
+
+    migrate_globals(root, version, &mut log);
+
+    let config_json =
+        serde_json::to_string_pretty(&config).map_err(|e| format!("Error writing config: {e}"))?;
+    let error = match custom_chips::resolve_config(&config_json) {
+        Ok(resolved) => {
+            GenBuilder::from_json(version, family, resolved.as_deref().unwrap_or(&config_json))
+                .err()
+                .map(|e| format!("{e:?}"))
+        }
+        Err(e) => Some(e),
+    };
+
+    Ok(ConfigMigration {
+        config_json,
+        changes: log.changes,
+        warnings: log.warnings,
+        error,
+    })
+}
+
+fn rename(obj: &mut Map<String, Value>, from: &str, to: &str, log: &mut Log) {
+    if !obj.contains_key(to)
+        && let Some(value) = obj.remove(from)
+    {
+        obj.insert(to.to_string(), value);
+        log.change(format!("Renamed '{from}' to '{to}'"));
+    }
+}
+
+fn migrate_globals(root: &mut Map<String, Value>, version: FirmwareVersion, log: &mut Log) {
+    if version >= V2 {
+        return;
+    }
+    let unsupported = [
+        ("instance_name", None),
+        ("boot_logging", Some(Value::Bool(false))),
+        ("turbo_boot", Some(Value::Bool(false))),
+        ("swd_enabled", Some(Value::Bool(true))),
+    ];
+    for (key, default) in unsupported {
+        let Some(value) = root.remove(key) else {
+            continue;
+        };
+        if Some(&value) == default.as_ref() {
+            log.change(format!(
+                "Removed '{key}', which firmware {version} does not support"
+            ));
+        } else {
+            log.warn(format!(
+                "Removed '{key}': {value}, which needs firmware {V2} or later"
+            ));
+        }
+    }
+}
+
+fn migrate_set(
+    set: &mut Map<String, Value>,
+    set_id: usize,
+    version: FirmwareVersion,
+    log: &mut Log,
+) {
+    let multi = set.get("type").and_then(Value::as_str) == Some("multi");
+
+    if let Some(chips) = set.get_mut("chips").and_then(Value::as_array_mut) {
+        chips.retain(|chip| {
+            let Some(name) = chip.get("type").and_then(Value::as_str) else {
+                return true;
+            };
+            // A custom type needs what the type it is served as does.
+            let (Some(name), Some(chip_type)) = (
+                custom_chips::chip_type_name(name),
+                custom_chips::chip_type_or_base(name),
+            ) else {
+                return true;
+            };
+            match chip_type.min_supported_firmware_version() {
+                Some(min) if version < min => {
+                    log.warn(format!(
+                        "Removed {name} chip from set {set_id}: needs firmware {min} or later"
+                    ));
+                    false
+                }
+                _ => true,
+            }
+        });
+        for (chip_idx, chip) in chips.iter_mut().enumerate() {
+            if let Some(chip) = chip.as_object_mut() {
+                let secondary = multi && chip_idx > 0;
+                migrate_chip(chip, set_id, chip_idx, secondary, version, log);
+            }
+        }
+    }
+
+    let Some(overrides) = set.get_mut("firmware_overrides") else {
+        return;
+    };
+    if version < MIN_FIRMWARE_OVERRIDES_VERSION {
+        set.remove("firmware_overrides");
+        log.warn(format!(
+            "Removed firmware overrides from set {set_id}: need firmware {MIN_FIRMWARE_OVERRIDES_VERSION} or later"
+        ));
+        return;
+    }
+    if version < V2 {
+        return;
+    }
+    let Some(overrides) = overrides.as_object_mut() else {
+        return;
+    };
+    if overrides.remove("ice").is_some() {
+        log.warn(format!(
+            "Removed Ice overrides from set {set_id}: firmware {version} is Fire only"
+        ));
+    }
+    if let Some(fire) = overrides.get_mut("fire").and_then(Value::as_object_mut) {
+        if fire
+            .get("serve_mode")
+            .and_then(Value::as_str)
+            .is_some_and(|mode| mode.eq_ignore_ascii_case("cpu"))
+        {
+            fire.remove("serve_mode");
+            log.warn(format!(
+                "Removed CPU serve mode from set {set_id}: firmware {version} serves with PIO only"
+            ));
+        }
+        if fire.get("rom_dma_preload") == Some(&Value::Bool(false)) {
+            fire.remove("rom_dma_preload");
+            log.change(format!(
+                "Removed 'rom_dma_preload': false from set {set_id}, which has no effect on firmware {version}"
+            ));
+        }
+    }
+}
+
+/// Custom chip types are left as they are: their lines are settled when
+/// they are resolved to the type they are served as.
+fn migrate_chip(
+    chip: &mut Map<String, Value>,
+    set_id: usize,
+    chip_idx: usize,
+    secondary: bool,
+    version: FirmwareVersion,
+    log: &mut Log,
+) {
+    let Some(chip_type) = chip
+        .get("type")
+        .and_then(Value::as_str)
+        .and_then(ChipType::try_from_str)
+    else {
+        return;
+    };
+    if chip_type.is_plugin() {
+        return;
+    }
+    let at = format!("set {set_id}, chip {chip_idx}");
+
+    if version < V2 {
+        for key in ["ce", "oe", "cs4", "allow_cs_ignore"] {
+            if chip.remove(key).is_some() {
+                log.warn(format!(
+                    "Removed '{key}' from {at}: needs firmware {V2} or later"
+                ));
+            }
+        }
+        // Every CS line must be stated, fixed ones included.
+        for line in chip_type.control_lines() {
+            if matches!(line.name, "cs1" | "cs2" | "cs3")
+                && !chip.contains_key(line.name)
+                && let Some(high) = line.line_type.fixed_active_level()
+            {
+                let logic = crate::level(high);
+                chip.insert(line.name.to_string(), Value::String(logic.to_string()));
+                log.change(format!("Set fixed {} to {logic} on {at}", line.name));
+            }
+        }
+        return;
+    }
+
+    let mut needs_allow_ignore = false;
+    for line in chip_type.control_lines() {
+        let Some(logic) = chip.get(line.name).and_then(Value::as_str) else {
+            continue;
+        };
+        let ignore = logic == "ignore";
+        if line.line_type != ControlLineType::Configurable && !ignore {
+            chip.remove(line.name);
+            log.change(format!(
+                "Removed {} from {at}: its polarity is fixed by the {} chip type",
+                line.name,
+                chip_type.name()
+            ));
+        } else if ignore && !secondary && !line.allow_ignore {
+            needs_allow_ignore = true;
+        }
+    }
+    if needs_allow_ignore && chip.get("allow_cs_ignore") != Some(&Value::Bool(true)) {
+        chip.insert("allow_cs_ignore".to_string(), Value::Bool(true));
+        log.warn(format!(
+            "Set 'allow_cs_ignore' on {at}, which ignores a CS line - check this is intended"
+        ));
+    }
+}
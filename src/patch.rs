@@ -0,0 +1,520 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! IPS, BPS and UPS patch application.
+//!
+//! The format is detected from the patch's magic. BPS and UPS carry CRC32s of
+//! the source, target and patch, and all three are checked; IPS has no
+//! checksums, so an IPS patch applied to the wrong ROM is only caught by the
+//! ROM's own configured digests.
+
+use crate::digest;
+
+/// Patch formats, as named in configs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PatchFormat {
+    Ips,
+    Bps,
+    Ups,
+}
+
+impl PatchFormat {
+    pub(crate) fn try_from_str(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "ips" => Some(Self::Ips),
+            "bps" => Some(Self::Bps),
+            "ups" => Some(Self::Ups),
+            _ => None,
+        }
+    }
+
+    fn detect(patch: &[u8]) -> Option<Self> {
+        if patch.starts_with(b"PATCH") {
+            Some(Self::Ips)
+        } else if patch.starts_with(b"BPS1") {
+            Some(Self::Bps)
+        } else if patch.starts_with(b"UPS1") {
+            Some(Self::Ups)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Ips => "IPS",
+            Self::Bps => "BPS",
+            Self::Ups => "UPS",
+        }
+    }
+}
+
+/// Apply `patch` to `source`, returning the patched image.
+///
+/// If `expected` is given, the patch must be in that format.
+pub(crate) fn apply(
+    source: &[u8],
+    patch: &[u8],
+    expected: Option<PatchFormat>,
+) -> Result<Vec<u8>, String> {
+    let format = PatchFormat::detect(patch).ok_or("Unrecognised patch format")?;
+    if let Some(expected) = expected
+        && expected != format
+    {
+        return Err(format!(
+            "Patch is {}, but config says {}",
+            format.name(),
+            expected.name()
+        ));
+    }
+
+    match format {
+        PatchFormat::Ips => apply_ips(source, patch),
+        PatchFormat::Bps => apply_bps(source, patch),
+        PatchFormat::Ups => apply_ups(source, patch),
+    }
+}
+
+/// Cursor over patch bytes, erroring rather than panicking on truncation.
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8], pos: usize) -> Self {
+        Self { data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.data.len())
+            .ok_or_else(|| format!("Patch truncated at offset {}", self.pos))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | b as usize))
+    }
+
+    /// The variable-length integer encoding shared by BPS and UPS.
+    fn varint(&mut self) -> Result<usize, String> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.u8()?;
+            value = (x as usize & 0x7f)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or("Patch integer overflow")?;
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or("Patch integer overflow")?;
+            value = value.checked_add(shift).ok_or("Patch integer overflow")?;
+        }
+    }
+}
+
+fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut out = source.to_vec();
+    let mut cur = Cursor::new(patch, 5);
+
+    loop {
+        if cur.data[cur.pos..].starts_with(b"EOF") && cur.data.len() - cur.pos <= 6 {
+            cur.pos += 3;
+            break;
+        }
+        let offset = cur.be(3)?;
+        let size = cur.be(2)?;
+        let (len, fill) = if size == 0 {
+            let run = cur.be(2)?;
+            (run, Some(cur.u8()?))
+        } else {
+            (size, None)
+        };
+
+        if out.len() < offset + len {
+            check_target_size(offset + len)?;
+            out.resize(offset + len, 0);
+        }
+        match fill {
+            Some(value) => out[offset..offset + len].fill(value),
+            None => out[offset..offset + len].copy_from_slice(cur.bytes(len)?),
+        }
+    }
+
+    // Optional truncation extension: 3 bytes of new length after "EOF".
+    if cur.data.len() - cur.pos == 3 {
+        let len = cur.be(3)?;
+        out.truncate(len);
+    }
+
+    Ok(out)
+}
+
+/// Check the 12-byte CRC32 footer shared by BPS and UPS: source, target and
+/// patch (which covers everything before its own CRC).
+fn check_footer(source: &[u8], target: &[u8], patch: &[u8]) -> Result<(), String> {
+    let footer = &patch[patch.len() - 12..];
+    let crc = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+
+    let patch_crc = digest::crc32(&patch[..patch.len() - 4]);
+    if patch_crc != crc(&footer[8..]) {
+        return Err("Patch is corrupt: patch crc32 mismatch".to_string());
+    }
+    if digest::crc32(source) != crc(footer) {
+        return Err("Patch does not apply to this ROM: source crc32 mismatch".to_string());
+    }
+    if digest::crc32(target) != crc(&footer[4..]) {
+        return Err("Patched ROM is wrong: target crc32 mismatch".to_string());
+    }
+    Ok(())
+}
+
+/// Reject a target size from a patch header larger than any ROM we can
+/// serve, before allocating it.
+fn check_target_size(target_size: usize) -> Result<(), String> {
+    let max = onerom_config::chip::CHIP_TYPES
+        .iter()
+        .map(|t| t.size_bytes())
+        .max()
+        .unwrap_or(0);
+    if target_size > max {
+        return Err(format!(
+            "Patch target size {target_size} bytes is larger than any supported ROM ({max} bytes)"
+        ));
+    }
+    Ok(())
+}
+
+fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < 4 + 12 {
+        return Err("Patch truncated".to_string());
+    }
+    let end = patch.len() - 12;
+    let mut cur = Cursor::new(&patch[..end], 4);
+
+    let source_size = cur.varint()?;
+    let target_size = cur.varint()?;
+    if source_size != source.len() {
+        return Err(format!(
+            "Patch does not apply to this ROM: expects {source_size} bytes, ROM is {}",
+            source.len()
+        ));
+    }
+
+    check_target_size(target_size)?;
+
+    let mut out = vec![0u8; target_size];
+    let copy = source.len().min(target_size);
+    out[..copy].copy_from_slice(&source[..copy]);
+
+    let mut pos: usize = 0;
+    while cur.pos < end {
+        pos = pos
+            .checked_add(cur.varint()?)
+            .ok_or("Patch offset overflow")?;
+        loop {
+            let x = cur.u8()?;
+            if let Some(b) = out.get_mut(pos) {
+                *b ^= x;
+            }
+            pos += 1;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+
+    check_footer(source, &out, patch)?;
+    Ok(out)
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.len() < 4 + 12 {
+        return Err("Patch truncated".to_string());
+    }
+    let end = patch.len() - 12;
+    let mut cur = Cursor::new(&patch[..end], 4);
+
+    let source_size = cur.varint()?;
+    let target_size = cur.varint()?;
+    let metadata_size = cur.varint()?;
+    cur.bytes(metadata_size)?;
+    if source_size != source.len() {
+        return Err(format!(
+            "Patch does not apply to this ROM: expects {source_size} bytes, ROM is {}",
+            source.len()
+        ));
+    }
+
+    check_target_size(target_size)?;
+
+    let mut out: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_rel: usize = 0;
+    let mut target_rel: usize = 0;
+    let bad = || "Patch reads outside the ROM".to_string();
+
+    while cur.pos < end {
+        let data = cur.varint()?;
+        let len = (data >> 2) + 1;
+        if len > target_size - out.len() {
+            return Err("Patch writes past its target size".to_string());
+        }
+        match data & 3 {
+            // SourceRead: the same bytes from the source, at the output offset.
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(source.get(start..start + len).ok_or_else(bad)?);
+            }
+            // TargetRead: literal bytes from the patch.
+            1 => out.extend_from_slice(cur.bytes(len)?),
+            // SourceCopy / TargetCopy: bytes from a relative offset into the
+            // source or the output so far.
+            command => {
+                let data = cur.varint()?;
+                let delta = data >> 1;
+                let rel = if command == 2 {
+                    &mut source_rel
+                } else {
+                    &mut target_rel
+                };
+                *rel = if data & 1 != 0 {
+                    rel.checked_sub(delta)
+                } else {
+                    rel.checked_add(delta)
+                }
+                .ok_or_else(bad)?;
+
+                for _ in 0..len {
+                    let b = if command == 2 {
+                        *source.get(*rel).ok_or_else(bad)?
+                    } else {
+                        *out.get(*rel).ok_or_else(bad)?
+                    };
+                    out.push(b);
+                    *rel += 1;
+                }
+            }
+        }
+    }
+
+    if out.len() != target_size {
+        return Err(format!(
+            "Patch produced {} bytes, expected {target_size}",
+            out.len()
+        ));
+    }
+    check_footer(source, &out, patch)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let x = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | x);
+                return;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    /// Append the source, target and patch CRC32 footer.
+    fn footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(digest::crc32(source).to_le_bytes());
+        patch.extend(digest::crc32(target).to_le_bytes());
+        patch.extend(digest::crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    /// A UPS patch from `source` to `target`, one hunk per differing run.
+    fn ups(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        varint(source.len(), &mut patch);
+        varint(target.len(), &mut patch);
+        let byte = |b: &[u8], i: usize| b.get(i).copied().unwrap_or(0);
+        let len = source.len().max(target.len());
+        let (mut i, mut last) = (0, 0);
+        while i < len {
+            if byte(source, i) == byte(target, i) {
+                i += 1;
+                continue;
+            }
+            varint(i - last, &mut patch);
+            while i < len && byte(source, i) != byte(target, i) {
+                patch.push(byte(source, i) ^ byte(target, i));
+                i += 1;
+            }
+            patch.push(0);
+            i += 1;
+            last = i;
+        }
+        footer(patch, source, target)
+    }
+
+    fn source() -> Vec<u8> {
+        (0..16).collect()
+    }
+
+    /// A BPS patch using each of its four commands on [`source`].
+    fn bps(target: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        varint(16, &mut patch);
+        varint(target.len(), &mut patch);
+        varint(0, &mut patch);
+        let command =
+            |patch: &mut Vec<u8>, len: usize, kind: usize| varint(((len - 1) << 2) | kind, patch);
+        // SourceRead 4.
+        command(&mut patch, 4, 0);
+        // TargetRead "ABCD".
+        command(&mut patch, 4, 1);
+        patch.extend(b"ABCD");
+        // SourceCopy 4 from source offset 8.
+        command(&mut patch, 4, 2);
+        varint(8 << 1, &mut patch);
+        // TargetCopy 4 from target offset 0.
+        command(&mut patch, 4, 3);
+        varint(0, &mut patch);
+        footer(patch, &source(), target)
+    }
+
+    fn bps_target() -> Vec<u8> {
+        [&[0, 1, 2, 3], &b"ABCD"[..], &[8, 9, 10, 11], &[0, 1, 2, 3]].concat()
+    }
+
+    #[test]
+    fn applies_ips() {
+        let mut patch = b"PATCH".to_vec();
+        // 3 bytes at 2.
+        patch.extend([0, 0, 2, 0, 3]);
+        patch.extend(b"xyz");
+        // RLE: 4 bytes of 0xEE at 14, growing the ROM.
+        patch.extend([0, 0, 14, 0, 0, 0, 4, 0xEE]);
+        patch.extend(b"EOF");
+        let out = apply(&source(), &patch, Some(PatchFormat::Ips)).unwrap();
+        let mut expected = source();
+        expected[2..5].copy_from_slice(b"xyz");
+        expected.truncate(14);
+        expected.extend([0xEE; 4]);
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn applies_ips_truncation() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0, 0, 0, 0, 1, 0xAA]);
+        patch.extend(b"EOF");
+        patch.extend([0, 0, 6]);
+        let out = apply(&source(), &patch, None).unwrap();
+        assert_eq!(out, [0xAA, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn rejects_oversized_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0xFF, 0xFF, 0xF0, 0, 0, 0xFF, 0xFF, 0]);
+        patch.extend(b"EOF");
+        let e = apply(&source(), &patch, None).unwrap_err();
+        assert!(e.contains("larger than any supported ROM"), "{e}");
+    }
+
+    #[test]
+    fn rejects_truncated_ips() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend([0, 0, 2, 0, 3]);
+        patch.extend(b"x");
+        let e = apply(&source(), &patch, None).unwrap_err();
+        assert!(e.starts_with("Patch truncated"), "{e}");
+    }
+
+    #[test]
+    fn applies_bps() {
+        let target = bps_target();
+        let out = apply(&source(), &bps(&target), Some(PatchFormat::Bps)).unwrap();
+        assert_eq!(out, target);
+    }
+
+    #[test]
+    fn applies_ups() {
+        let source = source();
+        let mut target = source.clone();
+        target[1] = 0x55;
+        target[9..12].fill(0xAA);
+        target.extend([1, 2, 3]);
+        assert_eq!(
+            apply(&source, &ups(&source, &target), None).unwrap(),
+            target
+        );
+        // And shrinking.
+        let target = &source[..10];
+        assert_eq!(apply(&source, &ups(&source, target), None).unwrap(), target);
+    }
+
+    #[test]
+    fn rejects_truncated_bps_and_ups() {
+        for patch in [&b"BPS1\x90"[..], b"UPS1\x90\x90\x81"] {
+            let e = apply(&source(), patch, None).unwrap_err();
+            assert!(e.starts_with("Patch truncated"), "{e}");
+        }
+        // A footer, but the commands cut short.
+        let mut patch = bps(&bps_target());
+        patch.drain(10..14);
+        assert!(apply(&source(), &patch, None).is_err());
+    }
+
+    #[test]
+    fn checks_crcs() {
+        let source = source();
+        let mut target = source.clone();
+        target[0] = 0xFF;
+        for patch in [bps(&bps_target()), ups(&source, &target)] {
+            // Corrupt patch.
+            let mut corrupt = patch.clone();
+            *corrupt.last_mut().unwrap() ^= 1;
+            let e = apply(&source, &corrupt, None).unwrap_err();
+            assert!(e.contains("patch crc32 mismatch"), "{e}");
+
+            // Wrong ROM, of the right size.
+            let mut other = source.clone();
+            other[15] ^= 1;
+            let e = apply(&other, &patch, None).unwrap_err();
+            assert!(e.contains("source crc32 mismatch"), "{e}");
+
+            // Wrong target CRC, in an otherwise valid patch.
+            let body = &patch[..patch.len() - 12];
+            let mut wrong = body.to_vec();
+            wrong.extend(digest::crc32(&source).to_le_bytes());
+            wrong.extend(0u32.to_le_bytes());
+            wrong.extend(digest::crc32(&wrong).to_le_bytes());
+            let e = apply(&source, &wrong, None).unwrap_err();
+            assert!(e.contains("target crc32 mismatch"), "{e}");
+        }
+    }
+
+    #[test]
+    fn checks_format() {
+        let e = apply(&source(), &bps(&bps_target()), Some(PatchFormat::Ips)).unwrap_err();
+        assert_eq!(e, "Patch is BPS, but config says IPS");
+        assert!(apply(&source(), b"NOPE", None).is_err());
+    }
+}
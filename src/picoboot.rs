@@ -0,0 +1,534 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! RP2350 PICOBOOT flashing.
+//!
+//! Carries out a [`FlashPlan`] on an RP2350 in BOOTSEL mode through the
+//! bootrom's PICOBOOT vendor interface. Each command is a 32-byte packet on
+//! the bulk OUT endpoint, followed by its data phase, if any (OUT for a
+//! write, IN for a read), then a zero-length acknowledgement in the other
+//! direction. A failed command halts the endpoints; the interface's
+//! `IF_RESET` control request clears the failure and `IF_CMD_STATUS` says
+//! what it was.
+//!
+//! The sequence is that of picotool: take exclusive access, exit XIP, erase
+//! the planned sectors, write and read back each chunk, then reboot into the
+//! new firmware.
+//!
+//! The tests run plans against a simulated device in `picoboot_sim`.
+
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::mcu::Family;
+
+use crate::flash_plan::FlashPlan;
+
+/// `dMagic` of every command.
+pub(crate) const MAGIC: u32 = 0x431f_d10b;
+/// Length of a command packet.
+pub(crate) const COMMAND_LEN: usize = 32;
+
+pub(crate) const CMD_EXCLUSIVE_ACCESS: u8 = 0x01;
+pub(crate) const CMD_FLASH_ERASE: u8 = 0x03;
+pub(crate) const CMD_READ: u8 = 0x84;
+pub(crate) const CMD_WRITE: u8 = 0x05;
+pub(crate) const CMD_EXIT_XIP: u8 = 0x06;
+pub(crate) const CMD_REBOOT2: u8 = 0x0a;
+
+/// Interface control requests.
+pub(crate) const IF_RESET: u8 = 0x41;
+pub(crate) const IF_CMD_STATUS: u8 = 0x42;
+
+/// `bExclusive` for exclusive access, without ejecting the USB drive.
+pub(crate) const EXCLUSIVE: u8 = 1;
+/// `REBOOT2` flags for a normal boot.
+const REBOOT_NORMAL: u32 = 0;
+/// How long after `REBOOT2` the device reboots.
+const REBOOT_DELAY_MS: u32 = 500;
+
+/// `dStatusCode` for success.
+pub(crate) const STATUS_OK: u32 = 0;
+
+/// Default number of times a failed command is retried.
+const DEFAULT_RETRIES: u32 = 2;
+
+/// Name of a PICOBOOT `dStatusCode`.
+pub(crate) fn status_name(status: u32) -> &'static str {
+    match status {
+        0 => "OK",
+        1 => "UNKNOWN_CMD",
+        2 => "INVALID_CMD_LENGTH",
+        3 => "INVALID_TRANSFER_LENGTH",
+        4 => "INVALID_ADDRESS",
+        5 => "BAD_ALIGNMENT",
+        6 => "INTERLEAVED_WRITE",
+        7 => "REBOOTED",
+        8 => "UNKNOWN_ERROR",
+        9 => "INVALID_STATE",
+        10 => "NOT_PERMITTED",
+        11 => "INVALID_ARG",
+        12 => "BUFFER_TOO_SMALL",
+        13 => "PRECONDITION_NOT_MET",
+        14 => "MODIFIED_DATA",
+        15 => "INVALID_DATA",
+        16 => "NOT_FOUND",
+        17 => "UNSUPPORTED_MODIFICATION",
+        _ => "UNKNOWN",
+    }
+}
+
+/// A PICOBOOT command packet.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Command {
+    pub(crate) token: u32,
+    pub(crate) id: u8,
+    /// Bytes of `args` used.
+    pub(crate) size: u8,
+    pub(crate) transfer_len: u32,
+    pub(crate) args: [u8; 16],
+}
+
+impl Command {
+    fn new(id: u8, args: &[u32], transfer_len: u32) -> Self {
+        let mut bytes = [0; 16];
+        for (arg, chunk) in args.iter().zip(bytes.chunks_exact_mut(4)) {
+            chunk.copy_from_slice(&arg.to_le_bytes());
+        }
+        Self {
+            token: 0,
+            id,
+            size: (args.len() * 4) as u8,
+            transfer_len,
+            args: bytes,
+        }
+    }
+
+    fn exclusive_access(exclusive: u8) -> Self {
+        let mut cmd = Self::new(CMD_EXCLUSIVE_ACCESS, &[], 0);
+        cmd.args[0] = exclusive;
+        cmd.size = 1;
+        cmd
+    }
+
+    /// The `n`th 32-bit argument.
+    #[cfg(test)]
+    pub(crate) fn arg(&self, n: usize) -> u32 {
+        u32::from_le_bytes(self.args[n * 4..n * 4 + 4].try_into().unwrap())
+    }
+
+    /// Whether the data phase, if any, is IN.
+    pub(crate) fn is_in(&self) -> bool {
+        self.id & 0x80 != 0
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(COMMAND_LEN);
+        bytes.extend_from_slice(&MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&self.token.to_le_bytes());
+        bytes.push(self.id);
+        bytes.push(self.size);
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(&self.transfer_len.to_le_bytes());
+        bytes.extend_from_slice(&self.args);
+        bytes
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        if bytes.len() != COMMAND_LEN || word(0) != MAGIC {
+            return None;
+        }
+        Some(Self {
+            token: word(4),
+            id: bytes[8],
+            size: bytes[9],
+            transfer_len: word(12),
+            args: bytes[16..32].try_into().unwrap(),
+        })
+    }
+}
+
+/// An `IF_CMD_STATUS` response.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CommandStatus {
+    pub(crate) token: u32,
+    pub(crate) status: u32,
+    // Not needed to drive the device, but part of the response.
+    #[allow(dead_code)]
+    pub(crate) id: u8,
+    #[allow(dead_code)]
+    pub(crate) in_progress: bool,
+}
+
+impl CommandStatus {
+    #[cfg(test)]
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(&self.token.to_le_bytes());
+        bytes.extend_from_slice(&self.status.to_le_bytes());
+        bytes.push(self.id);
+        bytes.push(self.in_progress as u8);
+        bytes.resize(16, 0);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 10 {
+            return Err(format!(
+                "Short IF_CMD_STATUS response: {} bytes",
+                bytes.len()
+            ));
+        }
+        Ok(Self {
+            token: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            status: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            id: bytes[8],
+            in_progress: bytes[9] != 0,
+        })
+    }
+}
+
+/// The host side of a PICOBOOT interface.
+pub(crate) trait PicobootTransport {
+    /// Write `data` to the bulk OUT endpoint.
+    async fn bulk_out(&mut self, data: &[u8]) -> Result<(), String>;
+    /// Read up to `length` bytes from the bulk IN endpoint.
+    async fn bulk_in(&mut self, length: u32) -> Result<Vec<u8>, String>;
+    /// Vendor control request to the interface: OUT with no data, or IN of
+    /// `length` bytes. `None` where the transport cannot make them.
+    async fn control(&mut self, request: u8, length: u16) -> Option<Result<Vec<u8>, String>>;
+}
+
+/// Options for [`picoboot_flash`]. All optional.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+pub(crate) struct PicobootOptions {
+    /// Read back and compare each write, default true.
+    pub(crate) verify: Option<bool>,
+    /// Reboot into the new firmware once done, default true.
+    pub(crate) reboot: Option<bool>,
+    /// Times a command the device rejects is retried, default 2.
+    pub(crate) retries: Option<u32>,
+}
+
+/// What a PICOBOOT flash did, from [`picoboot_flash`].
+#[derive(Debug, Default, Serialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct PicobootReport {
+    pub mcu: String,
+    /// Contiguous runs of sectors erased, one `FLASH_ERASE` each.
+    pub ranges_erased: u32,
+    pub bytes_erased: u32,
+    pub chunks_written: u32,
+    /// Bytes written, padding included.
+    pub bytes_written: u32,
+    /// Bytes read back and found to match.
+    pub bytes_verified: u32,
+    /// Commands retried after the device rejected them.
+    pub retries: u32,
+    /// Whether the device was told to reboot.
+    pub rebooted: bool,
+    /// Erases, retries and recovery, in order.
+    pub log: Vec<String>,
+}
+
+struct Picoboot<'a, T: PicobootTransport> {
+    dev: &'a mut T,
+    token: u32,
+    retries: u32,
+    report: PicobootReport,
+}
+
+impl<T: PicobootTransport> Picoboot<'_, T> {
+    /// Send `cmd` with `data` for an OUT data phase, returning what an IN
+    /// data phase read.
+    async fn exchange(&mut self, mut cmd: Command, data: &[u8]) -> Result<Vec<u8>, String> {
+        self.token = self.token.wrapping_add(1);
+        cmd.token = self.token;
+        self.dev.bulk_out(&cmd.to_bytes()).await?;
+        let mut read = Vec::new();
+        if cmd.transfer_len > 0 {
+            if cmd.is_in() {
+                read = self.dev.bulk_in(cmd.transfer_len).await?;
+                if read.len() != cmd.transfer_len as usize {
+                    return Err(format!(
+                        "Read {} bytes, expected {}",
+                        read.len(),
+                        cmd.transfer_len
+                    ));
+                }
+            } else {
+                self.dev.bulk_out(data).await?;
+            }
+        }
+        if cmd.is_in() {
+            self.dev.bulk_out(&[]).await?;
+        } else {
+            self.dev.bulk_in(0).await?;
+        }
+        Ok(read)
+    }
+
+    /// Clear a failed command, returning the device's status for it, if
+    /// the transport can ask.
+    async fn reset(&mut self) -> Result<Option<CommandStatus>, String> {
+        let Some(status) = self.dev.control(IF_CMD_STATUS, 16).await else {
+            return Ok(None);
+        };
+        let status = CommandStatus::from_bytes(&status?)?;
+        if let Some(reset) = self.dev.control(IF_RESET, 0).await {
+            reset?;
+        }
+        Ok(Some(status))
+    }
+
+    /// Send `cmd`, resetting the interface and retrying while the device
+    /// reports it failed.
+    async fn command(&mut self, what: &str, cmd: Command, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut attempt = 0;
+        loop {
+            let e = match self.exchange(cmd.clone(), data).await {
+                Ok(read) => return Ok(read),
+                Err(e) => e,
+            };
+            let status = match self.reset().await {
+                Ok(Some(s)) if s.token == self.token && s.status != STATUS_OK => s.status,
+                _ => return Err(format!("{what} failed: {e}")),
+            };
+            if attempt == self.retries {
+                return Err(format!("{what} failed: {}", status_name(status)));
+            }
+            attempt += 1;
+            self.report.retries += 1;
+            self.report
+                .log
+                .push(format!("{what}: {}, retrying", status_name(status)));
+        }
+    }
+}
+
+/// Carry out `plan` over `dev`, reporting bytes written through `progress`.
+pub(crate) async fn run<T: PicobootTransport>(
+    dev: &mut T,
+    plan: &FlashPlan,
+    options: &PicobootOptions,
+    progress: &mut dyn FnMut(u32, u32),
+) -> Result<PicobootReport, String> {
+    if plan.variant.family() != Family::Rp2350 {
+        return Err(format!(
+            "PICOBOOT flashing is for RP2350, not {}",
+            plan.variant
+        ));
+    }
+
+    let mut pb = Picoboot {
+        dev,
+        token: 0,
+        retries: options.retries.unwrap_or(DEFAULT_RETRIES),
+        report: PicobootReport {
+            mcu: plan.variant.to_string(),
+            ..Default::default()
+        },
+    };
+    // Clear anything a previous session left behind.
+    if let Some(Ok(status)) = pb.reset().await.transpose()
+        && status.status != STATUS_OK
+    {
+        pb.report.log.push(format!(
+            "Cleared {} from an earlier command",
+            status_name(status.status)
+        ));
+    }
+
+    pb.command(
+        "Taking exclusive access",
+        Command::exclusive_access(EXCLUSIVE),
+        &[],
+    )
+    .await?;
+    pb.command("Exiting XIP", Command::new(CMD_EXIT_XIP, &[], 0), &[])
+        .await?;
+
+    // One erase per run of contiguous sectors.
+    let mut ranges: Vec<(u32, u32)> = Vec::new();
+    for sector in &plan.erase {
+        match ranges.last_mut() {
+            Some((address, size)) if *address + *size == sector.address => *size += sector.size,
+            _ => ranges.push((sector.address, sector.size)),
+        }
+    }
+    for (address, size) in ranges {
+        let what = format!("Erasing {size} bytes at {address:#010x}");
+        pb.command(
+            &what,
+            Command::new(CMD_FLASH_ERASE, &[address, size], 0),
+            &[],
+        )
+        .await?;
+        pb.report.log.push(what);
+        pb.report.ranges_erased += 1;
+        pb.report.bytes_erased += size;
+    }
+
+    let verify = options.verify.unwrap_or(true);
+    let total: u32 = plan.writes.iter().map(|w| w.data.len() as u32).sum();
+    progress(0, total);
+    for w in &plan.writes {
+        let len = w.data.len() as u32;
+        let what = format!("Writing {len} bytes at {:#010x}", w.address);
+        pb.command(
+            &what,
+            Command::new(CMD_WRITE, &[w.address, len], len),
+            &w.data,
+        )
+        .await?;
+        pb.report.chunks_written += 1;
+        pb.report.bytes_written += len;
+
+        if verify {
+            let what = format!("Reading back {len} bytes at {:#010x}", w.address);
+            let read = pb
+                .command(&what, Command::new(CMD_READ, &[w.address, len], len), &[])
+                .await?;
+            if let Some(i) = read.iter().zip(&w.data).position(|(a, b)| a != b) {
+                return Err(format!(
+                    "Verify failed at {:#010x}: wrote {:#04x}, read {:#04x}",
+                    w.address + i as u32,
+                    w.data[i],
+                    read[i]
+                ));
+            }
+            pb.report.bytes_verified += len;
+        }
+        progress(pb.report.bytes_written, total);
+    }
+
+    if options.reboot.unwrap_or(true) {
+        // The device may be gone before the acknowledgement arrives.
+        let reboot = Command::new(CMD_REBOOT2, &[REBOOT_NORMAL, REBOOT_DELAY_MS, 0, 0], 0);
+        if let Err(e) = pb.exchange(reboot, &[]).await {
+            pb.report
+                .log
+                .push(format!("No acknowledgement of reboot: {e}"));
+        }
+        pb.report.rebooted = true;
+        pb.report.log.push("Rebooting".to_string());
+    } else {
+        pb.command(
+            "Releasing exclusive access",
+            Command::exclusive_access(0),
+            &[],
+        )
+        .await?;
+    }
+    Ok(pb.report)
+}
+
+/// A [`PicobootTransport`] over JS callbacks.
+struct JsPicobootTransport {
+    /// JS `async (data: Uint8Array) => void`.
+    bulk_out_cb: js_sys::Function,
+    /// JS `async (length: number) => Uint8Array`.
+    bulk_in_cb: js_sys::Function,
+    /// JS `async (request, value, dataOrLength) => Uint8Array | undefined`,
+    /// if given.
+    control_cb: Option<js_sys::Function>,
+}
+
+async fn call(cb: &js_sys::Function, args: &[JsValue], what: &str) -> Result<JsValue, String> {
+    let args: js_sys::Array = args.iter().collect();
+    let promise = cb
+        .apply(&JsValue::NULL, &args)
+        .map_err(|e| format!("{what} callback threw: {e:?}"))?;
+    wasm_bindgen_futures::JsFuture::from(js_sys::Promise::resolve(&promise))
+        .await
+        .map_err(|e| format!("{what} failed: {e:?}"))
+}
+
+impl PicobootTransport for JsPicobootTransport {
+    async fn bulk_out(&mut self, data: &[u8]) -> Result<(), String> {
+        let data = js_sys::Uint8Array::from(data);
+        call(&self.bulk_out_cb, &[data.into()], "bulk OUT")
+            .await
+            .map(|_| ())
+    }
+
+    async fn bulk_in(&mut self, length: u32) -> Result<Vec<u8>, String> {
+        let resolved = call(
+            &self.bulk_in_cb,
+            &[JsValue::from_f64(length as f64)],
+            "bulk IN",
+        )
+        .await?;
+        Ok(js_sys::Uint8Array::new(&resolved).to_vec())
+    }
+
+    async fn control(&mut self, request: u8, length: u16) -> Option<Result<Vec<u8>, String>> {
+        let cb = self.control_cb.as_ref()?;
+        let arg = if length == 0 {
+            js_sys::Uint8Array::new_with_length(0).into()
+        } else {
+            JsValue::from_f64(length as f64)
+        };
+        let args = [
+            JsValue::from_f64(request as f64),
+            JsValue::from_f64(0.0),
+            arg,
+        ];
+        Some(
+            call(cb, &args, "control")
+                .await
+                .map(|resolved| match length {
+                    0 => Vec::new(),
+                    _ => js_sys::Uint8Array::new(&resolved).to_vec(),
+                }),
+        )
+    }
+}
+
+/// Flash an RP2350 in BOOTSEL mode according to `plan`.
+///
+/// `bulk_out_cb` is a JS `async (data: Uint8Array) => void` writing to the
+/// PICOBOOT interface's bulk OUT endpoint, and `bulk_in_cb` a JS
+/// `async (length: number) => Uint8Array` reading from its bulk IN
+/// endpoint. Either should clear a halted endpoint before rejecting.
+/// `control_cb`, if given, makes vendor control transfers to the
+/// interface, as for [`dfu_flash`](crate::dfu_flash); without it a failed
+/// command cannot be identified or retried. `progress_cb`, if given, is
+/// called with bytes written so far and the total.
+///
+/// `options` may be omitted, or an object with any of `verify` (read back
+/// each write, default true), `reboot` (default true) and `retries`
+/// (default 2).
+#[wasm_bindgen]
+pub async fn picoboot_flash(
+    plan: &FlashPlan,
+    bulk_out_cb: js_sys::Function,
+    bulk_in_cb: js_sys::Function,
+    control_cb: Option<js_sys::Function>,
+    progress_cb: Option<js_sys::Function>,
+    options: JsValue,
+) -> Result<PicobootReport, JsValue> {
+    let options: PicobootOptions = crate::options(options)?;
+    let mut transport = JsPicobootTransport {
+        bulk_out_cb,
+        bulk_in_cb,
+        control_cb,
+    };
+    let mut progress = |done: u32, total: u32| {
+        if let Some(cb) = &progress_cb {
+            let _ = cb.call2(
+                &JsValue::NULL,
+                &JsValue::from_f64(done as f64),
+                &JsValue::from_f64(total as f64),
+            );
+        }
+    };
+    run(&mut transport, plan, &options, &mut progress)
+        .await
+        .map_err(|e| JsValue::from_str(&e))
+}
@@ -0,0 +1,357 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! A simulated RP2350 PICOBOOT interface.
+//!
+//! Behaves as the bootrom does towards the host: each command is checked
+//! for length, alignment and address range, runs through its data phase and
+//! is acknowledged, and a failure halts the endpoints until `IF_RESET`.
+//! Flash is NOR, so a write only clears bits; writing over data that was not
+//! erased leaves the AND of the two, which a read-back verify catches.
+//!
+//! Test only: the tests below run [`FlashPlan`](crate::flash_plan::FlashPlan)s
+//! through [`run`](crate::picoboot::run) against it, so the PICOBOOT
+//! sequence can be checked without hardware.
+
+use onerom_config::mcu::Variant;
+
+use crate::flash_plan::{ERASED, Geometry};
+use crate::picoboot::{
+    CMD_EXCLUSIVE_ACCESS, CMD_EXIT_XIP, CMD_FLASH_ERASE, CMD_READ, CMD_REBOOT2, CMD_WRITE, Command,
+    CommandStatus, IF_CMD_STATUS, IF_RESET, PicobootTransport, STATUS_OK, status_name,
+};
+use crate::test_util::SimulatedFlash;
+
+const UNKNOWN_CMD: u32 = 1;
+const INVALID_CMD_LENGTH: u32 = 2;
+const INVALID_TRANSFER_LENGTH: u32 = 3;
+const INVALID_ADDRESS: u32 = 4;
+const BAD_ALIGNMENT: u32 = 5;
+const UNKNOWN_ERROR: u32 = 8;
+const INVALID_STATE: u32 = 9;
+
+/// Erase and write alignment.
+const SECTOR_SIZE: u32 = 4096;
+const PAGE_SIZE: u32 = 256;
+
+/// Where the device is in a command.
+enum Phase {
+    /// Waiting for a command.
+    Idle,
+    /// Waiting for a write's data.
+    DataOut(Command),
+    /// Holding a read's data.
+    DataIn(Vec<u8>),
+    /// Waiting for the host to read the acknowledgement.
+    AckIn,
+    /// Waiting for the host to send the acknowledgement.
+    AckOut,
+}
+
+/// A simulated PICOBOOT interface with erased flash.
+pub(crate) struct SimulatedPicobootDevice {
+    geometry: Geometry,
+    flash: Vec<u8>,
+    phase: Phase,
+    status: CommandStatus,
+    halted: bool,
+    xip: bool,
+    /// Commands seen so far.
+    commands: u32,
+    /// Command numbers that fail once with UNKNOWN_ERROR.
+    faults: Vec<u32>,
+    reboot_pending: bool,
+    /// Set once the device has rebooted.
+    rebooted: bool,
+}
+
+impl SimulatedPicobootDevice {
+    pub(crate) fn new(variant: Variant, faults: Vec<u32>) -> Self {
+        let geometry = Geometry::of(variant);
+        Self {
+            flash: vec![ERASED; geometry.size as usize],
+            geometry,
+            phase: Phase::Idle,
+            status: CommandStatus {
+                token: 0,
+                status: STATUS_OK,
+                id: 0,
+                in_progress: false,
+            },
+            halted: false,
+            xip: true,
+            commands: 0,
+            faults,
+            reboot_pending: false,
+            rebooted: false,
+        }
+    }
+
+    /// Offset into flash of `address..address + len`, checking alignment
+    /// and range.
+    fn range(&self, address: u32, len: u32, align: u32) -> Result<usize, u32> {
+        if !address.is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(BAD_ALIGNMENT);
+        }
+        let offset = address
+            .checked_sub(self.geometry.base)
+            .ok_or(INVALID_ADDRESS)?;
+        match offset.checked_add(len) {
+            Some(end) if end <= self.geometry.size => Ok(offset as usize),
+            _ => Err(INVALID_ADDRESS),
+        }
+    }
+
+    fn fail(&mut self, status: u32) -> String {
+        self.status.status = status;
+        self.status.in_progress = false;
+        self.halted = true;
+        self.phase = Phase::Idle;
+        format!("Endpoint halted: {}", status_name(status))
+    }
+
+    fn complete(&mut self) {
+        self.status.in_progress = false;
+        self.phase = Phase::Idle;
+        if self.reboot_pending {
+            self.rebooted = true;
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.rebooted {
+            Err("Device disconnected".to_string())
+        } else if self.halted {
+            Err("Endpoint halted".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start `cmd`, returning the phase it moves to or the failure status.
+    fn start(&mut self, cmd: &Command) -> Result<Phase, u32> {
+        let args = |size: u8, transfer_len: u32| {
+            if cmd.size != size {
+                Err(INVALID_CMD_LENGTH)
+            } else if cmd.transfer_len != transfer_len {
+                Err(INVALID_TRANSFER_LENGTH)
+            } else {
+                Ok(())
+            }
+        };
+        match cmd.id {
+            CMD_EXCLUSIVE_ACCESS => {
+                args(1, 0)?;
+                Ok(Phase::AckIn)
+            }
+            CMD_EXIT_XIP => {
+                args(0, 0)?;
+                self.xip = false;
+                Ok(Phase::AckIn)
+            }
+            CMD_FLASH_ERASE => {
+                args(8, 0)?;
+                let (address, len) = (cmd.arg(0), cmd.arg(1));
+                let offset = self.range(address, len, SECTOR_SIZE)?;
+                self.flash[offset..offset + len as usize].fill(ERASED);
+                Ok(Phase::AckIn)
+            }
+            CMD_WRITE => {
+                args(8, cmd.arg(1))?;
+                if self.xip {
+                    return Err(INVALID_STATE);
+                }
+                self.range(cmd.arg(0), cmd.arg(1), PAGE_SIZE)?;
+                Ok(Phase::DataOut(cmd.clone()))
+            }
+            CMD_READ => {
+                args(8, cmd.arg(1))?;
+                let offset = self.range(cmd.arg(0), cmd.arg(1), 1)?;
+                Ok(Phase::DataIn(
+                    self.flash[offset..offset + cmd.arg(1) as usize].to_vec(),
+                ))
+            }
+            CMD_REBOOT2 => {
+                args(16, 0)?;
+                self.reboot_pending = true;
+                Ok(Phase::AckIn)
+            }
+            _ => Err(UNKNOWN_CMD),
+        }
+    }
+}
+
+impl PicobootTransport for SimulatedPicobootDevice {
+    async fn bulk_out(&mut self, data: &[u8]) -> Result<(), String> {
+        self.check()?;
+        match std::mem::replace(&mut self.phase, Phase::Idle) {
+            Phase::Idle => {
+                let Some(cmd) = Command::from_bytes(data) else {
+                    return Err(self.fail(UNKNOWN_CMD));
+                };
+                self.status = CommandStatus {
+                    token: cmd.token,
+                    status: STATUS_OK,
+                    id: cmd.id,
+                    in_progress: true,
+                };
+                let number = self.commands;
+                self.commands += 1;
+                if let Some(i) = self.faults.iter().position(|&f| f == number) {
+                    self.faults.remove(i);
+                    return Err(self.fail(UNKNOWN_ERROR));
+                }
+                match self.start(&cmd) {
+                    Ok(phase) => {
+                        self.phase = phase;
+                        Ok(())
+                    }
+                    Err(status) => Err(self.fail(status)),
+                }
+            }
+            Phase::DataOut(cmd) => {
+                if data.len() != cmd.transfer_len as usize {
+                    return Err(self.fail(INVALID_TRANSFER_LENGTH));
+                }
+                let offset = (cmd.arg(0) - self.geometry.base) as usize;
+                for (byte, new) in self.flash[offset..offset + data.len()].iter_mut().zip(data) {
+                    *byte &= new;
+                }
+                self.phase = Phase::AckIn;
+                Ok(())
+            }
+            Phase::AckOut if data.is_empty() => {
+                self.complete();
+                Ok(())
+            }
+            _ => Err(self.fail(INVALID_STATE)),
+        }
+    }
+
+    async fn bulk_in(&mut self, length: u32) -> Result<Vec<u8>, String> {
+        self.check()?;
+        match std::mem::replace(&mut self.phase, Phase::Idle) {
+            Phase::DataIn(mut data) => {
+                data.truncate(length as usize);
+                self.phase = Phase::AckOut;
+                Ok(data)
+            }
+            Phase::AckIn => {
+                self.complete();
+                Ok(Vec::new())
+            }
+            _ => Err(self.fail(INVALID_STATE)),
+        }
+    }
+
+    async fn control(&mut self, request: u8, length: u16) -> Option<Result<Vec<u8>, String>> {
+        if self.rebooted {
+            return Some(Err("Device disconnected".to_string()));
+        }
+        Some(match request {
+            IF_CMD_STATUS => {
+                let mut bytes = self.status.to_bytes();
+                bytes.truncate(length as usize);
+                Ok(bytes)
+            }
+            IF_RESET => {
+                self.halted = false;
+                self.phase = Phase::Idle;
+                self.status.status = STATUS_OK;
+                Ok(Vec::new())
+            }
+            _ => Err("Control transfer stalled".to_string()),
+        })
+    }
+}
+
+impl SimulatedFlash for SimulatedPicobootDevice {
+    fn flash(&self) -> (u32, &[u8]) {
+        (self.geometry.base, &self.flash)
+    }
+}
+
+mod tests {
+    use super::*;
+    use crate::flash_plan::FlashPlan;
+    use crate::picoboot::{PicobootOptions, PicobootReport, run};
+    use crate::test_util::{holds, run_ready};
+
+    fn test_plan() -> FlashPlan {
+        crate::test_util::test_plan(Variant::RP2350)
+    }
+
+    fn flash(
+        device: &mut SimulatedPicobootDevice,
+        plan: &FlashPlan,
+        options: &PicobootOptions,
+    ) -> Result<PicobootReport, String> {
+        run_ready(run(device, plan, options, &mut |_, _| {}))
+    }
+
+    #[test]
+    fn flashes_plan() {
+        let plan = test_plan();
+        let mut device = SimulatedPicobootDevice::new(plan.variant, vec![]);
+        let report = flash(&mut device, &plan, &PicobootOptions::default()).unwrap();
+        assert!(holds(&device, &plan));
+        assert_eq!(report.ranges_erased, 2);
+        assert_eq!(report.bytes_verified, report.bytes_written);
+        assert_eq!(report.retries, 0);
+        assert!(report.rebooted && device.rebooted);
+    }
+
+    #[test]
+    fn retries_rejected_commands() {
+        let plan = test_plan();
+        let mut device = SimulatedPicobootDevice::new(plan.variant, vec![0, 2, 5]);
+        let report = flash(&mut device, &plan, &PicobootOptions::default()).unwrap();
+        assert!(holds(&device, &plan));
+        assert_eq!(report.retries, 3);
+    }
+
+    #[test]
+    fn gives_up_after_retries() {
+        let plan = test_plan();
+        let mut device = SimulatedPicobootDevice::new(plan.variant, vec![4, 5, 6]);
+        let e = flash(&mut device, &plan, &PicobootOptions::default()).unwrap_err();
+        assert!(e.contains("UNKNOWN_ERROR"), "{e}");
+        assert!(!device.rebooted);
+    }
+
+    #[test]
+    fn recovers_halted_endpoints() {
+        let plan = test_plan();
+        let mut device = SimulatedPicobootDevice::new(plan.variant, vec![]);
+        device.fail(INVALID_STATE);
+        let report = flash(&mut device, &plan, &PicobootOptions::default()).unwrap();
+        assert!(holds(&device, &plan));
+        assert!(report.log[0].contains("INVALID_STATE"), "{:?}", report.log);
+    }
+
+    #[test]
+    fn verify_catches_unerased_flash() {
+        let mut plan = test_plan();
+        plan.erase.clear();
+        let mut device = SimulatedPicobootDevice::new(plan.variant, vec![]);
+        device.flash[1] = 0;
+        let e = flash(&mut device, &plan, &PicobootOptions::default()).unwrap_err();
+        assert!(e.starts_with("Verify failed at 0x10000001"), "{e}");
+    }
+
+    #[test]
+    fn skips_verify_and_reboot() {
+        let plan = test_plan();
+        let mut device = SimulatedPicobootDevice::new(plan.variant, vec![]);
+        let options = PicobootOptions {
+            verify: Some(false),
+            reboot: Some(false),
+            ..Default::default()
+        };
+        let report = flash(&mut device, &plan, &options).unwrap();
+        assert!(holds(&device, &plan));
+        assert_eq!(report.bytes_verified, 0);
+        assert!(!report.rebooted && !device.rebooted);
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! SVG pinout diagrams.
+//!
+//! Draws a chip type's DIP package from its [`ChipTypeInfo`], so every chip
+//! type gets a diagram without drawing one by hand. Optionally the chip is
+//! drawn seated in a board's socket, with each pin's GPIO and address/data
+//! bit from [`board_pin_map`](crate::board_pin_map), and with the CS
+//! polarity a config gives the chip.
+//!
+//! Active-low signals are overlined; ignored CS lines are greyed out.
+
+use std::fmt::Write;
+
+use serde::Deserialize;
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::hw::Board;
+
+use crate::board_pins::{BoardPinMapping, pin_mapping};
+use crate::{ChipTypeInfo, chip_type_info, custom_chips};
+
+/// Pin pitch.
+const PITCH: i32 = 20;
+const BODY_WIDTH: i32 = 140;
+/// Pin stub length, outside the body.
+const STUB: i32 = 14;
+/// Room for pin numbers, beyond the stubs.
+const NUMBER_WIDTH: i32 = 24;
+/// Room for the board annotation, beyond the pin numbers.
+const BOARD_WIDTH: i32 = 120;
+const MARGIN: i32 = 10;
+const TITLE_HEIGHT: i32 = 24;
+
+/// Options for [`chip_pinout_svg`]. All optional.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct PinoutOptions {
+    /// Board to seat the chip in.
+    board: Option<String>,
+    /// Config to take the chip's CS polarity from.
+    config_json: Option<String>,
+    /// Set in the config, default 0.
+    set_id: usize,
+    /// Chip within the set, default 0.
+    chip_idx: usize,
+}
+
+/// Polarity a control line is drawn with.
+#[derive(Clone, Copy, PartialEq)]
+enum Polarity {
+    Low,
+    High,
+    Ignore,
+    /// Configurable, with no config to say which.
+    Unknown,
+}
+
+/// Render chip type `name` as an SVG DIP diagram.
+///
+/// `options` may be omitted, or an object with any of:
+/// - `board`: draw the chip in this board's socket, annotated with GPIOs and
+///   address/data bits.
+/// - `config_json`, `set_id`, `chip_idx`: take CS polarity from this chip
+///   in the config (set and chip default to 0). The chip must be of type
+///   `name`.
+#[wasm_bindgen]
+pub fn chip_pinout_svg(name: String, options: JsValue) -> Result<String, JsValue> {
+    let options: PinoutOptions = crate::options(options)?;
+
+    let info = chip_type_info(name)?;
+
+    // A custom chip type sits in the socket as its base type.
+    let mapping = match &options.board {
+        Some(board) => {
+            let board = Board::try_from_str(board)
+                .ok_or_else(|| JsValue::from_str(&format!("Unknown board: {board}")))?;
+            let chip_type = custom_chips::socket_chip_type(board, &info.name)
+                .map_err(|e| JsValue::from_str(&e))?;
+            Some(pin_mapping(board, chip_type).map_err(|e| JsValue::from_str(&e))?)
+        }
+        None => None,
+    };
+
+    let config_cs = match &options.config_json {
+        Some(json) => Some(
+            config_chip(json, options.set_id, options.chip_idx, &info.name)
+                .map_err(|e| JsValue::from_str(&e))?,
+        ),
+        None => None,
+    };
+    let polarity = |line: &str| {
+        let fixed = custom_chips::fixed_level(&info.name, line);
+        let configured = config_cs
+            .as_ref()
+            .and_then(|chip| chip.get(line))
+            .and_then(Value::as_str);
+        match (configured, fixed) {
+            (Some("ignore"), _) => Polarity::Ignore,
+            (_, Some(true)) | (Some("active_high"), None) => Polarity::High,
+            (_, Some(false)) => Polarity::Low,
+            (Some("active_low"), None) => Polarity::Low,
+            _ => Polarity::Unknown,
+        }
+    };
+
+    Ok(render(&info, mapping.as_ref(), polarity))
+}
+
+/// The chip object at `set_id`/`chip_idx` in a config, checked to be of
+/// the chip type named `name`.
+fn config_chip(json: &str, set_id: usize, chip_idx: usize, name: &str) -> Result<Value, String> {
+    let config: Value =
+        serde_json::from_str(json).map_err(|e| format!("Error parsing config: {e}"))?;
+    let chip = config
+        .get("chip_sets")
+        .or_else(|| config.get("rom_sets"))
+        .and_then(|sets| sets.get(set_id))
+        .and_then(|set| set.get("chips").or_else(|| set.get("roms")))
+        .and_then(|chips| chips.get(chip_idx))
+        .ok_or_else(|| format!("Config has no chip {chip_idx} in set {set_id}"))?;
+
+    let configured = chip
+        .get("type")
+        .and_then(Value::as_str)
+        .and_then(custom_chips::chip_type_name);
+    if configured.as_deref() != Some(name) {
+        return Err(format!("Set {set_id}, chip {chip_idx} is not a {name}"));
+    }
+    Ok(chip.clone())
+}
+
+/// A pin's label: its signal names, and whether each is a control line.
+struct PinLabel {
+    names: Vec<(String, Option<String>)>,
+}
+
+fn render(
+    info: &ChipTypeInfo,
+    mapping: Option<&BoardPinMapping>,
+    polarity: impl Fn(&str) -> Polarity,
+) -> String {
+    let chip_pins = info.chip_pins as i32;
+    let half = chip_pins / 2;
+
+    // Rows are counted in socket terms when there is a board, so the chip
+    // sits where it would physically.
+    let offset = mapping.map_or(0, |m| m.pin_offset as i32);
+    let socket_half = mapping.map_or(half, |m| {
+        Board::try_from_str(&m.board).map_or(half, |b| b.chip_pins() as i32 / 2)
+    });
+    let first_row = offset.min(0);
+    let last_row = (half - 1 + offset).max(socket_half - 1);
+    let rows = last_row - first_row + 1;
+
+    let side = STUB + NUMBER_WIDTH + if mapping.is_some() { BOARD_WIDTH } else { 0 };
+    let width = 2 * (MARGIN + side) + BODY_WIDTH;
+    let height = 2 * MARGIN + TITLE_HEIGHT + rows * PITCH;
+    let body_left = MARGIN + side;
+    let body_right = body_left + BODY_WIDTH;
+    let row_y = |row: i32| MARGIN + TITLE_HEIGHT + (row - first_row) * PITCH + PITCH / 2;
+
+    let mut svg = String::new();
+    let _ = write!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}" font-family="monospace" font-size="11">"#
+    );
+    let title = match mapping {
+        Some(m) => format!("{} in {}", info.name, m.board),
+        None => format!("{} (DIP-{})", info.name, info.chip_pins),
+    };
+    let _ = write!(
+        svg,
+        r#"<text x="{}" y="{}" text-anchor="middle" font-size="13" font-weight="bold">{}</text>"#,
+        width / 2,
+        MARGIN + 14,
+        escape(&title)
+    );
+
+    // Socket outline, where the board's socket differs from the chip.
+    if mapping.is_some() && (socket_half != half || offset != 0) {
+        let top = row_y(0) - PITCH / 2;
+        let _ = write!(
+            svg,
+            r##"<rect x="{}" y="{top}" width="{}" height="{}" fill="none" stroke="#888" stroke-dasharray="4 3"/>"##,
+            body_left - 6,
+            BODY_WIDTH + 12,
+            socket_half * PITCH
+        );
+    }
+
+    // Body, with its notch.
+    let body_top = row_y(offset) - PITCH / 2;
+    let notch_x = body_left + BODY_WIDTH / 2;
+    let _ = write!(
+        svg,
+        r##"<rect x="{body_left}" y="{body_top}" width="{BODY_WIDTH}" height="{}" fill="#f4f4f4" stroke="#000"/>"##,
+        half * PITCH
+    );
+    let _ = write!(
+        svg,
+        r##"<path d="M {} {body_top} A 8 8 0 0 0 {} {body_top}" fill="#fff" stroke="#000"/>"##,
+        notch_x - 8,
+        notch_x + 8
+    );
+
+    for pin in 1..=chip_pins {
+        let left = pin <= half;
+        let row = if left { pin - 1 } else { chip_pins - pin } + offset;
+        let y = row_y(row);
+        let label = pin_label(info, pin as u8);
+
+        // Stub and pin number.
+        let (stub_x, number_x, anchor_out) = if left {
+            (body_left - STUB, body_left - STUB - 3, "end")
+        } else {
+            (body_right, body_right + STUB + 3, "start")
+        };
+        let _ = write!(
+            svg,
+            r##"<rect x="{stub_x}" y="{}" width="{STUB}" height="6" fill="#bbb" stroke="#000"/>"##,
+            y - 3
+        );
+        let _ = write!(
+            svg,
+            r#"<text x="{number_x}" y="{}" text-anchor="{anchor_out}">{pin}</text>"#,
+            y + 4
+        );
+
+        // Signal names, inside the body.
+        let (name_x, anchor_in) = if left {
+            (body_left + 4, "start")
+        } else {
+            (body_right - 4, "end")
+        };
+        let _ = write!(
+            svg,
+            r#"<text x="{name_x}" y="{}" text-anchor="{anchor_in}">"#,
+            y + 4
+        );
+        for (i, (name, control)) in label.names.iter().enumerate() {
+            if i > 0 {
+                svg.push('/');
+            }
+            let pol = control.as_deref().map_or(Polarity::High, &polarity);
+            let style = match pol {
+                Polarity::Low => r#" text-decoration="overline""#,
+                Polarity::Ignore => r##" fill="#999""##,
+                Polarity::High | Polarity::Unknown => "",
+            };
+            let mark = if pol == Polarity::Unknown { "?" } else { "" };
+            let _ = write!(svg, "<tspan{style}>{}{mark}</tspan>", escape(name));
+        }
+        svg.push_str("</text>");
+
+        // Board annotation, outside the pin number.
+        if let Some(m) = mapping
+            && let Some(p) = m.pins.iter().find(|p| p.chip_pin == pin as u8)
+            && !p.gpios.is_empty()
+        {
+            let gpios: Vec<String> = p.gpios.iter().map(|g| g.to_string()).collect();
+            let mut note = format!("GPIO {}", gpios.join("/"));
+            if let (Some(bus), Some(bit)) = (&p.bus, p.bit) {
+                let bus = if bus == "data" { "D" } else { "A" };
+                let _ = write!(note, " {bus}[{bit}]");
+            }
+            let note_x = if left {
+                number_x - NUMBER_WIDTH
+            } else {
+                number_x + NUMBER_WIDTH
+            };
+            let _ = write!(
+                svg,
+                r##"<text x="{note_x}" y="{}" text-anchor="{anchor_out}" fill="#06c">{}</text>"##,
+                y + 4,
+                escape(&note)
+            );
+        }
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Every signal on chip pin `pin`, with the control line each is, if any.
+fn pin_label(info: &ChipTypeInfo, pin: u8) -> PinLabel {
+    let mut names: Vec<(String, Option<String>)> = info
+        .pin_signals(pin)
+        .into_iter()
+        .map(|name| {
+            let control = info
+                .control_lines
+                .iter()
+                .find(|c| c.pin == pin && c.name.eq_ignore_ascii_case(&name))
+                .map(|c| c.name.clone());
+            (name, control)
+        })
+        .collect();
+    if names.is_empty() {
+        names.push(("NC".to_string(), None));
+    }
+    PinLabel { names }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
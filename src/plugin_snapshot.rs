@@ -0,0 +1,378 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Offline plugin catalogue snapshots.
+//!
+//! A [`PluginCatalog`] keeps the manifests it was built from, and a snapshot
+//! is just those manifests, plus any plugin binaries added to it, in one
+//! blob. Restoring one replays the manifests through `onerom-app` exactly as
+//! a live fetch would, so an offline catalogue behaves the same as the one
+//! it was taken from.
+//!
+//! The blob is [`SNAPSHOT_MAGIC`], a little-endian `u32` header length, a
+//! JSON header, then each manifest and binary the header lists, in order.
+//! The header records each file's URL, length and SHA-256, and when the
+//! manifests were fetched:
+//!
+//! ```json
+//! {
+//!   "fetched_at": 1760745600000,
+//!   "manifests": [{ "url": "...", "length": 1234, "sha256": "..." }],
+//!   "binaries": [{ "url": "...", "length": 5678, "sha256": "..." }]
+//! }
+//! ```
+
+use std::cell::{Cell, RefCell};
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use onerom_app::LocalPluginFetch;
+
+use crate::digest::sha256_hex;
+use crate::{JsFetch, PluginCatalog, WasmPluginFailure, WasmPluginVerification};
+
+/// Identifies a plugin catalogue snapshot, and its format version.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"ORPLUGS1";
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotHeader {
+    fetched_at: f64,
+    manifests: Vec<SnapshotFile>,
+    binaries: Vec<SnapshotFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotFile {
+    url: String,
+    length: u32,
+    sha256: String,
+}
+
+/// A decoded snapshot.
+struct Snapshot {
+    fetched_at: f64,
+    manifests: Vec<(String, Vec<u8>)>,
+    binaries: Vec<(String, Vec<u8>)>,
+}
+
+impl Snapshot {
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let files = |files: &[(String, Vec<u8>)]| {
+            files
+                .iter()
+                .map(|(url, data)| SnapshotFile {
+                    url: url.clone(),
+                    length: data.len() as u32,
+                    sha256: sha256_hex(data),
+                })
+                .collect()
+        };
+        let header = serde_json::to_vec(&SnapshotHeader {
+            fetched_at: self.fetched_at,
+            manifests: files(&self.manifests),
+            binaries: files(&self.binaries),
+        })
+        .map_err(|e| format!("Error writing snapshot: {e}"))?;
+
+        let mut blob = SNAPSHOT_MAGIC.to_vec();
+        blob.extend((header.len() as u32).to_le_bytes());
+        blob.extend(header);
+        for (_, data) in self.manifests.iter().chain(&self.binaries) {
+            blob.extend(data);
+        }
+        Ok(blob)
+    }
+
+    fn decode(blob: &[u8]) -> Result<Self, String> {
+        let rest = blob
+            .strip_prefix(SNAPSHOT_MAGIC)
+            .ok_or("Not a plugin catalogue snapshot")?;
+        let (len, rest) = rest
+            .split_first_chunk::<4>()
+            .ok_or("Snapshot is truncated")?;
+        let (header, mut rest) = rest
+            .split_at_checked(u32::from_le_bytes(*len) as usize)
+            .ok_or("Snapshot is truncated")?;
+        let header: SnapshotHeader =
+            serde_json::from_slice(header).map_err(|e| format!("Invalid snapshot header: {e}"))?;
+
+        let mut files = |list: Vec<SnapshotFile>| -> Result<Vec<(String, Vec<u8>)>, String> {
+            list.into_iter()
+                .map(|f| {
+                    let (data, after) = rest
+                        .split_at_checked(f.length as usize)
+                        .ok_or_else(|| format!("Snapshot is truncated at {}", f.url))?;
+                    rest = after;
+                    if sha256_hex(data) != f.sha256.to_ascii_lowercase() {
+                        return Err(format!("Snapshot copy of {} is corrupt", f.url));
+                    }
+                    Ok((f.url, data.to_vec()))
+                })
+                .collect()
+        };
+        let manifests = files(header.manifests)?;
+        let binaries = files(header.binaries)?;
+        Ok(Self {
+            fetched_at: header.fetched_at,
+            manifests,
+            binaries,
+        })
+    }
+}
+
+/// Serves fetches from a snapshot's manifests.
+struct SnapshotFetch<'a>(&'a [(String, Vec<u8>)]);
+
+impl LocalPluginFetch for SnapshotFetch<'_> {
+    type Error = String;
+
+    async fn fetch(&self, source: &str) -> Result<Vec<u8>, Self::Error> {
+        self.0
+            .iter()
+            .find(|(url, _)| url == source)
+            .map(|(_, data)| data.clone())
+            .ok_or_else(|| "not in the snapshot".to_string())
+    }
+}
+
+/// Fetches live, falling back to a snapshot for anything unreachable.
+struct Fallback<'a> {
+    live: &'a JsFetch,
+    snapshot: SnapshotFetch<'a>,
+    fell_back: Cell<bool>,
+}
+
+impl LocalPluginFetch for Fallback<'_> {
+    type Error = String;
+
+    async fn fetch(&self, source: &str) -> Result<Vec<u8>, Self::Error> {
+        match self.live.fetch(source).await {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                let data = self.snapshot.fetch(source).await.map_err(|_| e)?;
+                self.fell_back.set(true);
+                Ok(data)
+            }
+        }
+    }
+}
+
+/// Records what a fetch returns.
+struct Recording<'a, F> {
+    inner: &'a F,
+    fetched: RefCell<Vec<(String, Vec<u8>)>>,
+}
+
+impl<F: LocalPluginFetch<Error = String>> LocalPluginFetch for Recording<'_, F> {
+    type Error = String;
+
+    async fn fetch(&self, source: &str) -> Result<Vec<u8>, Self::Error> {
+        let data = self.inner.fetch(source).await?;
+        self.fetched
+            .borrow_mut()
+            .push((source.to_string(), data.clone()));
+        Ok(data)
+    }
+}
+
+impl PluginCatalog {
+    /// Fetch the catalogue and every plugin's releases through `fetch`,
+    /// keeping the manifests for snapshots.
+    pub(crate) async fn load<F: LocalPluginFetch<Error = String>>(
+        fetch: &F,
+        fetched_at: f64,
+    ) -> Result<Self, onerom_app::Error<String>> {
+        let fetch = Recording {
+            inner: fetch,
+            fetched: RefCell::new(Vec::new()),
+        };
+        let mut catalogue = onerom_app::Catalogue::fetch(&fetch).await?;
+
+        // Tolerate an individual plugin's releases being unreachable: such
+        // plugins keep empty releases and are reported through `failures`, so
+        // the JS side can show them as unavailable. Only the initial catalogue
+        // fetch above is fatal - without it there is nothing to show.
+        let failures = catalogue
+            .load_all_releases_resilient(&fetch)
+            .await
+            .into_iter()
+            .map(|(name, e)| WasmPluginFailure {
+                name,
+                reason: e.to_string(),
+            })
+            .collect();
+
+        Ok(Self {
+            catalogue,
+            failures,
+            manifests: fetch.fetched.into_inner(),
+            binaries: Vec::new(),
+            fetched_at,
+            offline: false,
+            changed: Vec::new(),
+        })
+    }
+
+    /// Whether `data` is the binary of one of the catalogue's releases at
+    /// `url`.
+    fn is_release_binary(&self, url: &str, data: &[u8]) -> bool {
+        let sha256 = sha256_hex(data);
+        self.catalogue.plugins().iter().any(|p| {
+            p.releases
+                .iter()
+                .any(|r| p.binary_url(r) == url && r.sha256.to_ascii_lowercase() == sha256)
+        })
+    }
+
+    /// Names of plugins whose release manifest differs between `self` and
+    /// `other`, or that only one of them lists.
+    fn differing_plugins(&self, other: &PluginCatalog) -> Vec<String> {
+        let manifest = |c: &PluginCatalog, url: &str| {
+            c.manifests
+                .iter()
+                .find(|(u, _)| u == url)
+                .map(|(_, data)| data.clone())
+        };
+        let mut changed: Vec<String> = Vec::new();
+        for (a, b) in [(self, other), (other, self)] {
+            for plugin in a.catalogue.plugins() {
+                let url = plugin.releases_manifest_url();
+                if manifest(a, &url) != manifest(b, &url) && !changed.contains(&plugin.name) {
+                    changed.push(plugin.name.clone());
+                }
+            }
+        }
+        changed
+    }
+}
+
+#[wasm_bindgen]
+impl PluginCatalog {
+    /// When the catalogue's manifests were fetched, in ms since the epoch.
+    #[wasm_bindgen(getter)]
+    pub fn fetched_at(&self) -> f64 {
+        self.fetched_at
+    }
+
+    /// Whether any of the catalogue came from a snapshot rather than the
+    /// network.
+    #[wasm_bindgen(getter)]
+    pub fn offline(&self) -> bool {
+        self.offline
+    }
+
+    /// Plugins whose releases changed since the snapshot passed to
+    /// [`revalidate_plugin_catalog`], by name.
+    pub fn changed(&self) -> Vec<String> {
+        self.changed.clone()
+    }
+
+    /// Verify a fetched plugin binary, as [`verify_plugin`](Self::verify_plugin),
+    /// and keep it to include in snapshots and return from
+    /// [`binary`](Self::binary).
+    pub fn add_binary(
+        &mut self,
+        name: String,
+        version: String,
+        bytes: Vec<u8>,
+    ) -> Result<WasmPluginVerification, JsValue> {
+        let verified = self.verify_plugin(name.clone(), version.clone(), &bytes)?;
+        let (plugin, release) = self.release(&name, &version)?;
+        let url = plugin.binary_url(release);
+        self.binaries.retain(|(u, _)| *u != url);
+        self.binaries.push((url, bytes));
+        Ok(verified)
+    }
+
+    /// A binary added with [`add_binary`](Self::add_binary) or restored from
+    /// a snapshot, for building offline, or `None` if there isn't one.
+    pub fn binary(&self, name: String, version: String) -> Option<Vec<u8>> {
+        let (plugin, release) = self.release(&name, &version).ok()?;
+        let url = plugin.binary_url(release);
+        self.binaries
+            .iter()
+            .find(|(u, _)| *u == url)
+            .map(|(_, data)| data.clone())
+    }
+
+    /// Save the catalogue, and any binaries added to it, as a single blob
+    /// for [`plugin_catalog_from_snapshot`].
+    pub fn export_snapshot(&self) -> Result<Vec<u8>, JsValue> {
+        Snapshot {
+            fetched_at: self.fetched_at,
+            manifests: self.manifests.clone(),
+            binaries: self.binaries.clone(),
+        }
+        .encode()
+        .map_err(|e| JsValue::from_str(&e))
+    }
+}
+
+/// Restore a catalogue saved by [`PluginCatalog::export_snapshot`], without
+/// any fetching.
+///
+/// Every file is checked against the SHA-256 the snapshot records, and every
+/// binary against its release in the snapshot's manifests. The catalogue is
+/// marked `offline`, and its `fetched_at` is when the snapshot's manifests
+/// were fetched, so the UI can show how old it is.
+#[wasm_bindgen]
+pub async fn plugin_catalog_from_snapshot(snapshot: Vec<u8>) -> Result<PluginCatalog, JsValue> {
+    let snapshot = Snapshot::decode(&snapshot).map_err(|e| JsValue::from_str(&e))?;
+    let fetch = SnapshotFetch(&snapshot.manifests);
+    let mut catalog = PluginCatalog::load(&fetch, snapshot.fetched_at)
+        .await
+        .map_err(|e| JsValue::from_str(&format!("Invalid snapshot: {e}")))?;
+
+    for (url, data) in snapshot.binaries {
+        if !catalog.is_release_binary(&url, &data) {
+            return Err(JsValue::from_str(&format!(
+                "Snapshot binary {url} does not match its release"
+            )));
+        }
+        catalog.binaries.push((url, data));
+    }
+    catalog.offline = true;
+    Ok(catalog)
+}
+
+/// Refresh a catalogue, typically one from a snapshot, against the live
+/// catalogue.
+///
+/// `fetch_callback` is as for [`plugin_catalog`](crate::plugin_catalog).
+/// Each manifest is fetched afresh, falling back to `catalog`'s copy if it
+/// cannot be, so this never fails for lack of a connection. The result is
+/// `offline` if anything fell back, in which case it keeps `catalog`'s
+/// `fetched_at`. [`PluginCatalog::changed`] lists the plugins whose releases
+/// differ from `catalog`'s, and binaries no longer matching a release are
+/// dropped. `catalog` is consumed.
+#[wasm_bindgen]
+pub async fn revalidate_plugin_catalog(
+    catalog: PluginCatalog,
+    fetch_callback: js_sys::Function,
+) -> Result<PluginCatalog, JsValue> {
+    let live = JsFetch {
+        callback: fetch_callback,
+    };
+    let fetch = Fallback {
+        live: &live,
+        snapshot: SnapshotFetch(&catalog.manifests),
+        fell_back: Cell::new(false),
+    };
+    let mut fresh = PluginCatalog::load(&fetch, js_sys::Date::now())
+        .await
+        .map_err(crate::plugin_err_to_js)?;
+
+    if fetch.fell_back.get() {
+        fresh.offline = true;
+        fresh.fetched_at = catalog.fetched_at;
+    }
+    fresh.changed = fresh.differing_plugins(&catalog);
+    for (url, data) in &catalog.binaries {
+        if fresh.is_release_binary(url, data) {
+            fresh.binaries.push((url.clone(), data.clone()));
+        }
+    }
+    Ok(fresh)
+}
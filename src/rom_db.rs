@@ -0,0 +1,240 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Known-ROM identification.
+//!
+//! A small database of common stock ROMs, keyed by CRC32 and (where known)
+//! SHA-1, each mapped to what the ROM is and how One ROM should be configured
+//! to serve it. The embedded entries live in `known_roms.json`; more can be
+//! loaded at runtime with [`load_known_roms`], and take precedence over the
+//! embedded ones.
+
+use std::sync::{LazyLock, Mutex};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use onerom_config::chip::ChipType;
+
+use crate::digest;
+
+/// A ROM the database recognises.
+#[derive(Debug, Clone, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi)]
+pub struct KnownRom {
+    /// Human-readable name, e.g. "C64 Kernal ROM, revision 3".
+    pub name: String,
+    /// Manufacturer part number, e.g. "901227-03".
+    pub part_number: String,
+    /// Machine the ROM comes from.
+    pub machine: String,
+    /// Image size in bytes.
+    pub size: usize,
+    /// CRC32 of the image, as 8 hex digits.
+    pub crc32: String,
+    /// SHA-1 of the image, as 40 hex digits, where known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<String>,
+    /// Recommended chip type, where known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chip_type: Option<String>,
+    /// Recommended CS1 logic ("active_low", "active_high" or "ignore"), where
+    /// known. Likewise `cs2` and `cs3`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cs1: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cs2: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cs3: Option<String>,
+}
+
+impl KnownRom {
+    /// Check and normalise an entry: digests to lowercase, chip type to its
+    /// primary name.
+    fn validate(mut self) -> Result<Self, String> {
+        let crc = digest::parse_crc32(&self.crc32).map_err(|e| format!("{}: {e}", self.name))?;
+        self.crc32 = digest::crc32_hex(crc);
+        self.sha1 = self
+            .sha1
+            .as_deref()
+            .map(digest::parse_sha1)
+            .transpose()
+            .map_err(|e| format!("{}: {e}", self.name))?;
+        if let Some(chip_type) = &self.chip_type {
+            let parsed = ChipType::try_from_str(chip_type)
+                .ok_or_else(|| format!("{}: unknown chip type '{chip_type}'", self.name))?;
+            self.chip_type = Some(parsed.name().to_string());
+        }
+        for cs in [&self.cs1, &self.cs2, &self.cs3].into_iter().flatten() {
+            if !matches!(cs.as_str(), "active_low" | "active_high" | "ignore") {
+                return Err(format!("{}: invalid CS logic '{cs}'", self.name));
+            }
+        }
+        Ok(self)
+    }
+
+    fn matches(&self, size: usize, crc32: &str, sha1: &str) -> bool {
+        self.size == size && (self.crc32 == crc32 || self.sha1.as_deref() == Some(sha1))
+    }
+
+    /// Whether `filename` carries this ROM's part number as a whole token.
+    fn named_in(&self, filename: &str) -> bool {
+        let filename = filename.to_ascii_lowercase();
+        let part = self.part_number.to_ascii_lowercase();
+        filename.match_indices(&part).any(|(start, _)| {
+            let before = filename[..start].chars().next_back();
+            let after = filename[start + part.len()..].chars().next();
+            !before.is_some_and(|c| c.is_ascii_alphanumeric())
+                && !after.is_some_and(|c| c.is_ascii_alphanumeric())
+        })
+    }
+}
+
+static EMBEDDED: LazyLock<Vec<KnownRom>> = LazyLock::new(|| {
+    let roms: Vec<KnownRom> = serde_json::from_str(include_str!("known_roms.json"))
+        .expect("embedded known_roms.json is invalid");
+    roms.into_iter()
+        .map(|rom| rom.validate().expect("embedded known ROM is invalid"))
+        .collect()
+});
+
+/// Entries added with [`load_known_roms`], most recently loaded first.
+static LOADED: Mutex<Vec<KnownRom>> = Mutex::new(Vec::new());
+
+/// Find the first entry, loaded then embedded, satisfying `f`.
+fn find(f: impl Fn(&KnownRom) -> bool) -> Option<KnownRom> {
+    let loaded = LOADED.lock().unwrap();
+    loaded.iter().chain(EMBEDDED.iter()).find(|r| f(r)).cloned()
+}
+
+/// Identify a ROM image by its contents.
+pub(crate) fn identify(data: &[u8]) -> Option<KnownRom> {
+    let crc32 = digest::crc32_hex(digest::crc32(data));
+    let sha1 = digest::sha1_hex(data);
+    find(|r| r.matches(data.len(), &crc32, &sha1))
+}
+
+/// Identify a ROM from a recorded filename, by the part number in it.
+///
+/// Only the name is checked, so unlike [`identify`] this says nothing about
+/// the image's contents.
+pub(crate) fn identify_filename(filename: &str) -> Option<KnownRom> {
+    find(|r| r.named_in(filename))
+}
+
+/// Return every known ROM, loaded entries first.
+#[wasm_bindgen]
+pub fn known_roms() -> Vec<KnownRom> {
+    let loaded = LOADED.lock().unwrap();
+    loaded.iter().chain(EMBEDDED.iter()).cloned().collect()
+}
+
+/// Identify a ROM image, returning `undefined` if it is not known.
+///
+/// Matches on size plus either CRC32 or SHA-1.
+#[wasm_bindgen]
+pub fn identify_rom(data: &[u8]) -> Option<KnownRom> {
+    identify(data)
+}
+
+/// Look up a known ROM by digest: 8 hex digits for a CRC32, 40 for a SHA-1.
+#[wasm_bindgen]
+pub fn known_rom_by_digest(value: String) -> Result<Option<KnownRom>, JsValue> {
+    if value.trim().len() == 40 {
+        let sha1 = digest::parse_sha1(&value).map_err(|e| JsValue::from_str(&e))?;
+        Ok(find(|r| r.sha1.as_deref() == Some(sha1.as_str())))
+    } else {
+        let crc32 = digest::parse_crc32(&value).map_err(|e| JsValue::from_str(&e))?;
+        let crc32 = digest::crc32_hex(crc32);
+        Ok(find(|r| r.crc32 == crc32))
+    }
+}
+
+/// Load additional known ROMs from a JSON array of entries, in the same shape
+/// [`known_roms`] returns. Loaded entries are checked before the embedded
+/// ones. Returns the number loaded.
+#[wasm_bindgen]
+pub fn load_known_roms(json: &str) -> Result<usize, JsValue> {
+    let roms: Vec<KnownRom> =
+        serde_json::from_str(json).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    let roms = roms
+        .into_iter()
+        .map(KnownRom::validate)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| JsValue::from_str(&e))?;
+
+    let count = roms.len();
+    let mut loaded = LOADED.lock().unwrap();
+    loaded.splice(0..0, roms);
+    Ok(count)
+}
+
+/// Fill in a config ROM entry from the known-ROM database.
+///
+/// `rom_index` counts ROMs across all sets, in config order. If `data` is a
+/// known ROM, the entry's `description`, `type` and CS lines are set from the
+/// database - only where the entry does not already have them, and only where
+/// the database knows them. Returns the config JSON, unchanged if `data` was
+/// not recognised.
+#[wasm_bindgen]
+pub fn autofill_config(config_json: &str, rom_index: usize, data: &[u8]) -> Result<String, String> {
+    let mut config: Value =
+        serde_json::from_str(config_json).map_err(|e| format!("Error parsing config: {e}"))?;
+
+    let Some(rom) = identify(data) else {
+        return Ok(config_json.to_string());
+    };
+
+    let chip = config_rom_mut(&mut config, rom_index)
+        .ok_or_else(|| format!("Config has no ROM {rom_index}"))?;
+
+    let fill = [
+        (
+            "description",
+            Some(format!("{}, {}", rom.name, rom.part_number)),
+        ),
+        ("type", rom.chip_type),
+        ("cs1", rom.cs1),
+        ("cs2", rom.cs2),
+        ("cs3", rom.cs3),
+    ];
+    for (key, value) in fill {
+        if let Some(value) = value
+            && !chip.contains_key(key)
+        {
+            chip.insert(key.to_string(), Value::String(value));
+        }
+    }
+
+    serde_json::to_string_pretty(&config).map_err(|e| format!("Error writing config: {e}"))
+}
+
+/// The `rom_index`th ROM object in a config, counting across sets.
+fn config_rom_mut(
+    config: &mut Value,
+    rom_index: usize,
+) -> Option<&mut serde_json::Map<String, Value>> {
+    let config = config.as_object_mut()?;
+    let sets = if config.contains_key("chip_sets") {
+        config.get_mut("chip_sets")
+    } else {
+        config.get_mut("rom_sets")
+    }?;
+    sets.as_array_mut()?
+        .iter_mut()
+        .filter_map(|set| {
+            let set = set.as_object_mut()?;
+            if set.contains_key("chips") {
+                set.get_mut("chips")
+            } else {
+                set.get_mut("roms")
+            }
+        })
+        .filter_map(|chips| chips.as_array_mut())
+        .flat_map(|chips| chips.iter_mut())
+        .nth(rom_index)?
+        .as_object_mut()
+}
@@ -0,0 +1,56 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Helpers shared by the tests of the simulated devices.
+
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+use onerom_config::mcu::Variant;
+
+use crate::flash_plan::{FlashPlan, plan};
+
+/// A simulated device's flash.
+pub(crate) trait SimulatedFlash {
+    /// The flash's base address and contents.
+    fn flash(&self) -> (u32, &[u8]);
+
+    /// Flash contents at `address..address + len`, if in range.
+    fn read_flash(&self, address: u32, len: usize) -> Option<&[u8]> {
+        let (base, flash) = self.flash();
+        let offset = address.checked_sub(base)? as usize;
+        flash.get(offset..offset.checked_add(len)?)
+    }
+}
+
+/// Run a future that never waits on anything outside itself.
+pub(crate) fn run_ready<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// A plan writing firmware, and ROM images at 64KB, to `variant`, with its
+/// default chunk size.
+pub(crate) fn test_plan(variant: Variant) -> FlashPlan {
+    let firmware = (0..10000u32).map(|i| i as u8).collect();
+    let roms = vec![0x5A; 5000];
+    plan(
+        variant,
+        vec![("firmware", 0, firmware), ("roms", 0x10000, roms)],
+        None,
+    )
+    .unwrap()
+}
+
+/// Whether `device`'s flash holds everything `plan` writes.
+pub(crate) fn holds(device: &impl SimulatedFlash, plan: &FlashPlan) -> bool {
+    plan.writes
+        .iter()
+        .all(|w| device.read_flash(w.address, w.data.len()) == Some(&w.data[..]))
+}
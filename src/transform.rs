@@ -0,0 +1,304 @@
+// Copyright (C) 2025 Piers Finlayson <piers@piers.rocks>
+//
+// MIT License
+
+//! Per-ROM byte transforms.
+//!
+//! A ROM entry's `transforms` are applied in order to the file as fetched,
+//! before any patches and before `onerom-gen` lays the image out, so ROMs
+//! dumped as even/odd pairs, with swapped lines and so on can be served
+//! without pre-processing them.
+//!
+//! Line numbers count from 0: address lines index into the whole image, data
+//! lines are D0-D7 within each byte.
+
+use serde::Deserialize;
+
+/// A transform as written in a config.
+///
+/// Interleave's second file is left as raw JSON; the config layer resolves it
+/// to a file ID.
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum RawTransform {
+    /// Interleave this image with the file in `with`, `width` bytes at a
+    /// time. This image supplies the even chunks unless `odd` is set.
+    Interleave {
+        with: serde_json::Value,
+        #[serde(default = "default_width")]
+        width: usize,
+        #[serde(default)]
+        odd: bool,
+    },
+    /// Keep part `index` of the image split into `parts` equal parts.
+    Split { parts: usize, index: usize },
+    /// Swap each pair of bytes, for 16-bit images.
+    ByteSwap,
+    /// Swap each pair of address lines.
+    SwapAddressLines { lines: Vec<[u8; 2]> },
+    /// Invert the given address lines.
+    InvertAddressLines { lines: Vec<u8> },
+    /// Swap each pair of data lines.
+    SwapDataLines { lines: Vec<[u8; 2]> },
+    /// Invert the given data lines.
+    InvertDataLines { lines: Vec<u8> },
+    /// XOR every byte with `value`.
+    Xor { value: u8 },
+    /// Set `length` bytes from `offset` to `value`.
+    Fill {
+        offset: usize,
+        length: usize,
+        value: u8,
+    },
+}
+
+fn default_width() -> usize {
+    1
+}
+
+/// A transform ready to apply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Transform {
+    Interleave {
+        file_id: usize,
+        width: usize,
+        odd: bool,
+    },
+    Split {
+        parts: usize,
+        index: usize,
+    },
+    ByteSwap,
+    SwapAddressLines(Vec<[u8; 2]>),
+    InvertAddressLines(Vec<u8>),
+    SwapDataLines(Vec<[u8; 2]>),
+    InvertDataLines(Vec<u8>),
+    Xor(u8),
+    Fill {
+        offset: usize,
+        length: usize,
+        value: u8,
+    },
+}
+
+impl Transform {
+    /// Resolve a raw transform. `interleave_file` maps Interleave's `with` to
+    /// a file ID.
+    pub(crate) fn from_raw(
+        raw: RawTransform,
+        interleave_file: impl FnOnce(serde_json::Value) -> Result<usize, String>,
+    ) -> Result<Self, String> {
+        let check_data_lines = |lines: &[u8]| match lines.iter().find(|&&l| l > 7) {
+            Some(l) => Err(format!("Data line D{l} out of range (D0-D7)")),
+            None => Ok(()),
+        };
+
+        Ok(match raw {
+            RawTransform::Interleave { with, width, odd } => {
+                if width == 0 {
+                    return Err("Interleave width must be at least 1".to_string());
+                }
+                Transform::Interleave {
+                    file_id: interleave_file(with)?,
+                    width,
+                    odd,
+                }
+            }
+            RawTransform::Split { parts, index } => {
+                if parts == 0 || index >= parts {
+                    return Err(format!("Invalid split: part {index} of {parts}"));
+                }
+                Transform::Split { parts, index }
+            }
+            RawTransform::ByteSwap => Transform::ByteSwap,
+            RawTransform::SwapAddressLines { lines } => Transform::SwapAddressLines(lines),
+            RawTransform::InvertAddressLines { lines } => Transform::InvertAddressLines(lines),
+            RawTransform::SwapDataLines { lines } => {
+                check_data_lines(lines.as_flattened())?;
+                Transform::SwapDataLines(lines)
+            }
+            RawTransform::InvertDataLines { lines } => {
+                check_data_lines(&lines)?;
+                Transform::InvertDataLines(lines)
+            }
+            RawTransform::Xor { value } => Transform::Xor(value),
+            RawTransform::Fill {
+                offset,
+                length,
+                value,
+            } => Transform::Fill {
+                offset,
+                length,
+                value,
+            },
+        })
+    }
+
+    /// The extra file this transform needs, if any.
+    pub(crate) fn file_id(&self) -> Option<usize> {
+        match self {
+            Transform::Interleave { file_id, .. } => Some(*file_id),
+            _ => None,
+        }
+    }
+
+    /// Apply to `data`. `other` is the data of [`file_id`](Self::file_id),
+    /// where there is one.
+    pub(crate) fn apply(&self, data: Vec<u8>, other: Option<&[u8]>) -> Result<Vec<u8>, String> {
+        match self {
+            Transform::Interleave { width, odd, .. } => {
+                let other = other.ok_or("Interleave file missing")?;
+                if other.len() != data.len() {
+                    return Err(format!(
+                        "Cannot interleave {} bytes with {} bytes",
+                        data.len(),
+                        other.len()
+                    ));
+                }
+                if !data.len().is_multiple_of(*width) {
+                    return Err(format!(
+                        "Image size {} is not a multiple of interleave width {width}",
+                        data.len()
+                    ));
+                }
+                let (even, odd) = if *odd {
+                    (other, data.as_slice())
+                } else {
+                    (data.as_slice(), other)
+                };
+                Ok(even
+                    .chunks(*width)
+                    .zip(odd.chunks(*width))
+                    .flat_map(|(e, o)| e.iter().chain(o))
+                    .copied()
+                    .collect())
+            }
+            Transform::Split { parts, index } => {
+                if !data.len().is_multiple_of(*parts) {
+                    return Err(format!(
+                        "Image size {} does not split into {parts} parts",
+                        data.len()
+                    ));
+                }
+                let len = data.len() / parts;
+                Ok(data[index * len..(index + 1) * len].to_vec())
+            }
+            Transform::ByteSwap => {
+                if !data.len().is_multiple_of(2) {
+                    return Err(format!("Cannot byte-swap odd size {}", data.len()));
+                }
+                Ok(data.chunks(2).flat_map(|p| [p[1], p[0]]).collect())
+            }
+            Transform::SwapAddressLines(pairs) => {
+                let bits = address_bits(&data)?;
+                let lines = pairs.as_flattened();
+                check_address_lines(lines, bits)?;
+                // Swapping moves the byte at each address to the swapped
+                // address, so each output address reads from the inverse:
+                // the same pairs undone in reverse order. Pairs sharing a
+                // line then move bytes as the data line swaps move bits.
+                let undo: Vec<[u8; 2]> = pairs.iter().rev().copied().collect();
+                Ok((0..data.len())
+                    .map(|addr| data[swap_bits(addr, &undo)])
+                    .collect())
+            }
+            Transform::InvertAddressLines(lines) => {
+                let bits = address_bits(&data)?;
+                check_address_lines(lines, bits)?;
+                let mask = lines.iter().fold(0usize, |m, &l| m | 1 << l);
+                Ok((0..data.len()).map(|addr| data[addr ^ mask]).collect())
+            }
+            Transform::SwapDataLines(pairs) => Ok(data
+                .iter()
+                .map(|&b| swap_bits(b as usize, pairs) as u8)
+                .collect()),
+            Transform::InvertDataLines(lines) => {
+                let mask = lines.iter().fold(0u8, |m, &l| m | 1 << l);
+                Ok(data.iter().map(|b| b ^ mask).collect())
+            }
+            Transform::Xor(value) => Ok(data.iter().map(|b| b ^ value).collect()),
+            Transform::Fill {
+                offset,
+                length,
+                value,
+            } => {
+                let mut data = data;
+                let end = offset
+                    .checked_add(*length)
+                    .filter(|&end| end <= data.len())
+                    .ok_or_else(|| {
+                        format!(
+                            "Fill {offset:#x}+{length:#x} is outside the {} byte image",
+                            data.len()
+                        )
+                    })?;
+                data[*offset..end].fill(*value);
+                Ok(data)
+            }
+        }
+    }
+}
+
+/// Number of address lines an image spans; it must be a power of two.
+fn address_bits(data: &[u8]) -> Result<u32, String> {
+    if !data.len().is_power_of_two() {
+        return Err(format!(
+            "Address lines need a power-of-two image size, not {}",
+            data.len()
+        ));
+    }
+    Ok(data.len().trailing_zeros())
+}
+
+fn check_address_lines(lines: &[u8], bits: u32) -> Result<(), String> {
+    match lines.iter().find(|&&l| l as u32 >= bits) {
+        Some(l) => Err(format!(
+            "Address line A{l} out of range for a {bits}-line image"
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Swap the given pairs of bits in `value`, in order.
+fn swap_bits(value: usize, pairs: &[[u8; 2]]) -> usize {
+    pairs.iter().fold(value, |v, &[a, b]| {
+        let (a, b) = (a as usize, b as usize);
+        if (v >> a) & 1 != (v >> b) & 1 {
+            v ^ (1 << a) ^ (1 << b)
+        } else {
+            v
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swaps_chained_lines_alike() {
+        // A0<->A1 then A1<->A2: the line at 0 ends at 2, 1 at 0 and 2 at 1.
+        let pairs = vec![[0, 1], [1, 2]];
+        let image: Vec<u8> = (0..8).collect();
+        let moved = Transform::SwapAddressLines(pairs.clone())
+            .apply(image.clone(), None)
+            .unwrap();
+        let bits = Transform::SwapDataLines(pairs)
+            .apply(image.clone(), None)
+            .unwrap();
+        assert_eq!(bits, [0, 4, 1, 5, 2, 6, 3, 7]);
+        // Each byte moves to the address its bits are swapped to.
+        for addr in 0..8 {
+            assert_eq!(moved[bits[addr] as usize], image[addr], "A{addr}");
+        }
+    }
+
+    #[test]
+    fn swaps_single_lines_both_ways() {
+        let image: Vec<u8> = (0..4).collect();
+        let moved = Transform::SwapAddressLines(vec![[0, 1]])
+            .apply(image, None)
+            .unwrap();
+        assert_eq!(moved, [0, 2, 1, 3]);
+    }
+}